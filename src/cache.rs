@@ -10,35 +10,61 @@ use serde::Serialize;
 use crate::FileStat;
 use crate::Path;
 
+/// Counters of lookups in the stat cache of a `PortableFs`.
 #[cfg_attr(feature = "json_schema", derive(JsonSchema))]
 #[cfg_attr(feature = "poem", derive(Object))]
 #[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
 pub struct CacheStats {
+    /// Number of lookups served from the cache.
     pub hits: u64,
+    /// Number of lookups that missed the cache.
     pub misses: u64,
 }
 
+impl CacheStats {
+    /// Returns the fraction of lookups served from the cache, or 0.0 if there
+    /// were no lookups.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
 #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
 pub(crate) trait Cache: Send {
     fn get(&mut self, key: &Path) -> Option<&FileStat>;
 
     fn put(&mut self, key: Path, value: FileStat);
 
-    #[cfg(test)]
+    /// Like `get` but neither counts in the stats nor marks the entry as used.
+    fn peek(&self, key: &Path) -> Option<&FileStat>;
+
     fn stats(&self) -> &CacheStats;
 
+    /// Zeroes the stats, leaving cached entries intact.
+    fn reset_stats(&mut self);
+
     #[cfg(test)]
     fn len(&self) -> u64;
 
     fn pop(&mut self, key: &Path) -> Option<FileStat>;
 
+    /// Returns the cached keys, most recently used first.
+    fn iter_keys(&self) -> Vec<Path>;
+
+    /// Returns the maximum number of cached entries.
+    fn capacity(&self) -> usize;
+
     #[cfg(test)]
     fn dump_keys(&self) -> String;
 }
 
 pub(crate) struct NullCache {
-    #[allow(dead_code)]
     stats: CacheStats,
 }
 
@@ -57,11 +83,18 @@ impl Cache for NullCache {
 
     fn put(&mut self, _key: Path, _value: FileStat) {}
 
-    #[cfg(test)]
+    fn peek(&self, _key: &Path) -> Option<&FileStat> {
+        None
+    }
+
     fn stats(&self) -> &CacheStats {
         &self.stats
     }
 
+    fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
     #[cfg(test)]
     fn len(&self) -> u64 {
         0
@@ -71,6 +104,14 @@ impl Cache for NullCache {
         None
     }
 
+    fn iter_keys(&self) -> Vec<Path> {
+        vec![]
+    }
+
+    fn capacity(&self) -> usize {
+        0
+    }
+
     #[cfg(test)]
     fn dump_keys(&self) -> String {
         "".to_owned()
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fs::DirEntry;
 
 #[cfg(feature = "poem")]
@@ -23,6 +24,22 @@ pub struct DirectoryEntry {
     pub name: String,
     /// Metadata of the file or directory.
     pub stats: FileStat,
+    /// Whether the entry is a symbolic link, e.g. to render a link badge.
+    /// `stats` are those of the link's target, unless the link is reported as
+    /// is, see `SymlinkPolicy::ReportOnly`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_symlink: bool,
+}
+
+impl DirectoryEntry {
+    /// Returns the path of this entry in the directory at `current`, e.g. to
+    /// `stat` or `read_file` it.
+    ///
+    /// Returns `Error::InvalidArgument` if `name` is not a single valid path
+    /// component, e.g. `..`.
+    pub fn full_path(&self, current: &Path) -> Result<Path, Error> {
+        Ok(current.join(&Path::try_from([&self.name].as_slice())?))
+    }
 }
 
 impl TryFrom<&FileInfo> for DirectoryEntry {
@@ -36,23 +53,77 @@ impl TryFrom<&FileInfo> for DirectoryEntry {
             })?
             .to_string();
         let stats = item.stats.clone();
-        Ok(DirectoryEntry { name, stats })
+        Ok(DirectoryEntry {
+            name,
+            is_symlink: stats.is_symlink,
+            stats,
+        })
     }
 }
 
 impl TryFrom<&DirEntry> for DirectoryEntry {
     type Error = Error;
     fn try_from(entry: &DirEntry) -> Result<Self, crate::Error> {
-        let metadata = std::fs::metadata(entry.path()).map_err(|e| Error::Read {
+        let read_error = |e: std::io::Error| Error::Read {
             what: "metadata".into(),
             how: e.to_string(),
-        })?;
+        };
+        let is_symlink = std::fs::symlink_metadata(entry.path())
+            .map_err(read_error)?
+            .is_symlink();
+        let metadata = std::fs::metadata(entry.path()).map_err(read_error)?;
+        let mut stats = FileStat::from_metadata(&metadata, None);
+        stats.is_symlink = is_symlink;
         Ok(Self {
             name: entry.file_name().into_string().unwrap(),
-            stats: FileStat::from_metadata(&metadata, None),
+            stats,
+            is_symlink,
         })
     }
 }
+/// An entry of a directory that couldn't be read, e.g. for lack of permissions.
+#[cfg_attr(feature = "json_schema", derive(JsonSchema))]
+#[cfg_attr(feature = "poem", derive(Object))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
+pub struct EntryError {
+    /// Name of the file or directory.
+    pub name: String,
+    /// Why the entry couldn't be read.
+    pub error: String,
+}
+
+/// Space used by a subtree, see `PortableFs::disk_usage`.
+#[cfg_attr(feature = "json_schema", derive(JsonSchema))]
+#[cfg_attr(feature = "poem", derive(Object))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
+pub struct DiskUsage {
+    /// Sum of the sizes of the files in bytes.
+    pub total_bytes: u64,
+    /// Number of files.
+    pub file_count: u64,
+    /// Number of directories, excluding the root of the subtree.
+    pub dir_count: u64,
+}
+
+/// Sums up entries by their stats: symlinks count as what `FileStat` reports,
+/// e.g. as directories when followed, and directory sizes are ignored.
+impl<'a> FromIterator<&'a FileInfo> for DiskUsage {
+    fn from_iter<I: IntoIterator<Item = &'a FileInfo>>(items: I) -> Self {
+        let mut usage = DiskUsage::default();
+        for item in items {
+            if item.stats.is_directory {
+                usage.dir_count += 1;
+            } else {
+                usage.file_count += 1;
+                usage.total_bytes += item.stats.size;
+            }
+        }
+        usage
+    }
+}
+
 /// Represents the contents of a directory, including the current path and its
 /// items.
 #[cfg_attr(feature = "json_schema", derive(JsonSchema))]
@@ -60,8 +131,335 @@ impl TryFrom<&DirEntry> for DirectoryEntry {
 #[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
 pub struct Directory {
+    /// Generation of the serialized format, see `SCHEMA_VERSION`. `None` for
+    /// payloads predating versioning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u32>,
     /// The current directory path.
     pub current_path: Path,
+    /// Path of the parent of `current_path`, e.g. to navigate up. `None` at
+    /// the root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<Path>,
     /// The list of files and directories in the current path.
     pub items: Vec<DirectoryEntry>,
+    /// Name to pass as `after_name` to `PortableFs::read_dir_after` to fetch
+    /// the next page. `None` if there are no more entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    /// Entries omitted from `items` because they couldn't be read.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<EntryError>,
+}
+
+/// Orders of the entries of a `Directory`, see `Directory::sort_by`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// By name.
+    Name,
+    /// Directories before files, each by name. This is the order of
+    /// `PortableFs::read_dir`.
+    DirectoriesFirst,
+    /// Directories by name before files, files grouped by extension, compared
+    /// case insensitively, and by name within a group. Files without an
+    /// extension come first.
+    TypeThenName,
+}
+
+impl Directory {
+    /// Sorts the entries by `key`, e.g. to present them in a file manager.
+    pub fn sort_by(&mut self, key: SortKey) {
+        let extension = |entry: &DirectoryEntry| {
+            std::path::Path::new(&entry.name)
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+        };
+        match key {
+            SortKey::Name => self.items.sort_by(|a, b| a.name.cmp(&b.name)),
+            SortKey::DirectoriesFirst => self.items.sort_by(|a, b| {
+                b.stats
+                    .is_directory
+                    .cmp(&a.stats.is_directory)
+                    .then_with(|| a.name.cmp(&b.name))
+            }),
+            SortKey::TypeThenName => self.items.sort_by_cached_key(|entry| {
+                let is_directory = entry.stats.is_directory;
+                let extension = if is_directory { None } else { extension(entry) };
+                (!is_directory, extension, entry.name.clone())
+            }),
+        }
+    }
+
+    /// Retains only the entries for which `f` returns true, e.g. to filter a
+    /// listing locally without another round trip to the server.
+    pub fn retain<F: Fn(&DirectoryEntry) -> bool>(&mut self, f: F) {
+        self.items.retain(f);
+    }
+
+    /// Returns a copy of this directory with only the entries for which `f`
+    /// returns true.
+    pub fn filtered<F: Fn(&DirectoryEntry) -> bool>(&self, f: F) -> Directory {
+        Directory {
+            schema_version: self.schema_version,
+            current_path: self.current_path.clone(),
+            parent: self.parent.clone(),
+            items: self.items.iter().filter(|e| f(e)).cloned().collect(),
+            next_cursor: self.next_cursor.clone(),
+            errors: self.errors.clone(),
+        }
+    }
+
+    /// Appends the entries of `other`, e.g. the next page of a listing
+    /// fetched with `PortableFs::read_dir_after`. Entries of both with the
+    /// same name are collapsed into the one of `other`, which is assumed to
+    /// be more recent, and so are errors. `next_cursor` is that of `other`.
+    ///
+    /// Returns `Error::InvalidArgument` if the directories have different
+    /// `current_path`s.
+    pub fn merge(&mut self, other: Directory) -> Result<(), Error> {
+        if self.current_path != other.current_path {
+            return Err(Error::InvalidArgument(format!(
+                "can't merge listings of {} and {}",
+                self.current_path, other.current_path
+            )));
+        }
+        let mut positions: HashMap<String, usize> = self
+            .items
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.name.clone(), index))
+            .collect();
+        for entry in other.items {
+            match positions.get(&entry.name) {
+                Some(index) => self.items[*index] = entry,
+                None => {
+                    positions.insert(entry.name.clone(), self.items.len());
+                    self.items.push(entry);
+                }
+            }
+        }
+        for error in other.errors {
+            self.errors.retain(|e| e.name != error.name);
+            self.errors.push(error);
+        }
+        self.next_cursor = other.next_cursor;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, is_directory: bool) -> DirectoryEntry {
+        DirectoryEntry {
+            name: name.into(),
+            stats: FileStat {
+                size: 0,
+                mtime: "2018-01-26T18:30:09.453Z".into(),
+                is_directory,
+                sha256: None,
+                xattrs: None,
+                dev: None,
+                ino: None,
+                is_symlink: false,
+            },
+            is_symlink: false,
+        }
+    }
+
+    fn directory() -> Directory {
+        Directory {
+            schema_version: Some(crate::SCHEMA_VERSION),
+            current_path: Path::try_from(["dir1"].as_slice()).unwrap(),
+            parent: Some(Path::empty()),
+            items: vec![
+                entry("dir2", true),
+                entry("file3.txt", false),
+                entry("file7.md", false),
+                entry("image.png", false),
+                entry("photo.PNG", false),
+            ],
+            next_cursor: None,
+            errors: vec![],
+        }
+    }
+
+    fn is_png(entry: &DirectoryEntry) -> bool {
+        entry.name.to_lowercase().ends_with(".png")
+    }
+
+    #[test]
+    fn test_filtered() {
+        let dir = directory();
+        let images = dir.filtered(is_png);
+        assert_eq!(images.current_path, dir.current_path);
+        let names: Vec<&str> = images.items.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["image.png", "photo.PNG"]);
+        assert_eq!(dir.items.len(), 5);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut first = directory();
+        first.items.truncate(3);
+        first.next_cursor = Some("file7.md".into());
+        let mut second = directory();
+        second.items.drain(..2);
+        second.items[0].stats.size = 42;
+        second.next_cursor = None;
+
+        first.merge(second).unwrap();
+        let names: Vec<&str> = first.items.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["dir2", "file3.txt", "file7.md", "image.png", "photo.PNG"]
+        );
+        assert_eq!(first.items[2].stats.size, 42);
+        assert_eq!(first.next_cursor, None);
+
+        let mut other = directory();
+        other.current_path = Path::try_from(["dir3"].as_slice()).unwrap();
+        assert!(matches!(first.merge(other), Err(Error::InvalidArgument(_))));
+        assert_eq!(first.items.len(), 5);
+    }
+
+    #[test]
+    fn test_sort_by() {
+        let mut dir = directory();
+        dir.items.extend([
+            entry("dir0", true),
+            entry("a.txt", false),
+            entry("Makefile", false),
+            entry("dir.d", true),
+        ]);
+        dir.items.reverse();
+        let names =
+            |dir: &Directory| -> Vec<String> { dir.items.iter().map(|e| e.name.clone()).collect() };
+
+        dir.sort_by(SortKey::TypeThenName);
+        assert_eq!(
+            names(&dir),
+            [
+                "dir.d",
+                "dir0",
+                "dir2",
+                "Makefile",
+                "file7.md",
+                "image.png",
+                "photo.PNG",
+                "a.txt",
+                "file3.txt"
+            ]
+        );
+
+        dir.sort_by(SortKey::DirectoriesFirst);
+        assert_eq!(
+            names(&dir),
+            [
+                "dir.d",
+                "dir0",
+                "dir2",
+                "Makefile",
+                "a.txt",
+                "file3.txt",
+                "file7.md",
+                "image.png",
+                "photo.PNG"
+            ]
+        );
+
+        dir.sort_by(SortKey::Name);
+        assert_eq!(
+            names(&dir),
+            [
+                "Makefile",
+                "a.txt",
+                "dir.d",
+                "dir0",
+                "dir2",
+                "file3.txt",
+                "file7.md",
+                "image.png",
+                "photo.PNG"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut dir = directory();
+        dir.retain(is_png);
+        assert_eq!(dir, directory().filtered(is_png));
+    }
+
+    #[test]
+    fn test_full_path() {
+        let dir = directory();
+        let file3 = dir.items.iter().find(|e| e.name == "file3.txt").unwrap();
+        assert_eq!(
+            file3.full_path(&dir.current_path).unwrap(),
+            Path::try_from(["dir1", "file3.txt"].as_slice()).unwrap()
+        );
+        assert_eq!(
+            file3.full_path(&Path::empty()).unwrap(),
+            Path::try_from(["file3.txt"].as_slice()).unwrap()
+        );
+        for name in ["..", ".", "", "a/b"] {
+            assert!(entry(name, false).full_path(&dir.current_path).is_err());
+        }
+    }
+
+    #[test]
+    fn test_deserialize_unversioned() {
+        let json = r#"{
+            "current_path": {"components": ["dir1"]},
+            "items": [{
+                "name": "file3.txt",
+                "stats": {
+                    "size": 0,
+                    "mtime": "2018-01-26T18:30:09.453Z",
+                    "is_directory": false,
+                    "sha256": null
+                }
+            }]
+        }"#;
+        let dir: Directory = serde_json::from_str(json).unwrap();
+        assert_eq!(dir.schema_version, None);
+        assert_eq!(dir.items[0].stats.xattrs, None);
+
+        let mut expected = directory().filtered(|e| e.name == "file3.txt");
+        expected.schema_version = None;
+        expected.parent = None;
+        assert_eq!(dir, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_is_symlink() {
+        let dir = tempdir::TempDir::new("").unwrap();
+        std::fs::write(dir.path().join("file.txt"), "data").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("file.txt"), dir.path().join("link.txt"))
+            .unwrap();
+
+        let mut entries: Vec<DirectoryEntry> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| DirectoryEntry::try_from(&e.unwrap()).unwrap())
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(entries[0].name, "file.txt");
+        assert!(!entries[0].is_symlink);
+        assert_eq!(entries[1].name, "link.txt");
+        assert!(entries[1].is_symlink);
+        assert!(entries[1].stats.is_symlink);
+        // Stats are those of the target
+        assert_eq!(entries[1].stats, entries[0].stats);
+
+        let info = FileInfo {
+            path: Path::try_from(["link.txt"].as_slice()).unwrap(),
+            stats: entries[1].stats.clone(),
+            digest_index: None,
+        };
+        assert!(DirectoryEntry::try_from(&info).unwrap().is_symlink);
+    }
 }
@@ -2,20 +2,665 @@
 use poem_openapi::Object;
 #[cfg(feature = "json_schema")]
 use schemars::JsonSchema;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
 use serde::Deserialize;
 use serde::Serialize;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::io::AsyncWrite;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::io::AsyncWriteExt;
 
+use crate::DiskUsage;
+use crate::Error;
 use crate::FileInfo;
 use crate::Path;
 
+fn write_error<E: ToString>(e: E) -> Error {
+    Error::Write {
+        what: "json".into(),
+        how: e.to_string(),
+    }
+}
+
 /// A list of files and directories contained in `base_dir`
 #[cfg_attr(feature = "json_schema", derive(JsonSchema))]
 #[cfg_attr(feature = "poem", derive(Object))]
 #[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
 pub struct RecursiveDirList {
+    /// Generation of the serialized format, see `SCHEMA_VERSION`. `None` for
+    /// payloads predating versioning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u32>,
     /// Path where the directory should be synced
     pub base_dir: Path,
     /// List of file info representing in the `base_dir` directory tree
     pub deltas: Vec<FileInfo>,
+    /// Pool of distinct digests referenced by `FileInfo::digest_index` when
+    /// the list is interned. Empty otherwise.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub digests: Vec<String>,
+    /// True if the walk stopped early after reaching its entry cap, so
+    /// `deltas` is incomplete.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// A chunk of a `RecursiveDirList` streamed over a channel that may drop or
+/// reorder messages. See `RecursiveDirList::reassemble`.
+#[cfg_attr(feature = "json_schema", derive(JsonSchema))]
+#[cfg_attr(feature = "poem", derive(Object))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
+pub struct DirListChunk {
+    /// Position of the chunk in the stream, starting at 0.
+    pub seq: u64,
+    /// Path of the listed directory, the same for all chunks of a stream.
+    pub base_dir: Path,
+    /// Entries of this chunk.
+    pub deltas: Vec<FileInfo>,
+    /// True for the final chunk of the stream.
+    pub last: bool,
+}
+
+/// Top-line counts of a `RecursiveDirList`, see `RecursiveDirList::summary`.
+#[cfg_attr(feature = "json_schema", derive(JsonSchema))]
+#[cfg_attr(feature = "poem", derive(Object))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
+pub struct TreeSummary {
+    /// Number of files, including symlinks not reported as directories.
+    pub total_files: u64,
+    /// Number of directories, excluding `base_dir` itself.
+    pub total_dirs: u64,
+    /// Sum of the sizes of the files in bytes. Directory sizes are ignored.
+    pub total_bytes: u64,
+}
+
+/// Counts entries the same way as `DiskUsage`.
+impl From<DiskUsage> for TreeSummary {
+    fn from(usage: DiskUsage) -> Self {
+        Self {
+            total_files: usage.file_count,
+            total_dirs: usage.dir_count,
+            total_bytes: usage.total_bytes,
+        }
+    }
+}
+
+impl RecursiveDirList {
+    /// Returns the number of files and directories in `deltas` and the sum of
+    /// the sizes of the files. Entries count as what their `FileStat`
+    /// reports, e.g. followed symlinks to directories as directories.
+    pub fn summary(&self) -> TreeSummary {
+        self.deltas.iter().collect::<DiskUsage>().into()
+    }
+
+    /// Retains only the entries for which `keep` returns true, e.g. to free
+    /// the memory of entries a client no longer displays. Interned digests
+    /// are left in `digests`, so `FileInfo::digest_index` stays valid.
+    pub fn prune<F: Fn(&FileInfo) -> bool>(&mut self, keep: F) {
+        self.deltas.retain(keep);
+    }
+
+    /// Removes the entry at `prefix` and all the entries under it, e.g. the
+    /// contents of a collapsed folder. Like `FileInfo::path`, `prefix` is
+    /// relative to the root of the file system, so it starts with `base_dir`.
+    pub fn prune_under(&mut self, prefix: &Path) {
+        self.prune(|item| !item.path.starts_with(prefix));
+    }
+
+    /// Rebuilds a list from the `chunks` of a stream, in any order.
+    ///
+    /// Returns `Error::InvalidArgument` if a chunk is missing or duplicated,
+    /// if only the final chunk isn't flagged `last`, or if chunks disagree on
+    /// `base_dir`.
+    pub fn reassemble<I: IntoIterator<Item = DirListChunk>>(chunks: I) -> Result<Self, Error> {
+        let mut chunks: Vec<DirListChunk> = chunks.into_iter().collect();
+        chunks.sort_by_key(|chunk| chunk.seq);
+        let base_dir = chunks
+            .first()
+            .ok_or_else(|| Error::InvalidArgument("no chunks to reassemble".into()))?
+            .base_dir
+            .clone();
+        let count = chunks.len();
+        let mut deltas = vec![];
+        for (expected, chunk) in chunks.into_iter().enumerate() {
+            if chunk.seq != expected as u64 {
+                return Err(Error::InvalidArgument(format!(
+                    "expected chunk {expected}, found {}",
+                    chunk.seq
+                )));
+            }
+            if chunk.last != (expected + 1 == count) {
+                return Err(Error::InvalidArgument(format!(
+                    "chunk {expected} of {count} is {}flagged last",
+                    if chunk.last { "" } else { "not " }
+                )));
+            }
+            if chunk.base_dir != base_dir {
+                return Err(Error::InvalidArgument(format!(
+                    "chunk {expected} is of {} instead of {base_dir}",
+                    chunk.base_dir
+                )));
+            }
+            deltas.extend(chunk.deltas);
+        }
+        Ok(Self {
+            schema_version: Some(crate::SCHEMA_VERSION),
+            base_dir,
+            deltas,
+            digests: vec![],
+            truncated: false,
+        })
+    }
+
+    /// Moves the digests of `deltas` into the `digests` pool, storing each
+    /// distinct digest once and referencing it by index. This shrinks lists
+    /// of trees with many identical files.
+    pub fn intern(&mut self) {
+        let mut indices: HashMap<String, u32> = self
+            .digests
+            .iter()
+            .enumerate()
+            .map(|(i, digest)| (digest.clone(), i as u32))
+            .collect();
+        for item in &mut self.deltas {
+            let Some(digest) = item.stats.sha256.take() else {
+                continue;
+            };
+            let index = *indices.entry(digest).or_insert_with_key(|digest| {
+                self.digests.push(digest.clone());
+                (self.digests.len() - 1) as u32
+            });
+            item.digest_index = Some(index);
+        }
+    }
+
+    /// Reverses `intern`, storing digests inline in each `FileInfo` and
+    /// clearing the pool. A no-op for lists that are not interned.
+    ///
+    /// Returns `Error::InvalidArgument` if an index is out of the pool's
+    /// range.
+    pub fn expand(&mut self) -> Result<(), Error> {
+        for item in &mut self.deltas {
+            let Some(index) = item.digest_index else {
+                continue;
+            };
+            let digest = self.digests.get(index as usize).ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "digest index {index} of {} out of range",
+                    item.path
+                ))
+            })?;
+            item.stats.sha256 = Some(digest.clone());
+            item.digest_index = None;
+        }
+        self.digests.clear();
+        Ok(())
+    }
+
+    /// Checks that the list is well-formed before trusting it, e.g. after
+    /// receiving it from a peer: paths are unique and under `base_dir`,
+    /// digest indices are in range and directories carry no file digest.
+    /// Digests of directory listings are accepted.
+    ///
+    /// Returns `Error::InvalidArgument` describing the first violation found.
+    pub fn validate(&self) -> Result<(), Error> {
+        let base = self.base_dir.canonical_components();
+        let mut seen = HashSet::new();
+        for item in &self.deltas {
+            let components = item.path.canonical_components();
+            if components.len() <= base.len() || !components.starts_with(base) {
+                return Err(Error::InvalidArgument(format!(
+                    "{} is not under base dir {}",
+                    item.path, self.base_dir
+                )));
+            }
+            if !seen.insert(&item.path) {
+                return Err(Error::InvalidArgument(format!(
+                    "duplicate path {}",
+                    item.path
+                )));
+            }
+            let digest = match item.digest_index {
+                Some(index) => Some(self.digests.get(index as usize).ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "digest index {index} of {} out of range",
+                        item.path
+                    ))
+                })?),
+                None => item.stats.sha256.as_ref(),
+            };
+            let is_file_digest =
+                |d: &String| !d.is_empty() && !d.starts_with(crate::hash::DIR_DIGEST_TAG);
+            if item.stats.is_directory && digest.is_some_and(is_file_digest) {
+                return Err(Error::InvalidArgument(format!(
+                    "directory {} has a file digest",
+                    item.path
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Serializes the list as JSON into `writer`, streaming `deltas` one
+    /// element at a time instead of building the whole document in memory.
+    ///
+    /// The output is identical to `serde_json::to_writer`.
+    pub fn write_json<W: std::io::Write>(&self, mut writer: W) -> Result<(), Error> {
+        writer.write_all(&self.json_head()?).map_err(write_error)?;
+        for (i, item) in self.deltas.iter().enumerate() {
+            if i > 0 {
+                writer.write_all(b",").map_err(write_error)?;
+            }
+            serde_json::to_writer(&mut writer, item).map_err(write_error)?;
+        }
+        writer.write_all(&self.json_tail()?).map_err(write_error)?;
+        writer.flush().map_err(write_error)
+    }
+
+    /// Async variant of `write_json` streaming into an `AsyncWrite`, for
+    /// example a socket.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn write_json_async<W: AsyncWrite + Unpin>(
+        &self,
+        mut writer: W,
+    ) -> Result<(), Error> {
+        writer
+            .write_all(&self.json_head()?)
+            .await
+            .map_err(write_error)?;
+        let mut buf = Vec::new();
+        for (i, item) in self.deltas.iter().enumerate() {
+            buf.clear();
+            if i > 0 {
+                buf.push(b',');
+            }
+            serde_json::to_writer(&mut buf, item).map_err(write_error)?;
+            writer.write_all(&buf).await.map_err(write_error)?;
+        }
+        writer
+            .write_all(&self.json_tail()?)
+            .await
+            .map_err(write_error)?;
+        writer.flush().await.map_err(write_error)
+    }
+
+    // The JSON preceding the elements of `deltas`, in serde field order.
+    fn json_head(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = b"{".to_vec();
+        if let Some(version) = self.schema_version {
+            buf.extend_from_slice(format!("\"schema_version\":{version},").as_bytes());
+        }
+        buf.extend_from_slice(b"\"base_dir\":");
+        serde_json::to_writer(&mut buf, &self.base_dir).map_err(write_error)?;
+        buf.extend_from_slice(b",\"deltas\":[");
+        Ok(buf)
+    }
+
+    // The JSON following the elements of `deltas`.
+    fn json_tail(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = b"]".to_vec();
+        if !self.digests.is_empty() {
+            buf.extend_from_slice(b",\"digests\":");
+            serde_json::to_writer(&mut buf, &self.digests).map_err(write_error)?;
+        }
+        if self.truncated {
+            buf.extend_from_slice(b",\"truncated\":true");
+        }
+        buf.push(b'}');
+        Ok(buf)
+    }
+}
+
+#[cfg(all(not(target_arch = "wasm32"), test))]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::PortableFs;
+    use crate::TestRoot;
+    use crate::hash::Sha256Builder;
+    use crate::hash::Sha256String;
+
+    async fn dir_list() -> RecursiveDirList {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        RecursiveDirList {
+            schema_version: Some(crate::SCHEMA_VERSION),
+            base_dir: Path::empty(),
+            deltas: fs.read_dir_recurse(&Path::empty()).await.unwrap(),
+            digests: vec![],
+            truncated: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_json() {
+        let list = dir_list().await;
+        let mut buf = Vec::new();
+        list.write_json(&mut buf).unwrap();
+        assert_eq!(buf, serde_json::to_vec(&list).unwrap());
+        let parsed: RecursiveDirList = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(parsed, list);
+    }
+
+    #[tokio::test]
+    async fn test_write_json_async() {
+        let list = dir_list().await;
+        let mut buf = Vec::new();
+        list.write_json_async(&mut buf).await.unwrap();
+        let parsed: RecursiveDirList = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(parsed, list);
+    }
+
+    #[tokio::test]
+    async fn test_write_json_interned() {
+        let mut list = dir_list().await;
+        list.intern();
+        let mut buf = Vec::new();
+        list.write_json(&mut buf).unwrap();
+        assert_eq!(buf, serde_json::to_vec(&list).unwrap());
+        let mut async_buf = Vec::new();
+        list.write_json_async(&mut async_buf).await.unwrap();
+        assert_eq!(async_buf, buf);
+    }
+
+    #[test]
+    fn test_deserialize_unversioned() {
+        let json = r#"{
+            "base_dir": {"components": ["dir1"]},
+            "deltas": [{
+                "path": {"components": ["dir1", "file3.txt"]},
+                "stats": {
+                    "size": 0,
+                    "mtime": "2018-01-26T18:30:09.453Z",
+                    "is_directory": false,
+                    "sha256": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                }
+            }]
+        }"#;
+        let list: RecursiveDirList = serde_json::from_str(json).unwrap();
+        assert_eq!(list.schema_version, None);
+        assert!(list.digests.is_empty());
+        assert_eq!(list.deltas.len(), 1);
+        assert_eq!(list.deltas[0].digest_index, None);
+        assert_eq!(list.deltas[0].stats.xattrs, None);
+    }
+
+    #[test]
+    fn test_write_json_empty() {
+        let list = RecursiveDirList {
+            schema_version: None,
+            base_dir: Path::try_from(["dir1"].as_slice()).unwrap(),
+            deltas: vec![],
+            digests: vec![],
+            truncated: false,
+        };
+        let mut buf = Vec::new();
+        list.write_json(&mut buf).unwrap();
+        let parsed: RecursiveDirList = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(parsed, list);
+    }
+
+    #[tokio::test]
+    async fn test_intern_round_trip() {
+        // All fixture files are empty and hence share a digest. Directories
+        // share the empty digest.
+        let list = dir_list().await;
+        let mut interned = list.clone();
+        interned.intern();
+        assert_eq!(interned.digests.len(), 2);
+        let file1 = Path::try_from(["file1.txt"].as_slice()).unwrap();
+        let mut file_indices = HashSet::new();
+        for item in &interned.deltas {
+            assert_eq!(item.stats.sha256, None);
+            if !item.stats.is_directory {
+                file_indices.insert(item.digest_index.unwrap());
+            }
+            if item.path == file1 {
+                let digest = &interned.digests[item.digest_index.unwrap() as usize];
+                assert_eq!(
+                    digest,
+                    &"".as_bytes()
+                        .sha256_build()
+                        .await
+                        .unwrap()
+                        .sha256_string()
+                        .await
+                        .unwrap()
+                );
+            }
+        }
+        assert_eq!(file_indices.len(), 1);
+
+        // Interning twice is a no-op.
+        let mut twice = interned.clone();
+        twice.intern();
+        assert_eq!(twice, interned);
+
+        let json = serde_json::to_vec(&interned).unwrap();
+        let mut parsed: RecursiveDirList = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed, interned);
+        parsed.expand().unwrap();
+        assert_eq!(parsed, list);
+        assert_eq!(
+            serde_json::to_vec(&parsed).unwrap(),
+            serde_json::to_vec(&list).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let mut list = dir_list().await;
+        list.validate().unwrap();
+        list.intern();
+        list.validate().unwrap();
+        list.expand().unwrap();
+
+        let file1 = Path::try_from(["file1.txt"].as_slice()).unwrap();
+        let mut duplicate = list.clone();
+        let item = duplicate
+            .deltas
+            .iter()
+            .find(|item| item.path == file1)
+            .unwrap()
+            .clone();
+        duplicate.deltas.push(item);
+        assert_eq!(
+            duplicate.validate(),
+            Err(Error::InvalidArgument("duplicate path file1.txt".into()))
+        );
+
+        let mut out_of_root = list.clone();
+        out_of_root.base_dir = Path::try_from(["dir1"].as_slice()).unwrap();
+        assert!(matches!(
+            out_of_root.validate(),
+            Err(Error::InvalidArgument(msg)) if msg.contains("is not under base dir dir1")
+        ));
+
+        let mut dir_digest = list.clone();
+        let dir = dir_digest
+            .deltas
+            .iter_mut()
+            .find(|item| item.stats.is_directory)
+            .unwrap();
+        dir.stats.sha256 = Some("digest".into());
+        let msg = format!("directory {} has a file digest", dir.path);
+        assert_eq!(dir_digest.validate(), Err(Error::InvalidArgument(msg)));
+    }
+
+    #[tokio::test]
+    async fn test_expand_out_of_range() {
+        let mut list = dir_list().await;
+        list.intern();
+        list.digests.pop();
+        let err = list.expand().unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{err:?}");
+    }
+
+    fn chunks(list: &RecursiveDirList, chunk_size: usize) -> Vec<DirListChunk> {
+        let count = list.deltas.chunks(chunk_size).len();
+        list.deltas
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(seq, deltas)| DirListChunk {
+                seq: seq as u64,
+                base_dir: list.base_dir.clone(),
+                deltas: deltas.to_vec(),
+                last: seq + 1 == count,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_reassemble() {
+        let list = dir_list().await;
+        let chunks = chunks(&list, 3);
+        assert_eq!(RecursiveDirList::reassemble(chunks.clone()).unwrap(), list);
+
+        let mut reordered = chunks.clone();
+        reordered.reverse();
+        assert_eq!(RecursiveDirList::reassemble(reordered).unwrap(), list);
+    }
+
+    #[tokio::test]
+    async fn test_reassemble_invalid() {
+        let list = dir_list().await;
+        let chunks = chunks(&list, 3);
+        assert!(chunks.len() > 2);
+        let invalid = |chunks: Vec<DirListChunk>| {
+            assert!(matches!(
+                RecursiveDirList::reassemble(chunks),
+                Err(Error::InvalidArgument(_))
+            ));
+        };
+
+        invalid(vec![]);
+        let mut missing = chunks.clone();
+        missing.remove(1);
+        invalid(missing);
+        // Dropping the final chunk leaves no chunk flagged last
+        invalid(chunks[..chunks.len() - 1].to_vec());
+        let mut duplicated = chunks.clone();
+        duplicated.push(chunks[0].clone());
+        invalid(duplicated);
+        let mut other_dir = chunks.clone();
+        other_dir[1].base_dir = Path::try_from(["dir1"].as_slice()).unwrap();
+        invalid(other_dir);
+    }
+
+    #[tokio::test]
+    async fn test_rebase() {
+        let list = dir_list().await;
+        let mnt_data = Path::try_from(["mnt", "data"].as_slice()).unwrap();
+        let rebased: Vec<FileInfo> = list
+            .deltas
+            .iter()
+            .map(|item| item.rebase(&list.base_dir, &mnt_data).unwrap())
+            .collect();
+        for (item, rebased) in list.deltas.iter().zip(&rebased) {
+            assert_eq!(rebased.path, mnt_data.join(&item.path));
+            assert_eq!(rebased.stats, item.stats);
+        }
+
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+        let file3 = list
+            .deltas
+            .iter()
+            .find(|item| item.path.basename() == Some("file3.txt"))
+            .unwrap();
+        assert_eq!(
+            file3.rebase(&dir1, &mnt_data).unwrap().path,
+            Path::try_from(["mnt", "data", "file3.txt"].as_slice()).unwrap()
+        );
+        let file1 = list
+            .deltas
+            .iter()
+            .find(|item| item.path.basename() == Some("file1.txt"))
+            .unwrap();
+        assert!(matches!(
+            file1.rebase(&dir1, &mnt_data),
+            Err(Error::InvalidPath { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let mut list = dir_list().await;
+        let dir2 = Path::try_from(["dir1", "dir2"].as_slice()).unwrap();
+        list.prune_under(&dir2);
+        let mut paths: Vec<String> = list.deltas.iter().map(|d| d.path.to_string()).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "dir1",
+                "dir1/file3.txt",
+                "dir1/file7.md",
+                "dir1/file8.rs",
+                "dir3",
+                "dir3/file6.txt",
+                "file1.txt",
+                "file2.txt",
+            ]
+        );
+
+        list.prune(|item| item.stats.is_directory);
+        let mut paths: Vec<String> = list.deltas.iter().map(|d| d.path.to_string()).collect();
+        paths.sort();
+        assert_eq!(paths, ["dir1", "dir3"]);
+
+        list.prune_under(&Path::empty());
+        assert!(list.deltas.is_empty());
+
+        // Entries of a list with a base_dir start with it
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+        let mut list = RecursiveDirList {
+            schema_version: Some(crate::SCHEMA_VERSION),
+            base_dir: dir1.clone(),
+            deltas: fs.read_dir_recurse(&dir1).await.unwrap(),
+            digests: vec![],
+            truncated: false,
+        };
+        let count = list.deltas.len();
+        list.prune_under(&Path::try_from(["dir2"].as_slice()).unwrap());
+        assert_eq!(list.deltas.len(), count);
+        list.prune_under(&dir2);
+        let mut paths: Vec<String> = list.deltas.iter().map(|d| d.path.to_string()).collect();
+        paths.sort();
+        assert_eq!(paths, ["dir1/file3.txt", "dir1/file7.md", "dir1/file8.rs"]);
+    }
+
+    #[tokio::test]
+    async fn test_summary() {
+        let mut list = dir_list().await;
+        assert_eq!(
+            list.summary(),
+            TreeSummary {
+                total_files: 7,
+                total_dirs: 4,
+                total_bytes: 0,
+            }
+        );
+
+        let file1 = list
+            .deltas
+            .iter_mut()
+            .find(|item| item.path.basename() == Some("file1.txt"))
+            .unwrap();
+        file1.stats.size = 10;
+        let dir1 = list
+            .deltas
+            .iter_mut()
+            .find(|item| item.path.basename() == Some("dir1"))
+            .unwrap();
+        dir1.stats.size = 100;
+        assert_eq!(list.summary().total_bytes, 10);
+    }
 }
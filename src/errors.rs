@@ -76,4 +76,151 @@ pub enum Error {
         /// The invalid path description.
         what: String,
     },
+
+    /// Error wrapping another error with context about what was being done,
+    /// see `Error::with_context`.
+    #[error("{context}: {error}")]
+    Context {
+        /// What was being done when the error occurred.
+        context: String,
+        /// The underlying error.
+        #[source]
+        error: Box<Error>,
+    },
+}
+
+/// Coarse categories of `Error`, e.g. to map errors to HTTP status codes in one
+/// place.
+#[cfg_attr(feature = "json_schema", derive(JsonSchema))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Hash, Eq)]
+pub enum ErrorKind {
+    /// The requested path doesn't exist.
+    NotFound,
+    /// Access to the path was denied.
+    Permission,
+    /// Reading or writing the filesystem or a channel failed.
+    Io,
+    /// The caller provided an invalid argument.
+    Invalid,
+    /// Data couldn't be parsed.
+    Parse,
+    /// The operation conflicts with the current state, e.g. the file exists.
+    Conflict,
+}
+
+impl Error {
+    /// Returns the category of this error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Read { .. }
+            | Error::Create { .. }
+            | Error::Write { .. }
+            | Error::Delete { .. }
+            | Error::Sync { .. } => ErrorKind::Io,
+            Error::InvalidArgument(_) => ErrorKind::Invalid,
+            Error::Parse { .. } => ErrorKind::Parse,
+            Error::FileExists(_) => ErrorKind::Conflict,
+            Error::InvalidPath { .. } => ErrorKind::NotFound,
+            Error::Context { error, .. } => error.kind(),
+        }
+    }
+
+    /// Wraps this error with `ctx` describing what was being done, e.g.
+    /// "while building tree for dir1". The kind of the error is preserved.
+    pub fn with_context(self, ctx: &str) -> Error {
+        Error::Context {
+            context: ctx.to_string(),
+            error: Box::new(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind() {
+        let what = String::from("what");
+        let how = String::from("how");
+        let cases = [
+            (
+                Error::Read {
+                    what: what.clone(),
+                    how: how.clone(),
+                },
+                ErrorKind::Io,
+            ),
+            (Error::InvalidArgument(what.clone()), ErrorKind::Invalid),
+            (
+                Error::Parse {
+                    what: what.clone(),
+                    how: how.clone(),
+                },
+                ErrorKind::Parse,
+            ),
+            (Error::FileExists(what.clone()), ErrorKind::Conflict),
+            (
+                Error::Create {
+                    what: what.clone(),
+                    how: how.clone(),
+                },
+                ErrorKind::Io,
+            ),
+            (
+                Error::Write {
+                    what: what.clone(),
+                    how: how.clone(),
+                },
+                ErrorKind::Io,
+            ),
+            (
+                Error::Delete {
+                    what: what.clone(),
+                    how: how.clone(),
+                },
+                ErrorKind::Io,
+            ),
+            (
+                Error::Sync {
+                    what: what.clone(),
+                    how: how.clone(),
+                },
+                ErrorKind::Io,
+            ),
+            (
+                Error::InvalidPath { what: what.clone() },
+                ErrorKind::NotFound,
+            ),
+            (
+                Error::FileExists(what).with_context("while copying"),
+                ErrorKind::Conflict,
+            ),
+        ];
+        for (error, kind) in cases {
+            assert_eq!(error.kind(), kind, "for {error}");
+        }
+    }
+
+    #[test]
+    fn test_with_context() {
+        let error = Error::Read {
+            what: "metadata".into(),
+            how: "No such file or directory".into(),
+        }
+        .with_context("while building tree for dir1");
+        assert_eq!(
+            error.to_string(),
+            "while building tree for dir1: Failed to read metadata: No such file or directory"
+        );
+
+        let error = error.with_context("while syncing");
+        assert_eq!(
+            error.to_string(),
+            "while syncing: while building tree for dir1: Failed to read metadata: No such file \
+             or directory"
+        );
+        assert_eq!(error.kind(), ErrorKind::Io);
+    }
 }
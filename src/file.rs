@@ -1,6 +1,11 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fs::Metadata;
+#[cfg(all(feature = "zstd", not(target_arch = "wasm32")))]
+use std::io::Read;
 use std::time::SystemTime;
 
+use derivative::Derivative;
 #[cfg(feature = "poem")]
 use poem_openapi::Object;
 #[cfg(feature = "json_schema")]
@@ -8,15 +13,25 @@ use schemars::JsonSchema;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::Error;
+use crate::hash::DEFAULT_HASH_BUFFER_SIZE;
+use crate::hash::DigestEncoding;
+use crate::hash::HashAlgorithm;
+use crate::hash::RetryPolicy;
 use crate::path::Path;
-use crate::utils::format_system_time;
+use crate::utils::Precision;
+use crate::utils::format_system_time_with;
 
 /// Represents the metadata of a file or directory, including its path, size,
 /// modification time, and type.
+///
+/// `dev` and `ino` identify the file on the host that produced the stats and,
+/// like `is_symlink`, do not take part in equality or hashing.
 #[cfg_attr(feature = "json_schema", derive(JsonSchema))]
 #[cfg_attr(feature = "poem", derive(Object))]
 #[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Derivative, Eq)]
+#[derivative(PartialEq, Hash)]
 pub struct FileStat {
     /// The size of the file in bytes. For directories, this may be zero or
     /// implementation-defined.
@@ -27,8 +42,26 @@ pub struct FileStat {
     /// Whether this entry is a directory.
     pub is_directory: bool,
     /// Optional digest of the file contents.
-    /// This allows us faster directory browsing.
+    /// This allows us faster directory browsing. Digests of algorithms other
+    /// than sha256 are tagged, see `HashAlgorithm`.
     pub sha256: Option<String>,
+    /// Optional extended attributes of the file, keyed by attribute name.
+    /// Captured only when enabled with `PortableFs::set_capture_xattrs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xattrs: Option<BTreeMap<String, Vec<u8>>>,
+    /// Optional id of the device containing the file. Populated on unix only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[derivative(PartialEq = "ignore", Hash = "ignore")]
+    pub dev: Option<u64>,
+    /// Optional inode number of the file on `dev`. Populated on unix only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[derivative(PartialEq = "ignore", Hash = "ignore")]
+    pub ino: Option<u64>,
+    /// Whether the entry is a symbolic link. Only known when the stats were
+    /// loaded from a directory walk or from `symlink_metadata`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[derivative(PartialEq = "ignore", Hash = "ignore")]
+    pub is_symlink: bool,
 }
 
 impl FileStat {
@@ -39,12 +72,183 @@ impl FileStat {
     /// using `format_system_time`. The optional `sha256` can be set to `None`
     /// for directories or omitted values.
     pub fn from_metadata(metadata: &Metadata, sha256: Option<String>) -> Self {
+        Self::from_metadata_with(metadata, sha256, Precision::default())
+    }
+
+    /// Like `from_metadata` but formats the modification time with
+    /// `precision`.
+    pub fn from_metadata_with(
+        metadata: &Metadata,
+        sha256: Option<String>,
+        precision: Precision,
+    ) -> Self {
         let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        #[cfg(unix)]
+        let (dev, ino) = {
+            use std::os::unix::fs::MetadataExt;
+            (Some(metadata.dev()), Some(metadata.ino()))
+        };
+        #[cfg(not(unix))]
+        let (dev, ino) = (None, None);
         FileStat {
             size: metadata.len(),
-            mtime: format_system_time(modified),
+            mtime: format_system_time_with(modified, precision),
             is_directory: metadata.is_dir(),
             sha256,
+            xattrs: None,
+            dev,
+            ino,
+            is_symlink: metadata.file_type().is_symlink(),
+        }
+    }
+
+    /// Returns true if both stats were loaded from the same file on the same
+    /// host, e.g. two paths hardlinked to one inode. Returns false if either
+    /// lacks `dev` or `ino`.
+    pub fn same_file(&self, other: &Self) -> bool {
+        self.dev.is_some() && self.ino.is_some() && self.dev == other.dev && self.ino == other.ino
+    }
+
+    /// Returns true if this is a file of zero bytes. Directories are never
+    /// empty by this measure, see `PortableFs::is_dir_empty`.
+    pub fn is_empty(&self) -> bool {
+        !self.is_directory && self.size == 0
+    }
+
+    /// Returns the fields that differ from `other`, e.g. to show what changed.
+    /// Extended attributes and file identifiers are not compared.
+    pub fn diff(&self, other: &Self) -> Vec<FieldDiff> {
+        let mut diffs = vec![];
+        if self.size != other.size {
+            diffs.push(FieldDiff::Size {
+                old: self.size,
+                new: other.size,
+            });
+        }
+        if self.mtime != other.mtime {
+            diffs.push(FieldDiff::Mtime {
+                old: self.mtime.clone(),
+                new: other.mtime.clone(),
+            });
+        }
+        if self.sha256 != other.sha256 {
+            diffs.push(FieldDiff::Sha256 {
+                old: self.sha256.clone(),
+                new: other.sha256.clone(),
+            });
+        }
+        if self.is_directory != other.is_directory {
+            diffs.push(FieldDiff::IsDirectory {
+                old: self.is_directory,
+                new: other.is_directory,
+            });
+        }
+        diffs
+    }
+
+    /// Returns true if both stats describe the same contents, ignoring the
+    /// modification time. Useful to avoid spurious deltas after a file was
+    /// touched or copied.
+    pub fn content_eq(&self, other: &Self) -> bool {
+        self.is_directory == other.is_directory
+            && self.size == other.size
+            && self.sha256 == other.sha256
+    }
+
+    /// Like `==` but digests missing from either side, e.g. from a listing
+    /// that skipped digests, are assumed to match.
+    pub fn eq_ignoring_missing_digest(&self, other: &Self) -> bool {
+        let digests_match = match (&self.sha256, &other.sha256) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        digests_match
+            && self.size == other.size
+            && self.mtime == other.mtime
+            && self.is_directory == other.is_directory
+            && self.xattrs == other.xattrs
+    }
+}
+
+/// A field that differs between two `FileStat`s, with its old and new values.
+/// See `FileStat::diff`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
+pub enum FieldDiff {
+    /// `FileStat::size` differs.
+    Size {
+        /// The size in the first stats.
+        old: u64,
+        /// The size in the second stats.
+        new: u64,
+    },
+    /// `FileStat::mtime` differs.
+    Mtime {
+        /// The mtime in the first stats.
+        old: String,
+        /// The mtime in the second stats.
+        new: String,
+    },
+    /// `FileStat::sha256` differs.
+    Sha256 {
+        /// The digest in the first stats.
+        old: Option<String>,
+        /// The digest in the second stats.
+        new: Option<String>,
+    },
+    /// `FileStat::is_directory` differs.
+    IsDirectory {
+        /// The flag in the first stats.
+        old: bool,
+        /// The flag in the second stats.
+        new: bool,
+    },
+}
+
+impl std::fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldDiff::Size { old, new } => write!(f, "size: {old} -> {new}"),
+            FieldDiff::Mtime { old, new } => write!(f, "mtime: {old} -> {new}"),
+            FieldDiff::Sha256 { old, new } => write!(f, "sha256: {old:?} -> {new:?}"),
+            FieldDiff::IsDirectory { old, new } => write!(f, "is_directory: {old} -> {new}"),
+        }
+    }
+}
+
+/// Options controlling what is captured when loading a `FileStat` from disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatOptions {
+    /// Whether to read extended attributes into `FileStat::xattrs`.
+    pub capture_xattrs: bool,
+    /// Algorithm used to digest file contents.
+    pub hash_algorithm: HashAlgorithm,
+    /// How digests are rendered into `FileStat::sha256`.
+    pub digest_encoding: DigestEncoding,
+    /// Size of the buffer used to read files while hashing. Must be non zero.
+    pub hash_buffer_size: usize,
+    /// Policy to retry transient IO errors while hashing.
+    pub retry_policy: RetryPolicy,
+    /// Precision of `FileStat::mtime`.
+    pub mtime_precision: Precision,
+    /// Whether files of at least `hash::MMAP_THRESHOLD` bytes are hashed
+    /// through a memory map instead of being read in chunks. Off by default:
+    /// if another process truncates a file while it is mapped, the process
+    /// is killed by SIGBUS.
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    pub use_mmap: bool,
+}
+
+impl Default for StatOptions {
+    fn default() -> Self {
+        Self {
+            capture_xattrs: false,
+            hash_algorithm: HashAlgorithm::default(),
+            digest_encoding: DigestEncoding::default(),
+            hash_buffer_size: DEFAULT_HASH_BUFFER_SIZE,
+            retry_policy: RetryPolicy::default(),
+            mtime_precision: Precision::default(),
+            #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+            use_mmap: false,
         }
     }
 }
@@ -60,6 +264,69 @@ pub struct FileInfo {
     pub path: Path,
     /// Metadata if the file exists.
     pub stats: FileStat,
+    /// Index of this file's digest in the owning `RecursiveDirList::digests`
+    /// when the list is interned, in which case `stats.sha256` is `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest_index: Option<u32>,
+}
+
+impl FileInfo {
+    /// Returns a copy of the entry with `old_base` replaced by `new_base` at
+    /// the start of its path, e.g. to present entries walked under one root
+    /// as if they were under another.
+    ///
+    /// Returns `Error::InvalidPath` if the path is not under `old_base`.
+    pub fn rebase(&self, old_base: &Path, new_base: &Path) -> Result<FileInfo, Error> {
+        let rest = self
+            .path
+            .canonical_components()
+            .strip_prefix(old_base.canonical_components())
+            .ok_or_else(|| Error::InvalidPath {
+                what: format!("{} is not under {old_base}", self.path),
+            })?;
+        Ok(FileInfo {
+            path: new_base.join(&Path::try_from(rest)?),
+            ..self.clone()
+        })
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn sort_key(
+        &self,
+    ) -> (
+        &Path,
+        u64,
+        &str,
+        bool,
+        &Option<String>,
+        &Option<BTreeMap<String, Vec<u8>>>,
+        Option<u32>,
+    ) {
+        (
+            &self.path,
+            self.stats.size,
+            &self.stats.mtime,
+            self.stats.is_directory,
+            &self.stats.sha256,
+            &self.stats.xattrs,
+            self.digest_index,
+        )
+    }
+}
+
+impl PartialOrd for FileInfo {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders by `path`, so sorted lists are in directory-tree order. Entries with
+/// the same path are ordered by the stats fields that take part in equality,
+/// then by `digest_index`.
+impl Ord for FileInfo {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
 }
 
 /// Akin to inode, represents the a file or directory, including its path, size,
@@ -81,6 +348,48 @@ impl FileNode {
     pub fn new(stats: FileStat, contents: Vec<u8>) -> Self {
         Self { stats, contents }
     }
+
+    /// Returns the contents compressed with zstd, e.g. to transmit them.
+    /// `stats` are left out and their digest remains that of the
+    /// uncompressed contents.
+    #[cfg(all(feature = "zstd", not(target_arch = "wasm32")))]
+    pub fn compressed(&self) -> Result<Vec<u8>, Error> {
+        zstd::bulk::compress(&self.contents, zstd::DEFAULT_COMPRESSION_LEVEL).map_err(|e| {
+            Error::Write {
+                what: "compressed contents".into(),
+                how: e.to_string(),
+            }
+        })
+    }
+
+    /// Creates a `FileNode` from `stats` and contents compressed with
+    /// `compressed`.
+    ///
+    /// Returns `Error::Parse` if `bytes` are not zstd compressed or don't
+    /// decompress to exactly `stats.size` bytes.
+    #[cfg(all(feature = "zstd", not(target_arch = "wasm32")))]
+    pub fn from_compressed(stats: FileStat, bytes: &[u8]) -> Result<Self, Error> {
+        let parse_error = |how: String| Error::Parse {
+            what: "compressed contents".into(),
+            how,
+        };
+        let decoder =
+            zstd::stream::read::Decoder::new(bytes).map_err(|e| parse_error(e.to_string()))?;
+        // Allocate as data is decoded rather than up front from the untrusted
+        // `stats.size`, and stop one byte past it to detect larger contents
+        let mut contents = Vec::new();
+        decoder
+            .take(stats.size.saturating_add(1))
+            .read_to_end(&mut contents)
+            .map_err(|e| parse_error(e.to_string()))?;
+        if contents.len() as u64 != stats.size {
+            return Err(parse_error(format!(
+                "decompressed size differs from {}",
+                stats.size
+            )));
+        }
+        Ok(Self::new(stats, contents))
+    }
 }
 
 impl From<(FileStat, Vec<u8>)> for FileNode {
@@ -100,4 +409,209 @@ mod tests {
         assert_eq!(s, &serde_json::to_string(&path).unwrap());
         assert_eq!(path, serde_json::from_str(s).unwrap());
     }
+
+    fn file_stat(mtime: &str) -> FileStat {
+        FileStat {
+            size: 13,
+            mtime: mtime.into(),
+            is_directory: false,
+            sha256: Some("digest".into()),
+            xattrs: None,
+            dev: None,
+            ino: None,
+            is_symlink: false,
+        }
+    }
+
+    #[cfg(all(feature = "zstd", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_compressed_round_trip() {
+        let contents = "Hello, world!\n".repeat(1000).into_bytes();
+        let stats = FileStat {
+            size: contents.len() as u64,
+            sha256: Some(HashAlgorithm::Sha256.digest_bytes(&contents).await.unwrap()),
+            ..file_stat("2018-01-26T18:30:09.453Z")
+        };
+        let node = FileNode::new(stats.clone(), contents.clone());
+
+        let compressed = node.compressed().unwrap();
+        assert!(compressed.len() < contents.len());
+        let restored = FileNode::from_compressed(stats.clone(), &compressed).unwrap();
+        assert_eq!(restored, node);
+        let digest = restored.stats.sha256.as_ref().unwrap();
+        assert!(
+            crate::hash::verify(&restored.contents, digest)
+                .await
+                .unwrap()
+        );
+
+        let empty = FileNode::new(
+            FileStat {
+                size: 0,
+                ..stats.clone()
+            },
+            vec![],
+        );
+        let restored = FileNode::from_compressed(empty.stats.clone(), &empty.compressed().unwrap());
+        assert_eq!(restored.unwrap(), empty);
+
+        let truncated = FileStat {
+            size: 10,
+            ..stats.clone()
+        };
+        assert!(matches!(
+            FileNode::from_compressed(truncated.clone(), &compressed),
+            Err(Error::Parse { .. })
+        ));
+        assert!(matches!(
+            FileNode::from_compressed(truncated, b"not zstd"),
+            Err(Error::Parse { .. })
+        ));
+
+        // Sizes the contents don't match are rejected without allocating
+        // for them
+        for size in [contents.len() as u64 + 1, u64::MAX] {
+            assert!(matches!(
+                FileNode::from_compressed(
+                    FileStat {
+                        size,
+                        ..stats.clone()
+                    },
+                    &compressed
+                ),
+                Err(Error::Parse { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let mut stat = file_stat("2018-01-26T18:30:09.453Z");
+        assert!(!stat.is_empty());
+        stat.size = 0;
+        assert!(stat.is_empty());
+        stat.is_directory = true;
+        assert!(!stat.is_empty());
+    }
+
+    #[test]
+    fn test_diff_single_field() {
+        let a = file_stat("2018-01-26T18:30:09.453Z");
+        assert_eq!(a.diff(&a), []);
+
+        let mut b = a.clone();
+        b.size = 14;
+        assert_eq!(a.diff(&b), [FieldDiff::Size { old: 13, new: 14 }]);
+
+        let b = file_stat("2025-11-20T00:35:58.153Z");
+        assert_eq!(
+            a.diff(&b),
+            [FieldDiff::Mtime {
+                old: a.mtime.clone(),
+                new: b.mtime.clone()
+            }]
+        );
+
+        let mut b = a.clone();
+        b.sha256 = None;
+        assert_eq!(
+            a.diff(&b),
+            [FieldDiff::Sha256 {
+                old: Some("digest".into()),
+                new: None
+            }]
+        );
+
+        let mut b = a.clone();
+        b.is_directory = true;
+        assert_eq!(
+            a.diff(&b),
+            [FieldDiff::IsDirectory {
+                old: false,
+                new: true
+            }]
+        );
+        assert_eq!(a.diff(&b)[0].to_string(), "is_directory: false -> true");
+    }
+
+    #[test]
+    fn test_content_eq_ignores_mtime() {
+        let a = file_stat("2018-01-26T18:30:09.453Z");
+        let b = file_stat("2025-11-20T00:35:58.153Z");
+        assert!(a.content_eq(&b));
+        assert_ne!(a, b);
+
+        let mut c = b.clone();
+        c.sha256 = Some("other".into());
+        assert!(!a.content_eq(&c));
+
+        let mut d = b.clone();
+        d.size += 1;
+        assert!(!a.content_eq(&d));
+    }
+
+    #[test]
+    fn test_file_info_ordering() {
+        let info = |components: &[&str], mtime: &str| FileInfo {
+            path: Path::try_from(components).unwrap(),
+            stats: file_stat(mtime),
+            digest_index: None,
+        };
+        let old = "2018-01-26T18:30:09.453Z";
+        let new = "2025-11-20T00:35:58.153Z";
+        let expected = vec![
+            info(&["a"], old),
+            info(&["a", "b"], old),
+            info(&["a", "b", "c"], old),
+            info(&["a", "b", "c"], new),
+            info(&["a", "d"], old),
+            info(&["a-b"], old),
+            info(&["b"], old),
+        ];
+
+        let mut shuffled = expected.clone();
+        shuffled.reverse();
+        shuffled.swap(1, 4);
+        shuffled.sort();
+        assert_eq!(shuffled, expected);
+
+        let mut other = info(&["a"], old);
+        other.stats.dev = Some(1);
+        assert_eq!(other.cmp(&expected[0]), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_eq_ignoring_missing_digest() {
+        let old = "2018-01-26T18:30:09.453Z";
+        let digest = |sha256: Option<&str>| FileStat {
+            sha256: sha256.map(str::to_owned),
+            ..file_stat(old)
+        };
+        let cases = [
+            (None, None, true),
+            (None, Some("digest"), true),
+            (Some("digest"), None, true),
+            (Some("digest"), Some("digest"), true),
+            (Some("digest"), Some("other"), false),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(
+                digest(a).eq_ignoring_missing_digest(&digest(b)),
+                expected,
+                "for {a:?} and {b:?}"
+            );
+        }
+        assert_ne!(digest(None), digest(Some("digest")));
+
+        let newer = FileStat {
+            sha256: None,
+            ..file_stat("2025-11-20T00:35:58.153Z")
+        };
+        assert!(!newer.eq_ignoring_missing_digest(&digest(Some("digest"))));
+        let larger = FileStat {
+            size: 14,
+            ..digest(None)
+        };
+        assert!(!larger.eq_ignoring_missing_digest(&digest(None)));
+    }
 }
@@ -1,6 +1,9 @@
+use std::collections::HashMap;
 use std::collections::HashSet;
 #[cfg(not(target_arch = "wasm32"))]
 use std::ffi::OsStr;
+#[cfg(not(target_arch = "wasm32"))]
+use std::ffi::OsString;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -16,7 +19,7 @@ use crate::Error;
 /// Enumertates the type of operations allowed/denied on a path
 #[cfg_attr(feature = "json_schema", derive(JsonSchema))]
 #[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
-#[derive(Debug, Clone, Serialize, Deserialize, Derivative, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Derivative, PartialEq, Eq, PartialOrd, Ord)]
 #[cfg(not(target_arch = "wasm32"))]
 pub enum FilterLevel {
     /// Deny traversing and returning path
@@ -30,6 +33,80 @@ pub enum FilterLevel {
     Allow,
 }
 
+/// Levels are ordered from the most restrictive, `Deny`, to the least
+/// restrictive, `Allow`.
+#[cfg(not(target_arch = "wasm32"))]
+impl FilterLevel {
+    /// Returns the level that permits the least of `a` and `b`, e.g. to
+    /// combine policies that must all agree.
+    pub fn most_restrictive(a: Self, b: Self) -> Self {
+        a.min(b)
+    }
+
+    /// Returns the level that permits the most of `a` and `b`, e.g. to
+    /// combine policies of which any may grant access.
+    pub fn least_restrictive(a: Self, b: Self) -> Self {
+        a.max(b)
+    }
+}
+
+/// Identifies the rule of a `FilterSet` that decided the fate of a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg(not(target_arch = "wasm32"))]
+pub enum FilterRule {
+    /// Path is under the contained denied root
+    DeniedRoot(PathBuf),
+
+    /// Path is under allowed roots, but deeper than their depth limits
+    DepthExceeded,
+
+    /// Path has more components than the maximum component depth
+    MaxComponentDepthExceeded,
+
+    /// Allowed roots are configured but path is not under any of them
+    MissingAllow,
+
+    /// Directory is allowed as there are no file specific rules
+    Directory,
+
+    /// Directory may contain files that match extension or filename rules
+    DirectoryTraversal,
+
+    /// File has no extension while allowed extensions are configured
+    MissingExtension,
+
+    /// File extension is not in allowed extensions
+    ExtensionMismatch,
+
+    /// File name is not in allowed filenames
+    FilenameMismatch,
+
+    /// Path is a file while only directories are allowed
+    DirectoriesOnly,
+
+    /// Path passed all the checks
+    Passed,
+}
+
+/// The result of evaluating a path against a `FilterSet` along with the rule
+/// that produced it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg(not(target_arch = "wasm32"))]
+pub struct FilterDecision {
+    /// The resulting filter level
+    pub level: FilterLevel,
+
+    /// The rule that decided the level
+    pub rule: FilterRule,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FilterDecision {
+    fn new(level: FilterLevel, rule: FilterRule) -> Self {
+        Self { level, rule }
+    }
+}
+
 /// A struct to configure and enforce path filtering rules.
 #[cfg_attr(feature = "json_schema", derive(JsonSchema))]
 #[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
@@ -52,6 +129,21 @@ pub struct FilterSet {
     /// Allowed specific file names (e.g., "README.md").
     /// If empty, checking is skipped.
     allowed_filenames: HashSet<String>,
+
+    /// Whether to allow directories and deny all files, regardless of the
+    /// extension and filename rules.
+    #[serde(default)]
+    directories_only: bool,
+
+    /// Maximum depth of the entries under allowed roots, keyed by root.
+    /// Roots missing from the map have no limit.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    #[cfg_attr(feature = "utoipa", schema(value_type = HashMap<String, usize>))]
+    root_depths: HashMap<PathBuf, usize>,
+
+    /// Maximum number of components of any path, regardless of roots.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_component_depth: Option<usize>,
 }
 
 impl FilterSet {
@@ -61,7 +153,6 @@ impl FilterSet {
     }
 
     /// Create a new fitler with given filters
-    #[allow(dead_code)]
     pub fn create_with<P: AsRef<Path>, S: AsRef<str>>(
         allowed_paths: &[P],
         denied_paths: &[P],
@@ -85,55 +176,242 @@ impl FilterSet {
                 .iter()
                 .map(|e| e.as_ref().to_lowercase())
                 .collect(),
+            directories_only: false,
+            root_depths: HashMap::new(),
+            max_component_depth: None,
         }
     }
 
+    /// Add new allow filter.
+    /// Deny list overrides allow list
     pub fn allow_path<P: AsRef<Path>>(&mut self, path: P) {
+        self.root_depths.remove(path.as_ref());
         self.allowed_roots.push(path.as_ref().to_path_buf());
     }
 
+    /// Like `allow_path` but only allows entries up to `max_depth` levels
+    /// under `path`: `1` allows the entries of `path` but nothing under its
+    /// subdirectories. A path under several allowed roots is allowed if any
+    /// of them allows its depth.
+    pub fn allow_path_with_depth<P: AsRef<Path>>(&mut self, path: P, max_depth: usize) {
+        let path = path.as_ref().to_path_buf();
+        if !self.allowed_roots.contains(&path) {
+            self.allowed_roots.push(path.clone());
+        }
+        self.root_depths.insert(path, max_depth);
+    }
+
+    /// Add new deny filter.
+    /// Deny list overrides allow list
     pub fn deny_path<P: AsRef<Path>>(&mut self, path: P) {
         self.denied_roots.push(path.as_ref().to_path_buf());
     }
 
+    /// Add an extension to allowed extention list
     pub fn allow_extension(&mut self, ext: &str) {
         self.allowed_extensions.insert(ext.to_lowercase());
     }
 
+    /// Add filename to allowed filename list
     pub fn allow_filename(&mut self, name: &str) {
         self.allowed_filenames.insert(name.to_string());
     }
 
+    /// When set, directories are allowed and all files are denied, e.g. for a
+    /// folder picker. Extension and filename rules are ignored, while allowed
+    /// and denied roots still apply.
+    pub fn set_directories_only(&mut self, directories_only: bool) {
+        self.directories_only = directories_only;
+    }
+
+    /// Denies paths of more than `max_depth` components, e.g. to bound the
+    /// size of listings, whatever the allowed roots. `Some(1)` allows only
+    /// the entries of the base dir. `None` removes the limit.
+    pub fn set_max_component_depth(&mut self, max_depth: Option<usize>) {
+        self.max_component_depth = max_depth;
+    }
+
+    /// Merges rules of `other` into this filter set.
+    ///
+    /// Extensions, filenames, allowed and denied roots are unioned. As usual,
+    /// denied roots override allowed roots. Roots allowed by both keep the
+    /// least restrictive depth limit, while the maximum component depth keeps
+    /// the most restrictive one.
+    pub fn merge(&mut self, other: &FilterSet) {
+        for root in &other.allowed_roots {
+            let other_depth = other.root_depths.get(root).copied();
+            if !self.allowed_roots.contains(root) {
+                self.allowed_roots.push(root.clone());
+                if let Some(depth) = other_depth {
+                    self.root_depths.insert(root.clone(), depth);
+                }
+                continue;
+            }
+            match (self.root_depths.get(root).copied(), other_depth) {
+                (Some(depth), Some(other_depth)) => {
+                    self.root_depths
+                        .insert(root.clone(), depth.max(other_depth));
+                }
+                (Some(_), None) => {
+                    self.root_depths.remove(root);
+                }
+                (None, _) => {}
+            }
+        }
+        for root in &other.denied_roots {
+            if !self.denied_roots.contains(root) {
+                self.denied_roots.push(root.clone());
+            }
+        }
+        self.allowed_extensions
+            .extend(other.allowed_extensions.iter().cloned());
+        self.allowed_filenames
+            .extend(other.allowed_filenames.iter().cloned());
+        self.directories_only |= other.directories_only;
+        self.max_component_depth = match (self.max_component_depth, other.max_component_depth) {
+            (Some(depth), Some(other_depth)) => Some(depth.min(other_depth)),
+            (depth, other_depth) => depth.or(other_depth),
+        };
+    }
+
     /// Determines if a path matches the filter criteria.
     ///
-    /// Returns `true` if the path passes all checks.
+    /// `path` must be relative to the base dir the rules were written
+    /// against, as roots are matched as path prefixes. Use `matches_absolute`
+    /// for paths that still carry the base dir.
+    ///
+    /// Returns `Error::InvalidArgument` if `path` is absolute.
     #[cfg(not(target_arch = "wasm32"))]
     pub fn matches<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> Result<FilterLevel, Error> {
+        Ok(self.explain(path, is_dir)?.level)
+    }
+
+    /// Like `matches` but for a `path` under `base`, which is stripped before
+    /// matching.
+    ///
+    /// Returns `Error::InvalidArgument` if `path` is not under `base`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn matches_absolute<B: AsRef<Path>, P: AsRef<Path>>(
+        &self,
+        base: B,
+        path: P,
+        is_dir: bool,
+    ) -> Result<FilterLevel, Error> {
+        let (base, path) = (base.as_ref(), path.as_ref());
+        let relative = path.strip_prefix(base).map_err(|_| {
+            Error::InvalidArgument(format!(
+                "{} is not under {}",
+                path.display(),
+                base.display()
+            ))
+        })?;
+        self.matches(relative, is_dir)
+    }
+
+    /// Evaluates a path like `matches` but also reports the rule that decided
+    /// the resulting `FilterLevel`. Useful to debug why a path is present or
+    /// missing from a listing.
+    ///
+    /// Returns `Error::InvalidArgument` if `path` is absolute.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn explain<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> Result<FilterDecision, Error> {
         let path = path.as_ref();
+        if path.has_root() {
+            return Err(Error::InvalidArgument(format!(
+                "filter expects a relative path, found {}",
+                path.display()
+            )));
+        }
 
         // Check Deny List
         // If the path starts with any denied root, it is rejected.
         for denied in &self.denied_roots {
             if path.starts_with(denied) {
-                return Ok(FilterLevel::Deny);
+                return Ok(FilterDecision::new(
+                    FilterLevel::Deny,
+                    FilterRule::DeniedRoot(denied.clone()),
+                ));
             }
         }
 
+        if self.exceeds_max_component_depth(path) {
+            return Ok(FilterDecision::new(
+                FilterLevel::Deny,
+                FilterRule::MaxComponentDepthExceeded,
+            ));
+        }
+
         // Check Allow List
         // If we have allowed roots, the path MUST start with one of them.
         if !self.allowed_roots.is_empty() {
-            let matches_allow = self.allowed_roots.iter().any(|root| path.starts_with(root));
-            if !matches_allow {
-                return Ok(FilterLevel::Deny);
+            let mut allowing = self
+                .allowed_roots
+                .iter()
+                .filter(|root| path.starts_with(root))
+                .peekable();
+            if allowing.peek().is_none() {
+                return Ok(FilterDecision::new(
+                    FilterLevel::Deny,
+                    FilterRule::MissingAllow,
+                ));
+            }
+            let depth = path.components().count();
+            let within_depth = allowing.any(|root| {
+                self.root_depths
+                    .get(root)
+                    .is_none_or(|max| depth - root.components().count() <= *max)
+            });
+            if !within_depth {
+                return Ok(FilterDecision::new(
+                    FilterLevel::Deny,
+                    FilterRule::DepthExceeded,
+                ));
             }
         }
 
+        Ok(self.explain_entry(path, is_dir))
+    }
+
+    /// Compiles the rules into a `CompiledFilterSet` for fast repeated
+    /// matching, e.g. over all the entries of a walk.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn compile(&self) -> CompiledFilterSet {
+        CompiledFilterSet {
+            denied_roots: RootTrie::from_roots(&self.denied_roots),
+            allowed_roots: (!self.allowed_roots.is_empty()).then(|| {
+                let mut trie = RootTrie::from_roots(&self.allowed_roots);
+                for (root, max_depth) in &self.root_depths {
+                    trie.set_max_depth(root, *max_depth);
+                }
+                trie
+            }),
+            rules: self.clone(),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn exceeds_max_component_depth(&self, path: &Path) -> bool {
+        self.max_component_depth
+            .is_some_and(|max| path.components().count() > max)
+    }
+
+    // Evaluates the rules that don't depend on roots.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn explain_entry(&self, path: &Path, is_dir: bool) -> FilterDecision {
+        if self.directories_only {
+            return if is_dir {
+                FilterDecision::new(FilterLevel::Allow, FilterRule::Directory)
+            } else {
+                FilterDecision::new(FilterLevel::Deny, FilterRule::DirectoriesOnly)
+            };
+        }
+
         if is_dir && self.allowed_extensions.is_empty() && self.allowed_filenames.is_empty() {
-            return Ok(FilterLevel::Allow);
+            return FilterDecision::new(FilterLevel::Allow, FilterRule::Directory);
         } else if is_dir {
             // There might be more files under the dir that might match filter
             // criteria
-            return Ok(FilterLevel::Traverse);
+            return FilterDecision::new(FilterLevel::Traverse, FilterRule::DirectoryTraversal);
         }
 
         // File-specific checks (Extension and Filename)
@@ -141,19 +419,19 @@ impl FilterSet {
         if !self.allowed_extensions.is_empty() {
             if let Some(ext) = path.extension() {
                 if !self.check_extension(ext) {
-                    return Ok(FilterLevel::Deny);
+                    return FilterDecision::new(FilterLevel::Deny, FilterRule::ExtensionMismatch);
                 }
             } else {
-                return Ok(FilterLevel::Deny);
+                return FilterDecision::new(FilterLevel::Deny, FilterRule::MissingExtension);
             }
         }
 
         // Check Filename specifically (if configured)
         if !self.allowed_filenames.is_empty() && !self.check_filename(path) {
-            return Ok(FilterLevel::Deny);
+            return FilterDecision::new(FilterLevel::Deny, FilterRule::FilenameMismatch);
         }
 
-        Ok(FilterLevel::Allow)
+        FilterDecision::new(FilterLevel::Allow, FilterRule::Passed)
     }
 
     #[cfg(not(target_arch = "wasm32"))]
@@ -173,6 +451,111 @@ impl FilterSet {
     }
 }
 
+/// Roots stored by path component, to find whether any of them is a prefix of
+/// a path in a single pass over the path.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Default)]
+struct RootTrie {
+    children: HashMap<OsString, RootTrie>,
+    is_root: bool,
+    // Depth limit of the entries under this root, if any
+    max_depth: Option<usize>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl RootTrie {
+    fn from_roots(roots: &[PathBuf]) -> Self {
+        let mut trie = RootTrie::default();
+        for root in roots {
+            let mut node = &mut trie;
+            for component in root.components() {
+                node = node
+                    .children
+                    .entry(component.as_os_str().to_owned())
+                    .or_default();
+            }
+            node.is_root = true;
+        }
+        trie
+    }
+
+    fn set_max_depth(&mut self, root: &Path, max_depth: usize) {
+        let mut node = self;
+        for component in root.components() {
+            match node.children.get_mut(component.as_os_str()) {
+                Some(child) => node = child,
+                None => return,
+            }
+        }
+        node.max_depth = Some(max_depth);
+    }
+
+    // Same as `roots.iter().any(|root| path.starts_with(root))`, ignoring
+    // depth limits.
+    fn has_prefix_of(&self, path: &Path) -> bool {
+        self.find_prefix_of(path, false).is_some()
+    }
+
+    // Returns whether `path` is under a root, and whether one of those roots
+    // allows its depth. Stops at the first root if `check_depth` is false.
+    fn find_prefix_of(&self, path: &Path, check_depth: bool) -> Option<bool> {
+        let depth = path.components().count();
+        let mut found = None;
+        let mut node = self;
+        for (consumed, component) in path.components().enumerate() {
+            if node.is_root {
+                let allows = node.max_depth.is_none_or(|max| depth - consumed <= max);
+                if allows || !check_depth {
+                    return Some(allows);
+                }
+                found = Some(false);
+            }
+            match node.children.get(component.as_os_str()) {
+                Some(child) => node = child,
+                None => return found,
+            }
+        }
+        if node.is_root { Some(true) } else { found }
+    }
+}
+
+/// A `FilterSet` compiled with `FilterSet::compile`. Matches paths in time
+/// proportional to their length rather than to the number of roots.
+///
+/// Later changes to the source `FilterSet` are not reflected.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone)]
+pub struct CompiledFilterSet {
+    denied_roots: RootTrie,
+    allowed_roots: Option<RootTrie>,
+    rules: FilterSet,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl CompiledFilterSet {
+    /// Same as `FilterSet::matches` on the source filter set.
+    ///
+    /// Returns `Error::InvalidArgument` if `path` is absolute.
+    pub fn matches<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> Result<FilterLevel, Error> {
+        let path = path.as_ref();
+        if path.has_root() {
+            return Err(Error::InvalidArgument(format!(
+                "filter expects a relative path, found {}",
+                path.display()
+            )));
+        }
+        if self.denied_roots.has_prefix_of(path) || self.rules.exceeds_max_component_depth(path) {
+            return Ok(FilterLevel::Deny);
+        }
+        if let Some(roots) = &self.allowed_roots
+            && roots.find_prefix_of(path, true) != Some(true)
+        {
+            return Ok(FilterLevel::Deny);
+        }
+        Ok(self.rules.explain_entry(path, is_dir).level)
+    }
+}
+
 #[cfg(all(not(target_arch = "wasm32"), test))]
 mod tests {
     use std::path::Path as StdPath;
@@ -205,6 +588,39 @@ mod tests {
         assert_eq!(fset.matches("main.txt", false).unwrap(), FilterLevel::Deny);
     }
 
+    #[test]
+    fn test_matches_relative_and_absolute() {
+        let filterset = FilterSet::create_with::<&str, &str>(&[], &["target"], &[], &[]);
+        let base = std::env::temp_dir().join("base");
+
+        assert_eq!(
+            filterset.matches("target/main.rs", false).unwrap(),
+            FilterLevel::Deny
+        );
+        assert_eq!(
+            filterset
+                .matches_absolute(&base, base.join("target/main.rs"), false)
+                .unwrap(),
+            FilterLevel::Deny
+        );
+        assert_eq!(
+            filterset
+                .matches_absolute(&base, base.join("src/main.rs"), false)
+                .unwrap(),
+            FilterLevel::Allow
+        );
+
+        // Absolute paths would silently bypass the deny rule
+        assert!(matches!(
+            filterset.matches(base.join("target/main.rs"), false),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            filterset.matches_absolute(&base, std::env::temp_dir().join("target"), true),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
     #[test]
     fn test_filter_it_deny() {
         let filterset = FilterSet::create_with::<&str, &str>(&[], &["target"], &[], &[]);
@@ -271,4 +687,312 @@ mod tests {
             FilterLevel::Deny
         );
     }
+
+    fn check_explain(fset: &FilterSet, path: &str, is_dir: bool, rule: FilterRule) {
+        let decision = fset.explain(path, is_dir).unwrap();
+        assert_eq!(decision.rule, rule, "for {path}");
+        assert_eq!(
+            decision.level,
+            fset.matches(path, is_dir).unwrap(),
+            "for {path}"
+        );
+    }
+
+    #[test]
+    fn test_explain_with_path() {
+        let fset = FilterSet::create_with::<&str, &str>(&[], &[], &[], &["rs"]);
+
+        check_explain(&fset, "main.rs", false, FilterRule::Passed);
+        check_explain(&fset, "main_rs", true, FilterRule::DirectoryTraversal);
+        check_explain(&fset, "src/lib.rs", false, FilterRule::Passed);
+        check_explain(&fset, "main.txt", false, FilterRule::ExtensionMismatch);
+        check_explain(&fset, "main", false, FilterRule::MissingExtension);
+    }
+
+    #[test]
+    fn test_explain_deny() {
+        let fset = FilterSet::create_with::<&str, &str>(&[], &["target"], &[], &[]);
+
+        check_explain(
+            &fset,
+            "target/debug/main",
+            true,
+            FilterRule::DeniedRoot("target".into()),
+        );
+        check_explain(&fset, "main/debug/target", true, FilterRule::Directory);
+        check_explain(&fset, "main/target/debug", true, FilterRule::Directory);
+        check_explain(&fset, "src/main.rs", true, FilterRule::Directory);
+    }
+
+    #[test]
+    fn test_explain_allow_and_deny() {
+        let fset = FilterSet::create_with::<&str, &str>(&[], &[], &[], &["rs"]);
+
+        check_explain(&fset, "main.rs", false, FilterRule::Passed);
+        check_explain(&fset, "test_main.rs", false, FilterRule::Passed);
+        check_explain(&fset, "main.txt", false, FilterRule::ExtensionMismatch);
+    }
+
+    #[test]
+    fn test_explain_deny_overrides_allow() {
+        let fset =
+            FilterSet::create_with::<&str, &str>(&["target/debug"], &["target"], &[], &["rs"]);
+
+        for (path, is_dir) in [
+            ("target", false),
+            ("target", true),
+            ("target/debug", true),
+            ("target/debug", false),
+            ("target/debug/test.rs", false),
+        ] {
+            check_explain(&fset, path, is_dir, FilterRule::DeniedRoot("target".into()));
+        }
+        check_explain(&fset, "src/main.rs", false, FilterRule::MissingAllow);
+    }
+
+    #[test]
+    fn test_explain_filename() {
+        let fset = FilterSet::create_with::<&str, &str>(&[], &[], &["README.md"], &[]);
+
+        check_explain(&fset, "README.md", false, FilterRule::Passed);
+        check_explain(&fset, "LICENSE", false, FilterRule::FilenameMismatch);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut global = FilterSet::create_with::<&str, &str>(&[], &["target"], &[], &["rs"]);
+        let request = FilterSet::create_with::<&str, &str>(&[], &["docs"], &[], &["md"]);
+        global.merge(&request);
+        global.merge(&request);
+
+        assert_eq!(global.denied_roots.len(), 2);
+        assert_eq!(
+            global.matches("target/main.rs", false).unwrap(),
+            FilterLevel::Deny
+        );
+        assert_eq!(
+            global.matches("docs/README.md", false).unwrap(),
+            FilterLevel::Deny
+        );
+        assert_eq!(
+            global.matches("src/lib.rs", false).unwrap(),
+            FilterLevel::Allow
+        );
+        assert_eq!(
+            global.matches("README.md", false).unwrap(),
+            FilterLevel::Allow
+        );
+        assert_eq!(
+            global.matches("notes.txt", false).unwrap(),
+            FilterLevel::Deny
+        );
+    }
+
+    #[test]
+    fn test_merge_deny_overrides_allow() {
+        let mut fset = FilterSet::create_with::<&str, &str>(&["src"], &[], &[], &[]);
+        fset.merge(&FilterSet::create_with::<&str, &str>(
+            &["docs"],
+            &["src/generated"],
+            &[],
+            &[],
+        ));
+
+        assert_eq!(
+            fset.matches("src/lib.rs", false).unwrap(),
+            FilterLevel::Allow
+        );
+        assert_eq!(
+            fset.matches("docs/a.md", false).unwrap(),
+            FilterLevel::Allow
+        );
+        assert_eq!(
+            fset.matches("src/generated/a.rs", false).unwrap(),
+            FilterLevel::Deny
+        );
+        assert_eq!(fset.matches("target/a", false).unwrap(), FilterLevel::Deny);
+    }
+
+    #[test]
+    fn test_compiled_matches_naive() {
+        let mut fset = FilterSet::create_with(
+            &["", "src", "docs/api", "./vendor"],
+            &["target", "src/generated", "docs/api/private/", "a/./b"],
+            &["README.md", "Makefile"],
+            &["rs", "MD"],
+        );
+        for i in 0..200 {
+            fset.deny_path(format!("src/mod{i}/tmp"));
+            fset.allow_path(format!("crates/crate{i}"));
+        }
+        let mut paths = vec![
+            "",
+            "src",
+            "src/lib.rs",
+            "src/generated",
+            "src/generated/x.rs",
+            "src/generatedx.rs",
+            "target/debug/main",
+            "docs",
+            "docs/api/index.md",
+            "docs/api/private",
+            "docs/api/private/secret.md",
+            "docs/apix/index.md",
+            "README.md",
+            "README",
+            "Makefile",
+            "vendor/x.rs",
+            "./vendor/x.rs",
+            "a/b/c.rs",
+            "a/b",
+            "a/c.rs",
+            "src/./lib.rs",
+            "src//lib.rs",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+        for i in (0..200).step_by(7) {
+            paths.push(format!("src/mod{i}/tmp/file.rs"));
+            paths.push(format!("src/mod{i}/tmpfile.rs"));
+            paths.push(format!("crates/crate{i}/src/lib.rs"));
+            paths.push(format!("crates/crate{i}0x/lib.rs"));
+        }
+
+        let check = |fset: &FilterSet| {
+            let compiled = fset.compile();
+            for path in &paths {
+                for is_dir in [false, true] {
+                    assert_eq!(
+                        compiled.matches(path, is_dir).unwrap(),
+                        fset.matches(path, is_dir).unwrap(),
+                        "{path} is_dir: {is_dir}"
+                    );
+                }
+            }
+            assert!(compiled.matches("/src/lib.rs", false).is_err());
+        };
+        check(&fset);
+        check(&FilterSet::new());
+        fset.set_directories_only(true);
+        check(&fset);
+    }
+
+    #[test]
+    fn test_filter_level_ordering() {
+        use FilterLevel::*;
+        assert!(Deny < Traverse);
+        assert!(Traverse < Allow);
+        assert_eq!(FilterLevel::most_restrictive(Deny, Allow), Deny);
+        assert_eq!(FilterLevel::most_restrictive(Allow, Traverse), Traverse);
+        assert_eq!(FilterLevel::least_restrictive(Deny, Allow), Allow);
+        assert_eq!(FilterLevel::least_restrictive(Deny, Traverse), Traverse);
+        assert_eq!(FilterLevel::most_restrictive(Allow, Allow), Allow);
+    }
+
+    #[test]
+    fn test_allow_path_with_depth() {
+        let mut fset = FilterSet::new();
+        fset.allow_path_with_depth("logs", 1);
+        fset.allow_path("config");
+        let compiled = fset.compile();
+        let cases = [
+            ("logs", true, FilterLevel::Allow),
+            ("logs/app.log", false, FilterLevel::Allow),
+            ("logs/2024", true, FilterLevel::Allow),
+            ("logs/2024/app.log", false, FilterLevel::Deny),
+            ("config/a/b/c.toml", false, FilterLevel::Allow),
+            ("other.txt", false, FilterLevel::Deny),
+        ];
+        for (path, is_dir, level) in cases {
+            assert_eq!(fset.matches(path, is_dir).unwrap(), level, "for {path}");
+            assert_eq!(compiled.matches(path, is_dir).unwrap(), level, "for {path}");
+        }
+        assert_eq!(
+            fset.explain("logs/2024/app.log", false).unwrap().rule,
+            FilterRule::DepthExceeded
+        );
+
+        // Any root allowing the depth is enough
+        fset.allow_path("logs/2024");
+        for fset in [
+            fset.clone(),
+            serde_json::from_str(&serde_json::to_string(&fset).unwrap()).unwrap(),
+        ] {
+            assert_eq!(
+                fset.matches("logs/2024/app.log", false).unwrap(),
+                FilterLevel::Allow
+            );
+            assert_eq!(
+                fset.compile().matches("logs/2024/app.log", false).unwrap(),
+                FilterLevel::Allow
+            );
+            assert_eq!(
+                fset.compile().matches("logs/2023/app.log", false).unwrap(),
+                FilterLevel::Deny
+            );
+        }
+
+        // Merging keeps the least restrictive limit
+        let mut deeper = FilterSet::new();
+        deeper.allow_path_with_depth("logs", 2);
+        let mut merged = FilterSet::new();
+        merged.allow_path_with_depth("logs", 1);
+        merged.merge(&deeper);
+        assert_eq!(
+            merged.matches("logs/2023/app.log", false).unwrap(),
+            FilterLevel::Allow
+        );
+        assert_eq!(
+            merged.matches("logs/2023/01/app.log", false).unwrap(),
+            FilterLevel::Deny
+        );
+        let mut unlimited = FilterSet::new();
+        unlimited.allow_path("logs");
+        merged.merge(&unlimited);
+        assert_eq!(
+            merged.matches("logs/2023/01/app.log", false).unwrap(),
+            FilterLevel::Allow
+        );
+    }
+
+    #[test]
+    fn test_max_component_depth() {
+        let mut fset = FilterSet::new();
+        fset.allow_path("a");
+        fset.set_max_component_depth(Some(3));
+        let compiled = fset.compile();
+        let cases = [
+            ("a", true, FilterLevel::Allow),
+            ("a/b/c", true, FilterLevel::Allow),
+            ("a/b/c.txt", false, FilterLevel::Allow),
+            ("a/b/c/d", true, FilterLevel::Deny),
+            ("a/b/c/d.txt", false, FilterLevel::Deny),
+            ("b/c", true, FilterLevel::Deny),
+        ];
+        for (path, is_dir, level) in cases {
+            assert_eq!(fset.matches(path, is_dir).unwrap(), level, "for {path}");
+            assert_eq!(compiled.matches(path, is_dir).unwrap(), level, "for {path}");
+        }
+        assert_eq!(
+            fset.explain("a/b/c/d.txt", false).unwrap().rule,
+            FilterRule::MaxComponentDepthExceeded
+        );
+        let json = serde_json::to_string(&fset).unwrap();
+        assert_eq!(serde_json::from_str::<FilterSet>(&json).unwrap(), fset);
+
+        // Merging keeps the most restrictive limit
+        let mut shallower = FilterSet::new();
+        shallower.set_max_component_depth(Some(2));
+        fset.merge(&shallower);
+        assert_eq!(fset.matches("a/b/c.txt", false).unwrap(), FilterLevel::Deny);
+        fset.merge(&FilterSet::new());
+        assert_eq!(fset.matches("a/b.txt", false).unwrap(), FilterLevel::Allow);
+
+        fset.set_max_component_depth(None);
+        assert_eq!(
+            fset.matches("a/b/c/d.txt", false).unwrap(),
+            FilterLevel::Allow
+        );
+    }
 }
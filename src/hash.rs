@@ -1,13 +1,353 @@
 //! A helper modeule to build sha256 strings
+#[cfg(all(feature = "xxhash", not(target_arch = "wasm32")))]
+use std::hash::Hasher;
 #[cfg(not(target_arch = "wasm32"))]
 use std::path::Path as StdPath;
+use std::time::Duration;
 
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+#[cfg(feature = "json_schema")]
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
 use sha2::Digest;
 use sha2::Sha256;
 #[cfg(not(target_arch = "wasm32"))]
+use tokio::io::AsyncRead;
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::io::AsyncReadExt;
+#[cfg(feature = "xxhash")]
+use twox_hash::XxHash3_64;
 
 use crate::errors::Error;
+/// The incremental BLAKE3 hasher used for `HashAlgorithm::Blake3`.
+#[cfg(feature = "blake3")]
+pub use blake3::Hasher as Blake3;
+
+/// Algorithms used to digest file contents.
+///
+/// Digests are stored in `FileStat::sha256`. Sha256 digests are plain hex
+/// strings, while other algorithms prefix the hex string with a tag, for
+/// example `blake3:<hex>`, so that clients know how to verify them.
+#[cfg_attr(feature = "json_schema", derive(JsonSchema))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Hash, Eq)]
+pub enum HashAlgorithm {
+    /// SHA-256, the default
+    #[default]
+    Sha256,
+    /// BLAKE3 with a 256 bit output
+    #[cfg(feature = "blake3")]
+    Blake3,
+    /// 64 bit XXH3. Much faster than the others but not cryptographic, so
+    /// suited to change detection only.
+    #[cfg(feature = "xxhash")]
+    XxHash3,
+}
+
+impl HashAlgorithm {
+    /// Returns the tag prefixed to digests of this algorithm, if any.
+    pub fn tag(&self) -> Option<&'static str> {
+        match self {
+            HashAlgorithm::Sha256 => None,
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Some("blake3:"),
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash3 => Some("xxh3:"),
+        }
+    }
+
+    /// Returns the algorithm that produced `digest` along with the untagged
+    /// hex string.
+    pub fn of_digest(digest: &str) -> (HashAlgorithm, &str) {
+        #[cfg(feature = "blake3")]
+        if let Some(hex) = HashAlgorithm::Blake3
+            .tag()
+            .and_then(|tag| digest.strip_prefix(tag))
+        {
+            return (HashAlgorithm::Blake3, hex);
+        }
+        #[cfg(feature = "xxhash")]
+        if let Some(hex) = HashAlgorithm::XxHash3
+            .tag()
+            .and_then(|tag| digest.strip_prefix(tag))
+        {
+            return (HashAlgorithm::XxHash3, hex);
+        }
+        (HashAlgorithm::Sha256, digest)
+    }
+
+    fn tagged(&self, hex: String) -> String {
+        match self.tag() {
+            Some(tag) => format!("{tag}{hex}"),
+            None => hex,
+        }
+    }
+
+    /// Returns the tagged digest of the file at `path`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn digest_path(&self, path: &StdPath) -> Result<String, Error> {
+        self.digest_path_with(path, DEFAULT_HASH_BUFFER_SIZE, &RetryPolicy::default())
+            .await
+    }
+
+    /// Returns the tagged digest of the file at `path`, reading it in chunks
+    /// of `buffer_size` bytes and retrying transient errors according to
+    /// `retry_policy`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn digest_path_with(
+        &self,
+        path: &StdPath,
+        buffer_size: usize,
+        retry_policy: &RetryPolicy,
+    ) -> Result<String, Error> {
+        let hex = match self {
+            HashAlgorithm::Sha256 => {
+                let mut context = Sha256::new();
+                read_in_chunks(path, buffer_size, retry_policy, |data| context.update(data))
+                    .await?;
+                context.sha256_string().await?
+            }
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => {
+                let mut context = Blake3::new();
+                read_in_chunks(path, buffer_size, retry_policy, |data| {
+                    context.update(data);
+                })
+                .await?;
+                context.blake3_string().await?
+            }
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash3 => {
+                let mut context = XxHash3_64::new();
+                read_in_chunks(path, buffer_size, retry_policy, |data| context.write(data)).await?;
+                xxhash_string(&context)
+            }
+        };
+        Ok(self.tagged(hex))
+    }
+
+    /// Returns the tagged digest of the file at `path`, hashing it through a
+    /// memory map instead of reading it in chunks. Transient errors are not
+    /// retried. See `StatOptions::use_mmap` for the risks of mapping files.
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    pub(crate) async fn digest_mapped(&self, path: &StdPath) -> Result<String, Error> {
+        let hex = match self {
+            HashAlgorithm::Sha256 => {
+                read_mapped(path, |data| {
+                    let mut context = Sha256::new();
+                    context.update(data);
+                    context
+                })
+                .await?
+                .sha256_string()
+                .await?
+            }
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => {
+                read_mapped(path, |data| {
+                    let mut context = Blake3::new();
+                    context.update(data);
+                    context
+                })
+                .await?
+                .blake3_string()
+                .await?
+            }
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash3 => {
+                let context = read_mapped(path, |data| {
+                    let mut context = XxHash3_64::new();
+                    context.write(data);
+                    context
+                })
+                .await?;
+                xxhash_string(&context)
+            }
+        };
+        Ok(self.tagged(hex))
+    }
+
+    /// Returns the tagged digest of `data`.
+    pub async fn digest_bytes(&self, data: &[u8]) -> Result<String, Error> {
+        let hex = match self {
+            HashAlgorithm::Sha256 => data.sha256_build().await?.sha256_string().await?,
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => data.blake3_build().await?.blake3_string().await?,
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash3 => format!("{:016x}", XxHash3_64::oneshot(data)),
+        };
+        Ok(self.tagged(hex))
+    }
+}
+
+/// How digests are rendered into strings, see
+/// `PortableFs::set_digest_encoding`.
+///
+/// Algorithm and directory tags, see `HashAlgorithm`, are kept in front of
+/// `Hex` and `Base64Url` digests. `Multihash` digests describe their
+/// algorithm themselves, so only the directory tag is kept.
+#[cfg_attr(feature = "json_schema", derive(JsonSchema))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Hash, Eq)]
+pub enum DigestEncoding {
+    /// Lowercase hex, the default
+    #[default]
+    Hex,
+    /// Unpadded base64url, about two thirds the length of hex
+    Base64Url,
+    /// Multihash in multibase base64url, i.e. `u` followed by the unpadded
+    /// base64url of the multihash bytes. Not supported for
+    /// `HashAlgorithm::XxHash3`, which has no multihash code.
+    Multihash,
+}
+
+/// Multihash code of SHA-256.
+const MULTIHASH_SHA256: u8 = 0x12;
+/// Multihash code of BLAKE3.
+#[cfg(feature = "blake3")]
+const MULTIHASH_BLAKE3: u8 = 0x1e;
+/// Multibase prefix of unpadded base64url.
+const MULTIBASE_BASE64URL: char = 'u';
+
+impl DigestEncoding {
+    /// Renders `digest`, a tagged hex digest as returned by
+    /// `HashAlgorithm::digest_bytes`, in this encoding.
+    ///
+    /// Returns `Error::InvalidArgument` if `digest` is not valid hex or has
+    /// no multihash code.
+    pub fn encode(&self, digest: &str) -> Result<String, Error> {
+        // Directories have an empty digest
+        if digest.is_empty() {
+            return Ok(String::new());
+        }
+        let (dir_tag, tagged) = split_dir_tag(digest);
+        let (algorithm, hex) = HashAlgorithm::of_digest(tagged);
+        let bytes = decode_hex(hex)?;
+        let encoded = match self {
+            DigestEncoding::Hex => return Ok(digest.to_owned()),
+            DigestEncoding::Base64Url => algorithm.tagged(URL_SAFE_NO_PAD.encode(bytes)),
+            DigestEncoding::Multihash => {
+                let code = multihash_code(algorithm)?;
+                let length = u8::try_from(bytes.len())
+                    .ok()
+                    .filter(|length| *length < 0x80)
+                    .ok_or_else(|| {
+                        Error::InvalidArgument(format!("digest too long for multihash: {hex}"))
+                    })?;
+                let mut multihash = vec![code, length];
+                multihash.extend(bytes);
+                format!("{MULTIBASE_BASE64URL}{}", URL_SAFE_NO_PAD.encode(multihash))
+            }
+        };
+        Ok(format!("{dir_tag}{encoded}"))
+    }
+
+    /// Parses `encoded`, a digest rendered in this encoding, back into a
+    /// tagged hex digest. The reverse of `encode`.
+    ///
+    /// Returns `Error::InvalidArgument` if `encoded` is malformed.
+    pub fn decode(&self, encoded: &str) -> Result<String, Error> {
+        if encoded.is_empty() {
+            return Ok(String::new());
+        }
+        let invalid = || Error::InvalidArgument(format!("invalid {self:?} digest: {encoded}"));
+        let (dir_tag, digest) = split_dir_tag(encoded);
+        let (algorithm, bytes) = match self {
+            DigestEncoding::Hex => {
+                let (_, hex) = HashAlgorithm::of_digest(digest);
+                decode_hex(hex)?;
+                return Ok(encoded.to_owned());
+            }
+            DigestEncoding::Base64Url => {
+                let (algorithm, base64) = HashAlgorithm::of_digest(digest);
+                let bytes = URL_SAFE_NO_PAD.decode(base64).map_err(|_| invalid())?;
+                (algorithm, bytes)
+            }
+            DigestEncoding::Multihash => {
+                let multihash = digest
+                    .strip_prefix(MULTIBASE_BASE64URL)
+                    .and_then(|base64| URL_SAFE_NO_PAD.decode(base64).ok())
+                    .ok_or_else(invalid)?;
+                let [code, length, bytes @ ..] = multihash.as_slice() else {
+                    return Err(invalid());
+                };
+                let algorithm = [
+                    HashAlgorithm::Sha256,
+                    #[cfg(feature = "blake3")]
+                    HashAlgorithm::Blake3,
+                ]
+                .into_iter()
+                .find(|a| multihash_code(*a).is_ok_and(|c| c == *code))
+                .ok_or_else(invalid)?;
+                if usize::from(*length) != bytes.len() {
+                    return Err(invalid());
+                }
+                (algorithm, bytes.to_vec())
+            }
+        };
+        let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        Ok(format!("{dir_tag}{}", algorithm.tagged(hex)))
+    }
+}
+
+/// Splits the directory tag, if any, off `digest`.
+fn split_dir_tag(digest: &str) -> (&'static str, &str) {
+    match digest.strip_prefix(DIR_DIGEST_TAG) {
+        Some(rest) => (DIR_DIGEST_TAG, rest),
+        None => ("", digest),
+    }
+}
+
+/// Returns the multihash code of `algorithm`.
+fn multihash_code(algorithm: HashAlgorithm) -> Result<u8, Error> {
+    match algorithm {
+        HashAlgorithm::Sha256 => Ok(MULTIHASH_SHA256),
+        #[cfg(feature = "blake3")]
+        HashAlgorithm::Blake3 => Ok(MULTIHASH_BLAKE3),
+        #[cfg(feature = "xxhash")]
+        HashAlgorithm::XxHash3 => Err(Error::InvalidArgument(
+            "xxh3 digests have no multihash code".into(),
+        )),
+    }
+}
+
+/// Returns the bytes of the hex string `hex`.
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::InvalidArgument(format!("invalid hex digest: {hex}")));
+    }
+    Ok((0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+/// Returns the hex string of the 64 bit fingerprint in `context`.
+#[cfg(all(feature = "xxhash", not(target_arch = "wasm32")))]
+fn xxhash_string(context: &XxHash3_64) -> String {
+    format!("{:016x}", context.finish())
+}
+
+/// Returns true if `digest` is the digest of `data`. The algorithm is picked
+/// based on the tag of `digest`.
+pub async fn verify(data: &[u8], digest: &str) -> Result<bool, Error> {
+    let (algorithm, _) = HashAlgorithm::of_digest(digest);
+    Ok(algorithm.digest_bytes(data).await? == digest)
+}
+
+/// Returns true if `digest`, rendered in `encoding`, is the digest of `data`.
+///
+/// Returns `Error::InvalidArgument` if `digest` is not a valid `encoding`
+/// digest.
+pub async fn verify_encoded(
+    data: &[u8],
+    digest: &str,
+    encoding: DigestEncoding,
+) -> Result<bool, Error> {
+    verify(data, &encoding.decode(digest)?).await
+}
 
 /// Trait for constructing a `Sha256` digest context from various inputs.
 ///
@@ -33,6 +373,37 @@ pub trait Sha256Builder {
             Ok(())
         }
     }
+
+    /// Update the provided `context` with `data` bytes like `sha256_update`,
+    /// but in slices of up to `chunk_size` bytes, yielding to the scheduler
+    /// between slices. Useful to hash large buffers without hogging the
+    /// runtime. Inputs of up to `chunk_size` bytes are fed at once.
+    ///
+    /// Returns `Error::InvalidArgument` if `chunk_size` is zero.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn sha256_update_chunked(
+        &self,
+        data: &[u8],
+        context: &mut Sha256,
+        chunk_size: usize,
+    ) -> impl std::future::Future<Output = Result<(), Error>> + Send
+    where
+        Self: Sync,
+    {
+        async move {
+            if chunk_size == 0 {
+                return Err(Error::InvalidArgument("chunk size must be non zero".into()));
+            }
+            if data.len() <= chunk_size {
+                return self.sha256_update(data, context).await;
+            }
+            for chunk in data.chunks(chunk_size) {
+                self.sha256_update(chunk, context).await?;
+                tokio::task::yield_now().await;
+            }
+            Ok(())
+        }
+    }
 }
 
 /// Convert a completed `Sha256` digest context into a hex-encoded string.
@@ -48,29 +419,169 @@ impl Sha256String for Sha256 {
     }
 }
 
+/// Tag of the digests of directory listings, see
+/// `PortableFs::set_compute_dir_digests`.
+pub(crate) const DIR_DIGEST_TAG: &str = "dir:";
+
+/// Size of the buffer used to read files while hashing, unless configured
+/// otherwise.
+pub const DEFAULT_HASH_BUFFER_SIZE: usize = 4096;
+
+/// Policy to retry transient IO errors, i.e. `Interrupted`, `TimedOut` and
+/// `WouldBlock`, while reading files for hashing.
+///
+/// Retries back off exponentially starting at `initial_backoff`. The default
+/// policy doesn't retry.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of retries while hashing a file.
+    pub max_retries: u32,
+    /// Delay before the first retry. Doubled on each subsequent retry.
+    pub initial_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Returns true if `e` is worth retrying.
+    pub fn is_transient(e: &std::io::Error) -> bool {
+        matches!(
+            e.kind(),
+            std::io::ErrorKind::Interrupted
+                | std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::WouldBlock
+        )
+    }
+
+    /// Returns the delay before retry number `attempt`, starting at zero.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+    }
+
+    /// Sleeps before the next retry if `e` is transient and retries are left.
+    /// Returns false if the caller should give up.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn wait_retry(&self, e: &std::io::Error, attempt: &mut u32) -> bool {
+        if *attempt >= self.max_retries || !Self::is_transient(e) {
+            return false;
+        }
+        tokio::time::sleep(self.backoff(*attempt)).await;
+        *attempt += 1;
+        true
+    }
+}
+
+/// Files of at least this many bytes are hashed through a memory map when
+/// `StatOptions::use_mmap` is set. Smaller files are read in chunks.
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+pub const MMAP_THRESHOLD: u64 = 16 << 20;
+
+/// Maps the file at `path` into memory and passes its contents to `f` on a
+/// blocking thread.
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+async fn read_mapped<T, F>(path: &StdPath, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&[u8]) -> T + Send + 'static,
+{
+    let read_error = |e: String| Error::Read {
+        what: path.to_string_lossy().to_string(),
+        how: e,
+    };
+    let owned = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&owned)?;
+        // SAFETY: the map is only read, and only within this closure. Contents
+        // modified while mapped yield a bogus digest, which `FileStat` loading
+        // detects by comparing stats before and after hashing. A file
+        // truncated while mapped is not safe though: reading past its new end
+        // raises SIGBUS and kills the process. Mapping is therefore opt-in
+        // through `StatOptions::use_mmap`, for files no other process shrinks.
+        #[allow(unsafe_code)]
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Ok::<_, std::io::Error>(f(&map))
+    })
+    .await
+    .map_err(|e| read_error(e.to_string()))?
+    .map_err(|e| read_error(e.to_string()))
+}
+
+/// Reads the file at `path` in chunks of up to `buffer_size` bytes, feeding
+/// each chunk to `f`.
 #[cfg(not(target_arch = "wasm32"))]
-impl Sha256Builder for &StdPath {
-    async fn sha256_build(&self) -> Result<Sha256, Error> {
-        let mut file = tokio::fs::File::open(&self)
-            .await
-            .map_err(|e| Error::Read {
-                what: self.to_string_lossy().to_string(),
-                how: e.to_string(),
-            })?;
-        let mut context = Sha256::new();
-        let mut buffer = vec![0; 4096]; // Read in chunks
+async fn read_in_chunks<F: FnMut(&[u8])>(
+    path: &StdPath,
+    buffer_size: usize,
+    retry_policy: &RetryPolicy,
+    f: F,
+) -> Result<(), Error> {
+    let read_error = |e: std::io::Error| Error::Read {
+        what: path.to_string_lossy().to_string(),
+        how: e.to_string(),
+    };
+    let mut attempt = 0;
+    let file = loop {
+        match tokio::fs::File::open(path).await {
+            Ok(file) => break file,
+            Err(e) => {
+                if !retry_policy.wait_retry(&e, &mut attempt).await {
+                    return Err(read_error(e));
+                }
+            }
+        }
+    };
+    read_chunks(file, buffer_size, retry_policy, attempt, f)
+        .await
+        .map_err(read_error)
+}
 
-        loop {
-            let bytes_read = file.read(&mut buffer).await.map_err(|e| Error::Read {
-                what: self.to_string_lossy().to_string(),
-                how: e.to_string(),
-            })?;
+/// Reads `reader` to the end in chunks of up to `buffer_size` bytes, feeding
+/// each chunk to `f`. `attempt` is the number of retries already spent.
+#[cfg(not(target_arch = "wasm32"))]
+async fn read_chunks<R: AsyncRead + Unpin, F: FnMut(&[u8])>(
+    mut reader: R,
+    buffer_size: usize,
+    retry_policy: &RetryPolicy,
+    mut attempt: u32,
+    mut f: F,
+) -> Result<(), std::io::Error> {
+    if buffer_size == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "hash buffer size must be non zero",
+        ));
+    }
+    let mut buffer = vec![0; buffer_size];
 
-            if bytes_read == 0 {
-                break; // End of file
+    loop {
+        let bytes_read = match reader.read(&mut buffer).await {
+            Ok(bytes_read) => bytes_read,
+            Err(e) => {
+                if retry_policy.wait_retry(&e, &mut attempt).await {
+                    continue;
+                }
+                return Err(e);
             }
-            context.update(&buffer[..bytes_read]);
+        };
+
+        if bytes_read == 0 {
+            break; // End of file
         }
+        f(&buffer[..bytes_read]);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Sha256Builder for &StdPath {
+    async fn sha256_build(&self) -> Result<Sha256, Error> {
+        let mut context = Sha256::new();
+        read_in_chunks(
+            self,
+            DEFAULT_HASH_BUFFER_SIZE,
+            &RetryPolicy::default(),
+            |data| context.update(data),
+        )
+        .await?;
         Ok(context)
     }
 }
@@ -85,4 +596,404 @@ impl Sha256Builder for &[u8] {
     }
 }
 
-// (impl for &[u8] moved above with documentation)
+/// Trait for constructing a `Blake3` hasher from various inputs.
+///
+/// Mirrors `Sha256Builder` for the BLAKE3 digest.
+#[cfg(feature = "blake3")]
+pub trait Blake3Builder {
+    /// Build and return a `Blake3` hasher for `self`.
+    fn blake3_build(&self) -> impl std::future::Future<Output = Result<Blake3, Error>> + Send;
+
+    /// Update the provided `context` with `data` bytes.
+    fn blake3_update(
+        &self,
+        data: &[u8],
+        context: &mut Blake3,
+    ) -> impl std::future::Future<Output = Result<(), Error>> + Send {
+        async move {
+            context.update(data);
+            Ok(())
+        }
+    }
+}
+
+/// Convert a completed `Blake3` hasher into a hex-encoded string.
+#[cfg(feature = "blake3")]
+pub trait Blake3String {
+    /// Consume the `Blake3` hasher and return the hex string representation
+    /// of the digest (lowercase hex).
+    fn blake3_string(self) -> impl std::future::Future<Output = Result<String, Error>> + Send;
+}
+
+#[cfg(feature = "blake3")]
+impl Blake3String for Blake3 {
+    async fn blake3_string(self) -> Result<String, Error> {
+        Ok(self.finalize().to_hex().to_string())
+    }
+}
+
+#[cfg(all(feature = "blake3", not(target_arch = "wasm32")))]
+impl Blake3Builder for &StdPath {
+    async fn blake3_build(&self) -> Result<Blake3, Error> {
+        let mut context = Blake3::new();
+        read_in_chunks(
+            self,
+            DEFAULT_HASH_BUFFER_SIZE,
+            &RetryPolicy::default(),
+            |data| {
+                context.update(data);
+            },
+        )
+        .await?;
+        Ok(context)
+    }
+}
+
+/// `Blake3Builder` implementation for byte slices.
+#[cfg(feature = "blake3")]
+impl Blake3Builder for &[u8] {
+    async fn blake3_build(&self) -> Result<Blake3, Error> {
+        let mut context = Blake3::new();
+        self.blake3_update(self, &mut context).await?;
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn test_buffer_size_independent_digest() {
+        let dir = tempdir::TempDir::new("").unwrap();
+        let path = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let expected = HashAlgorithm::Sha256.digest_bytes(&data).await.unwrap();
+        for buffer_size in [1, 7, DEFAULT_HASH_BUFFER_SIZE, 1 << 20] {
+            assert_eq!(
+                HashAlgorithm::Sha256
+                    .digest_path_with(&path, buffer_size, &RetryPolicy::default())
+                    .await
+                    .unwrap(),
+                expected,
+                "for {buffer_size}"
+            );
+        }
+        assert!(matches!(
+            HashAlgorithm::Sha256
+                .digest_path_with(&path, 0, &RetryPolicy::default())
+                .await,
+            Err(Error::Read { .. })
+        ));
+    }
+
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    #[tokio::test]
+    async fn test_mapped_digest_matches_streaming() {
+        let dir = tempdir::TempDir::new("").unwrap();
+        let path = dir.path().join("data.bin");
+        for len in [100_000, MMAP_THRESHOLD + 1] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            std::fs::write(&path, &data).unwrap();
+
+            let mut context = Sha256::new();
+            read_in_chunks(
+                &path,
+                DEFAULT_HASH_BUFFER_SIZE,
+                &RetryPolicy::default(),
+                |data| context.update(data),
+            )
+            .await
+            .unwrap();
+            let streamed = context.sha256_string().await.unwrap();
+            let mapped = HashAlgorithm::Sha256.digest_mapped(&path).await.unwrap();
+            assert_eq!(mapped, streamed, "for {len}");
+            assert_eq!(
+                HashAlgorithm::Sha256.digest_path(&path).await.unwrap(),
+                streamed,
+                "for {len}"
+            );
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn test_chunked_update_matches_single_shot() {
+        let data: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+        let data = data.as_slice();
+        let expected = data
+            .sha256_build()
+            .await
+            .unwrap()
+            .sha256_string()
+            .await
+            .unwrap();
+        for chunk_size in [1 << 12, data.len(), 2 * data.len()] {
+            let mut context = Sha256::new();
+            data.sha256_update_chunked(data, &mut context, chunk_size)
+                .await
+                .unwrap();
+            assert_eq!(
+                context.sha256_string().await.unwrap(),
+                expected,
+                "for {chunk_size}"
+            );
+        }
+        assert!(matches!(
+            data.sha256_update_chunked(data, &mut Sha256::new(), 0)
+                .await,
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verify_sha256() {
+        let data: &[u8] = b"abc";
+        let digest = HashAlgorithm::Sha256.digest_bytes(data).await.unwrap();
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(verify(data, &digest).await.unwrap());
+        assert!(!verify(b"abd", &digest).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_digest_encoding_round_trip() {
+        let hex = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        for (encoding, encoded) in [
+            (DigestEncoding::Hex, hex.to_owned()),
+            (
+                DigestEncoding::Base64Url,
+                "ungWv48Bz-pBQUDeXa4iI7ADYaOWF3qctBD_YfIAFa0".to_owned(),
+            ),
+            (
+                DigestEncoding::Multihash,
+                "uEiC6eBa_jwHP6kFBQN5driIjsANho5YXepy0EP9h8gAVrQ".to_owned(),
+            ),
+        ] {
+            assert_eq!(encoding.encode(hex).unwrap(), encoded, "for {encoding:?}");
+            assert_eq!(encoding.decode(&encoded).unwrap(), hex, "for {encoding:?}");
+            assert!(verify_encoded(b"abc", &encoded, encoding).await.unwrap());
+            assert!(!verify_encoded(b"abd", &encoded, encoding).await.unwrap());
+
+            let dir_digest = format!("{DIR_DIGEST_TAG}{hex}");
+            let dir_encoded = encoding.encode(&dir_digest).unwrap();
+            assert_eq!(dir_encoded, format!("{DIR_DIGEST_TAG}{encoded}"));
+            assert_eq!(encoding.decode(&dir_encoded).unwrap(), dir_digest);
+
+            assert_eq!(encoding.encode("").unwrap(), "");
+            assert_eq!(encoding.decode("").unwrap(), "");
+            assert!(matches!(
+                encoding.decode("not a digest!"),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+        assert!(matches!(
+            DigestEncoding::Base64Url.encode("xyz"),
+            Err(Error::InvalidArgument(_))
+        ));
+        // Multihash with a truncated digest
+        assert!(matches!(
+            DigestEncoding::Multihash.decode("uEiC6eBa_"),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    /// Reader failing with `kind` `failures` times before returning `data`.
+    #[cfg(not(target_arch = "wasm32"))]
+    struct FlakyReader {
+        failures: u32,
+        kind: std::io::ErrorKind,
+        data: &'static [u8],
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    impl AsyncRead for FlakyReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return std::task::Poll::Ready(Err(self.kind.into()));
+            }
+            let n = buf.remaining().min(self.data.len());
+            buf.put_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn read_flaky(
+        failures: u32,
+        kind: std::io::ErrorKind,
+        policy: &RetryPolicy,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let reader = FlakyReader {
+            failures,
+            kind,
+            data: b"Hello, world!",
+        };
+        let mut out = Vec::new();
+        read_chunks(reader, 4, policy, 0, |data| out.extend_from_slice(data)).await?;
+        Ok(out)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn test_retry_transient_errors() {
+        use std::io::ErrorKind;
+
+        let policy = RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(1),
+        };
+        assert_eq!(
+            read_flaky(3, ErrorKind::Interrupted, &policy)
+                .await
+                .unwrap(),
+            b"Hello, world!"
+        );
+        assert_eq!(
+            read_flaky(2, ErrorKind::TimedOut, &policy).await.unwrap(),
+            b"Hello, world!"
+        );
+        assert_eq!(
+            read_flaky(4, ErrorKind::WouldBlock, &policy)
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            read_flaky(1, ErrorKind::PermissionDenied, &policy)
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert!(
+            read_flaky(1, ErrorKind::Interrupted, &RetryPolicy::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(10),
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(10));
+        assert_eq!(policy.backoff(1), Duration::from_millis(20));
+        assert_eq!(policy.backoff(3), Duration::from_millis(80));
+        assert_eq!(policy.backoff(64), Duration::from_millis(10) * u32::MAX);
+    }
+
+    #[cfg(feature = "blake3")]
+    #[tokio::test]
+    async fn test_blake3_digest() {
+        let data: &[u8] = b"abc";
+        let digest = HashAlgorithm::Blake3.digest_bytes(data).await.unwrap();
+        assert_eq!(
+            digest,
+            "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(
+            HashAlgorithm::of_digest(&digest),
+            (
+                HashAlgorithm::Blake3,
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+            )
+        );
+        assert!(verify(data, &digest).await.unwrap());
+        assert!(!verify(b"abd", &digest).await.unwrap());
+    }
+
+    #[cfg(feature = "xxhash")]
+    #[tokio::test]
+    async fn test_xxhash_digest() {
+        let algorithm = HashAlgorithm::XxHash3;
+        let digest = algorithm.digest_bytes(b"abc").await.unwrap();
+        assert_eq!(digest, algorithm.digest_bytes(b"abc").await.unwrap());
+        assert_ne!(digest, algorithm.digest_bytes(b"abd").await.unwrap());
+        assert!(digest.starts_with("xxh3:"));
+        assert_eq!(digest.len(), "xxh3:".len() + 16);
+        assert_eq!(HashAlgorithm::of_digest(&digest).0, HashAlgorithm::XxHash3);
+        assert_eq!(
+            HashAlgorithm::of_digest(&HashAlgorithm::Sha256.digest_bytes(b"abc").await.unwrap()).0,
+            HashAlgorithm::Sha256
+        );
+        assert!(verify(b"abc", &digest).await.unwrap());
+
+        let dir = tempdir::TempDir::new("xxhash").unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, vec![7u8; 100_000]).unwrap();
+        assert_eq!(
+            algorithm
+                .digest_path_with(&path, 4096, &RetryPolicy::default())
+                .await
+                .unwrap(),
+            algorithm.digest_bytes(&vec![7u8; 100_000]).await.unwrap()
+        );
+    }
+
+    // Input of the official test vectors: a repeating 0..251 byte sequence
+    #[cfg(feature = "blake3")]
+    fn blake3_test_input(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[cfg(feature = "blake3")]
+    #[tokio::test]
+    async fn test_blake3_known_vectors() {
+        let vectors: [(Vec<u8>, &str); 7] = [
+            (
+                b"".to_vec(),
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                b"abc".to_vec(),
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+            ),
+            (
+                blake3_test_input(1),
+                "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+            ),
+            (
+                blake3_test_input(1023),
+                "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11",
+            ),
+            (
+                blake3_test_input(1024),
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                blake3_test_input(1025),
+                "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+            ),
+            (
+                blake3_test_input(2048),
+                "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a",
+            ),
+        ];
+        for (input, expected) in vectors {
+            let digest = input
+                .as_slice()
+                .blake3_build()
+                .await
+                .unwrap()
+                .blake3_string()
+                .await
+                .unwrap();
+            assert_eq!(digest, expected, "for {} bytes", input.len());
+        }
+    }
+}
@@ -22,6 +22,7 @@
 //! The output might look like
 //! ```json
 //! {
+//!   "schema_version": 1,
 //!   "current_path": {
 //!     "components": []
 //!   },
@@ -55,20 +56,59 @@ mod errors;
 mod file;
 mod filter;
 pub mod hash;
+mod manifest;
 #[cfg(not(target_arch = "wasm32"))]
 mod native;
 mod path;
 mod portable_fs;
 pub mod utils;
 
+pub use cache::CacheStats;
 pub use dir::Directory;
 pub use dir::DirectoryEntry;
+pub use dir::DiskUsage;
+pub use dir::EntryError;
+pub use dir::SortKey;
+pub use dir_list::DirListChunk;
 pub use dir_list::RecursiveDirList;
+pub use dir_list::TreeSummary;
 pub use errors::Error;
+pub use errors::ErrorKind;
+pub use file::FieldDiff;
 pub use file::FileInfo;
 pub use file::FileNode;
 pub use file::FileStat;
+pub use file::StatOptions;
+#[cfg(not(target_arch = "wasm32"))]
+pub use filter::CompiledFilterSet;
+#[cfg(not(target_arch = "wasm32"))]
+pub use filter::FilterDecision;
+#[cfg(not(target_arch = "wasm32"))]
+pub use filter::FilterLevel;
+#[cfg(not(target_arch = "wasm32"))]
+pub use filter::FilterRule;
+pub use filter::FilterSet;
+pub use manifest::Manifest;
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::LazyFileStat;
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::MultiRootFs;
 #[cfg(all(not(target_arch = "wasm32"), feature = "test_utils"))]
 pub use native::TestRoot;
+#[cfg(all(not(target_arch = "wasm32"), feature = "test_utils"))]
+pub use native::TestRootBuilder;
+pub use path::DEFAULT_MAX_COMPONENTS;
 pub use path::Path;
+pub use path::StringPath;
+pub use portable_fs::DEFAULT_WALK_CHANNEL_CAPACITY;
+pub use portable_fs::NonUtf8Policy;
+pub use portable_fs::Observer;
 pub use portable_fs::PortableFs;
+pub use portable_fs::SymlinkPolicy;
+
+/// Generation of the serialized format of `Directory` and
+/// `RecursiveDirList`, reported in their `schema_version` fields.
+///
+/// Payloads from peers predating versioning have no `schema_version`. Fields
+/// added since are optional, so payloads of any generation deserialize.
+pub const SCHEMA_VERSION: u32 = 1;
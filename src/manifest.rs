@@ -0,0 +1,20 @@
+#[cfg(feature = "json_schema")]
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::Path;
+
+/// A content addressable listing of files contained in `base`.
+///
+/// Unlike `RecursiveDirList`, a manifest carries only digests and sizes of
+/// files, which makes it smaller and stable across mtime changes.
+#[cfg_attr(feature = "json_schema", derive(JsonSchema))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
+pub struct Manifest {
+    /// Path of the exported subtree
+    pub base: Path,
+    /// Path, sha256 and size in bytes of each file under `base`
+    pub entries: Vec<(Path, String, u64)>,
+}
@@ -1,9 +1,16 @@
 mod dir_walker;
 mod file;
+mod multi_root_fs;
 mod native_fs_cache;
 mod portable_fs;
 #[cfg(feature = "test_utils")]
 pub(crate) mod test_utils;
+#[cfg(all(unix, feature = "xattr"))]
+mod xattr;
+pub use file::LazyFileStat;
+pub use multi_root_fs::MultiRootFs;
 pub(crate) use native_fs_cache::FsCache;
 #[cfg(feature = "test_utils")]
 pub use test_utils::TestRoot;
+#[cfg(feature = "test_utils")]
+pub use test_utils::TestRootBuilder;
@@ -1,26 +1,49 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
 use std::path::Path as StdPath;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 
-use async_recursion::async_recursion;
 use futures_lite::StreamExt;
+use log::debug;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
+use tokio::task::JoinSet;
 
 use super::portable_fs::lookup_or_load;
 use crate::Error;
 use crate::FileInfo;
 use crate::FileStat;
+use crate::NonUtf8Policy;
 use crate::Path;
+use crate::SymlinkPolicy;
+use crate::filter::CompiledFilterSet;
 use crate::filter::FilterLevel;
 use crate::portable_fs::FsLayer;
 
 pub(crate) struct DirWalker {
     strip_prefix: PathBuf,
     layer: Arc<FsLayer>,
+    // Compiled once per walk rather than locking and matching the layer's
+    // filter set per entry.
+    filter: CompiledFilterSet,
     chunk_size: usize,
     max_depth: Option<usize>,
+    max_entries: Option<usize>,
+    entries: AtomicUsize,
+    truncated: AtomicBool,
+    skip_unreadable: bool,
+    skip_digests: bool,
+    errors: Mutex<Vec<(Path, Error)>>,
+    // Component names seen during the walk. Entries hold shared names while
+    // being walked and are only turned into `Path`s when needed, so names
+    // repeated across directories like `src` are stored once.
+    interned: Mutex<HashSet<Arc<str>>>,
     tx: Sender<Vec<FileInfo>>,
     lookup: HashMap<PathBuf, FileStat>,
 }
@@ -34,16 +57,47 @@ impl DirWalker {
         tx: Sender<Vec<FileInfo>>,
         lookup: HashMap<PathBuf, FileStat>,
     ) -> Self {
+        let filter = layer.filter_set.read().unwrap().compile();
         Self {
             strip_prefix: strip_prefix.as_ref().to_path_buf(),
+            filter,
             layer,
             chunk_size,
             max_depth,
+            max_entries: None,
+            entries: AtomicUsize::new(0),
+            truncated: AtomicBool::new(false),
+            skip_unreadable: false,
+            skip_digests: false,
+            errors: Mutex::new(vec![]),
+            interned: Mutex::new(HashSet::new()),
             tx,
             lookup,
         }
     }
 
+    /// Stops the walk once `max_entries` entries have been sent. `None` sends
+    /// all entries.
+    pub fn with_max_entries(mut self, max_entries: Option<usize>) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// When enabled, entries whose stats can't be loaded and directories
+    /// that can't be listed are skipped and collected instead of failing the
+    /// walk. Directories that can't be listed are still sent themselves.
+    pub fn with_skip_unreadable(mut self, skip_unreadable: bool) -> Self {
+        self.skip_unreadable = skip_unreadable;
+        self
+    }
+
+    /// When enabled, stats are loaded without digesting file contents and
+    /// bypass the cache.
+    pub fn with_skip_digests(mut self, skip_digests: bool) -> Self {
+        self.skip_digests = skip_digests;
+        self
+    }
+
     pub async fn walk_dir<P: AsRef<StdPath>>(
         full_path: P,
         strip_prefix: P,
@@ -51,19 +105,159 @@ impl DirWalker {
         chunk_size: usize,
         max_depth: Option<usize>,
     ) -> Result<Vec<FileInfo>, Error> {
+        let (items, _) = Self::walk_dir_capped(
+            full_path,
+            strip_prefix,
+            layer,
+            chunk_size,
+            max_depth,
+            None,
+            true,
+        )
+        .await?;
+        Ok(items)
+    }
+
+    /// Like `walk_dir` but returns at most `max_entries` entries. The returned
+    /// flag is true if the walk stopped early because more entries were
+    /// found. Files are digested only if `include_digests` is set.
+    pub async fn walk_dir_capped<P: AsRef<StdPath>>(
+        full_path: P,
+        strip_prefix: P,
+        layer: Arc<FsLayer>,
+        chunk_size: usize,
+        max_depth: Option<usize>,
+        max_entries: Option<usize>,
+        include_digests: bool,
+    ) -> Result<(Vec<FileInfo>, bool), Error> {
+        let (items, truncated, _) = Self::collect(
+            full_path,
+            strip_prefix,
+            layer,
+            chunk_size,
+            max_depth,
+            move |walker| {
+                walker
+                    .with_max_entries(max_entries)
+                    .with_skip_digests(!include_digests)
+            },
+        )
+        .await?;
+        Ok((items, truncated))
+    }
+
+    /// Like `walk_dir` but skips entries whose stats can't be loaded, e.g. for
+    /// lack of permissions, and returns them along with their errors. Files
+    /// are digested only if `include_digests` is set.
+    pub async fn walk_dir_lenient<P: AsRef<StdPath>>(
+        full_path: P,
+        strip_prefix: P,
+        layer: Arc<FsLayer>,
+        chunk_size: usize,
+        max_depth: Option<usize>,
+        include_digests: bool,
+    ) -> Result<(Vec<FileInfo>, Vec<(Path, Error)>), Error> {
+        let (items, _, errors) = Self::collect(
+            full_path,
+            strip_prefix,
+            layer,
+            chunk_size,
+            max_depth,
+            move |walker| {
+                walker
+                    .with_skip_unreadable(true)
+                    .with_skip_digests(!include_digests)
+            },
+        )
+        .await?;
+        Ok((items, errors))
+    }
+
+    /// Like `walk_dir` but loads stats without digesting file contents, so
+    /// `FileStat::sha256` is `None` for files. The cache is neither read nor
+    /// populated.
+    pub async fn walk_dir_without_digests<P: AsRef<StdPath>>(
+        full_path: P,
+        strip_prefix: P,
+        layer: Arc<FsLayer>,
+        chunk_size: usize,
+        max_depth: Option<usize>,
+    ) -> Result<Vec<FileInfo>, Error> {
+        let (items, _, _) = Self::collect(
+            full_path,
+            strip_prefix,
+            layer,
+            chunk_size,
+            max_depth,
+            |walker| walker.with_skip_digests(true),
+        )
+        .await?;
+        Ok(items)
+    }
+
+    // Walks with a walker set up by `configure` and collects the sent
+    // entries, whether the walk was truncated, and the skipped entries.
+    async fn collect<P: AsRef<StdPath>, F: FnOnce(Self) -> Self + Send + 'static>(
+        full_path: P,
+        strip_prefix: P,
+        layer: Arc<FsLayer>,
+        chunk_size: usize,
+        max_depth: Option<usize>,
+        configure: F,
+    ) -> Result<(Vec<FileInfo>, bool, Vec<(Path, Error)>), Error> {
         let full_path = full_path.as_ref().to_path_buf();
         let strip_prefix = strip_prefix.as_ref().to_path_buf();
-        let (tx, mut rx) = mpsc::channel(100);
+        let (tx, mut rx) = mpsc::channel(layer.walk_channel_capacity.load(Ordering::Relaxed));
         let x = tokio::spawn(async move {
-            let dir_walker = DirWalker::create(
+            let dir_walker = configure(DirWalker::create(
                 strip_prefix,
                 layer,
                 chunk_size,
                 max_depth,
                 tx,
                 HashMap::new(),
-            );
-            dir_walker.walk_dir_stream(&full_path).await
+            ));
+            dir_walker.walk_dir_stream(&full_path).await?;
+            Ok::<_, Error>((
+                dir_walker.truncated.load(Ordering::Relaxed),
+                dir_walker.errors.into_inner().unwrap(),
+            ))
+        });
+        let mut items = Vec::new();
+        while let Some(mut item) = rx.recv().await {
+            items.append(&mut item);
+        }
+        let (truncated, errors) = x.await.map_err(|e| Error::Read {
+            what: "failed to join walk_dir thread".to_owned(),
+            how: e.to_string(),
+        })??;
+        Ok((items, truncated, errors))
+    }
+
+    /// Like `walk_dir` but walks up to `concurrency` directories at a time,
+    /// each in its own task. Returned entries are the same as `walk_dir`'s,
+    /// though possibly in a different order.
+    pub async fn walk_dir_parallel<P: AsRef<StdPath>>(
+        full_path: P,
+        strip_prefix: P,
+        layer: Arc<FsLayer>,
+        chunk_size: usize,
+        max_depth: Option<usize>,
+        concurrency: NonZeroUsize,
+    ) -> Result<Vec<FileInfo>, Error> {
+        let full_path = full_path.as_ref().to_path_buf();
+        let strip_prefix = strip_prefix.as_ref().to_path_buf();
+        let (tx, mut rx) = mpsc::channel(layer.walk_channel_capacity.load(Ordering::Relaxed));
+        let x = tokio::spawn(async move {
+            let dir_walker = Arc::new(DirWalker::create(
+                strip_prefix,
+                layer,
+                chunk_size,
+                max_depth,
+                tx,
+                HashMap::new(),
+            ));
+            dir_walker.walk_parallel(full_path, concurrency).await
         });
         let mut items = Vec::new();
         while let Some(mut item) = rx.recv().await {
@@ -76,6 +270,66 @@ impl DirWalker {
         Ok(items)
     }
 
+    async fn walk_parallel(
+        self: Arc<Self>,
+        root: PathBuf,
+        concurrency: NonZeroUsize,
+    ) -> Result<(), Error> {
+        let max_depth = self.max_depth.unwrap_or(usize::MAX);
+        let mut pending = vec![(root, 0)];
+        let mut tasks = JoinSet::new();
+        loop {
+            while tasks.len() < concurrency.get() {
+                let Some((dir_path, depth)) = pending.pop() else {
+                    break;
+                };
+                let dir_walker = self.clone();
+                tasks.spawn(async move {
+                    let subdirs = dir_walker.walk_single(&dir_path).await?;
+                    Ok::<_, Error>((subdirs, depth))
+                });
+            }
+            let Some(ret) = tasks.join_next().await else {
+                break;
+            };
+            let (subdirs, depth) = ret.map_err(|e| Error::Read {
+                what: "failed to join walk_dir task".to_owned(),
+                how: e.to_string(),
+            })??;
+            if depth < max_depth {
+                pending.extend(subdirs.into_iter().map(|d| (d, depth + 1)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks entries of `dir_path` without descending and returns the
+    /// subdirectories to walk next.
+    async fn walk_single(&self, dir_path: &StdPath) -> Result<Vec<PathBuf>, Error> {
+        let content_only = self.layer.content_only_deltas.load(Ordering::Relaxed);
+        let mut chunks = Vec::with_capacity(self.chunk_size);
+        let mut subdirs = Vec::new();
+        let mut entries = Self::read_dir(dir_path).await?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry.map_err(|e| Error::Read {
+                what: "walkdir".into(),
+                how: e.to_string(),
+            })?;
+            let entry_path = entry.path();
+            let is_symlink = Self::is_symlink(&entry).await?;
+            if self
+                .process_entry(&entry_path, is_symlink, content_only, &mut chunks)
+                .await?
+            {
+                subdirs.push(entry_path);
+            }
+        }
+        if !chunks.is_empty() {
+            self.write_chunks(&mut chunks).await?;
+        }
+        Ok(subdirs)
+    }
+
     async fn write_chunks(&self, chunks: &mut Vec<FileInfo>) -> Result<(), Error> {
         self.tx
             .send(std::mem::take(chunks))
@@ -91,6 +345,11 @@ impl DirWalker {
     }
 
     async fn push_and_send(&self, chunks: &mut Vec<FileInfo>, item: FileInfo) -> Result<(), Error> {
+        let sent = self.entries.fetch_add(1, Ordering::Relaxed);
+        if self.max_entries.is_some_and(|max| sent >= max) {
+            self.truncated.store(true, Ordering::Relaxed);
+            return Ok(());
+        }
         chunks.push(item);
         if chunks.len() == self.chunk_size {
             self.write_chunks(chunks).await?;
@@ -98,94 +357,263 @@ impl DirWalker {
         Ok(())
     }
 
-    /// Walk a directory tree up to a specified depth
-    pub async fn walk_dir_stream<P: AsRef<StdPath>>(&self, full_path: &P) -> Result<(), Error> {
-        let mut chunks = Vec::with_capacity(self.chunk_size);
-        self.walk_recursive(full_path.as_ref(), 0, &mut chunks)
-            .await?;
-        Ok(())
+    async fn is_symlink(entry: &async_fs::DirEntry) -> Result<bool, Error> {
+        let file_type = entry.file_type().await.map_err(|e| Error::Read {
+            what: entry.path().to_string_lossy().to_string(),
+            how: e.to_string(),
+        })?;
+        Ok(file_type.is_symlink())
     }
 
-    #[async_recursion]
-    async fn walk_recursive(
+    /// Returns true if the symlink at `entry_path` can be followed without
+    /// leaving the walked root or looping back to one of its ancestors.
+    async fn may_follow(&self, entry_path: &StdPath) -> Result<bool, Error> {
+        let Ok(target) = tokio::fs::canonicalize(entry_path).await else {
+            debug!("skipping dangling link {}", entry_path.display());
+            return Ok(false);
+        };
+        let root = tokio::fs::canonicalize(&self.strip_prefix)
+            .await
+            .map_err(|e| Error::Read {
+                what: self.strip_prefix.to_string_lossy().to_string(),
+                how: e.to_string(),
+            })?;
+        if !target.starts_with(&root) {
+            debug!("skipping link {} out of root", entry_path.display());
+            return Ok(false);
+        }
+        for ancestor in entry_path
+            .ancestors()
+            .skip(1)
+            .take_while(|a| a.starts_with(&self.strip_prefix))
+        {
+            if tokio::fs::canonicalize(ancestor).await.ok().as_ref() == Some(&target) {
+                debug!("skipping link {} to an ancestor", entry_path.display());
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Looks up stats of `entry_path` and sends it if the filter allows it and
+    /// it differs from `lookup`. Symlinks are handled according to the
+    /// configured `SymlinkPolicy`.
+    ///
+    /// Returns true if `entry_path` is a directory that should be descended
+    /// into.
+    async fn process_entry(
         &self,
-        dir_path: &StdPath,
-        current_depth: usize,
+        entry_path: &StdPath,
+        is_symlink: bool,
+        content_only: bool,
         chunks: &mut Vec<FileInfo>,
-    ) -> Result<(), Error> {
-        // Stop if we've reached max depth
-        if current_depth > *self.max_depth.as_ref().unwrap_or(&usize::MAX) {
-            return Ok(());
+    ) -> Result<bool, Error> {
+        let relative_path = entry_path
+            .strip_prefix(&self.strip_prefix)
+            .map_err(|e| Error::Read {
+                what: "strip_prefix".into(),
+                how: e.to_string(),
+            })?
+            .to_owned();
+        if relative_path.to_str().is_none() {
+            match *self.layer.non_utf8_policy.read().unwrap() {
+                NonUtf8Policy::Skip => {
+                    debug!("skipping non UTF-8 {}", entry_path.display());
+                    return Ok(false);
+                }
+                NonUtf8Policy::Error => {
+                    return Err(Error::InvalidPath {
+                        what: format!("{} is not valid UTF-8", relative_path.display()),
+                    });
+                }
+                NonUtf8Policy::Lossy => {}
+            }
         }
+        let components = self.intern(&relative_path);
+        let portable_path = || Path::try_from(components.as_slice());
+        let policy = *self.layer.symlink_policy.read().unwrap();
+        let stats = match policy {
+            SymlinkPolicy::Deny if is_symlink => return Ok(false),
+            SymlinkPolicy::ReportOnly if is_symlink => {
+                let metadata =
+                    tokio::fs::symlink_metadata(entry_path)
+                        .await
+                        .map_err(|e| Error::Read {
+                            what: entry_path.to_string_lossy().to_string(),
+                            how: e.to_string(),
+                        })?;
+                let precision = self.layer.stat_options.read().unwrap().mtime_precision;
+                FileStat::from_metadata_with(&metadata, None, precision)
+            }
+            SymlinkPolicy::Follow if is_symlink && !self.may_follow(entry_path).await? => {
+                return Ok(false);
+            }
+            _ if self.skip_digests => {
+                let metadata = tokio::fs::metadata(entry_path)
+                    .await
+                    .map_err(|e| Error::Read {
+                        what: entry_path.to_string_lossy().to_string(),
+                        how: e.to_string(),
+                    })?;
+                let precision = self.layer.stat_options.read().unwrap().mtime_precision;
+                FileStat::from_metadata_with(&metadata, None, precision)
+            }
+            _ => match lookup_or_load(self.layer.clone(), entry_path, &portable_path()?).await {
+                Ok(stats) => stats,
+                Err(e) if self.skip_unreadable => {
+                    debug!("skipping unreadable {}: {e}", entry_path.display());
+                    self.errors.lock().unwrap().push((portable_path()?, e));
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        let stats = FileStat {
+            is_symlink,
+            ..stats
+        };
+        let is_dir = stats.is_directory;
+        let filter_level = self.filter.matches(&relative_path, is_dir).unwrap();
+        if filter_level == FilterLevel::Deny {
+            return Ok(false);
+        } else if filter_level == FilterLevel::Allow {
+            let skip_push = !self.should_emit(entry_path, is_dir).await?
+                || self
+                    .lookup
+                    .get(&relative_path)
+                    .map(|s| {
+                        if content_only {
+                            s.content_eq(&stats)
+                        } else {
+                            s.eq_ignoring_missing_digest(&stats)
+                        }
+                    })
+                    .unwrap_or(false);
+            if !skip_push {
+                self.push_and_send(
+                    chunks,
+                    FileInfo {
+                        path: portable_path()?,
+                        stats,
+                        digest_index: None,
+                    },
+                )
+                .await?;
+            }
+        }
+        Ok(is_dir)
+    }
 
-        // Read directory entries
-        let mut entries = async_fs::read_dir(&dir_path)
+    /// Splits `relative_path` into components shared with the other entries
+    /// of the walk. Invalid UTF-8 sequences are replaced.
+    fn intern(&self, relative_path: &StdPath) -> Vec<Arc<str>> {
+        let mut interned = self.interned.lock().unwrap();
+        relative_path
+            .components()
+            .map(|comp| {
+                let name = comp.as_os_str().to_string_lossy();
+                if let Some(shared) = interned.get(name.as_ref()) {
+                    return shared.clone();
+                }
+                let shared: Arc<str> = Arc::from(name.as_ref());
+                interned.insert(shared.clone());
+                shared
+            })
+            .collect()
+    }
+
+    /// Returns false if the allowed entry at `entry_path` should be walked but
+    /// not sent, as configured with `include_directories` and
+    /// `include_empty_dirs`.
+    async fn should_emit(&self, entry_path: &StdPath, is_dir: bool) -> Result<bool, Error> {
+        if !is_dir {
+            return Ok(true);
+        }
+        if !self.layer.include_directories.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        if self.layer.include_empty_dirs.load(Ordering::Relaxed) {
+            return Ok(true);
+        }
+        let mut entries = async_fs::read_dir(entry_path)
             .await
             .map_err(|e| Error::Read {
-                what: dir_path.to_string_lossy().to_string(),
+                what: entry_path.to_string_lossy().to_string(),
                 how: e.to_string(),
             })?;
+        Ok(entries.next().await.is_some())
+    }
 
-        // Process each entry
-        while let Some(entry) = entries.next().await {
+    /// Walk a directory tree up to a specified depth
+    ///
+    /// Entries are sent depth first, each directory followed by its subtree,
+    /// and the pending chunk is flushed once a directory's entries are done.
+    pub async fn walk_dir_stream<P: AsRef<StdPath>>(&self, full_path: &P) -> Result<(), Error> {
+        let max_depth = self.max_depth.unwrap_or(usize::MAX);
+        let content_only = self.layer.content_only_deltas.load(Ordering::Relaxed);
+        let mut chunks = Vec::with_capacity(self.chunk_size);
+        // Entries left to walk of the directories from the root down to the
+        // current one, with their depth
+        let mut stack = vec![(Self::read_dir(full_path.as_ref()).await?, 0)];
+        while let Some((entries, depth)) = stack.last_mut() {
+            let depth = *depth;
+            // Stop if we've hit the entry cap
+            let entry = if self.truncated.load(Ordering::Relaxed) {
+                None
+            } else {
+                entries.next().await
+            };
+            let Some(entry) = entry else {
+                stack.pop();
+                if !chunks.is_empty() {
+                    self.write_chunks(&mut chunks).await?;
+                }
+                continue;
+            };
             let entry = entry.map_err(|e| Error::Read {
                 what: "walkdir".into(),
                 how: e.to_string(),
             })?;
             let entry_path = entry.path();
-
-            let relative_path = entry_path
-                .strip_prefix(&self.strip_prefix)
-                .map_err(|e| Error::Read {
-                    what: "strip_prefix".into(),
-                    how: e.to_string(),
-                })?
-                .to_owned();
-            let portable_path = Path::try_from(&relative_path)?;
-            let stats = lookup_or_load(self.layer.clone(), &entry_path, &portable_path).await?;
-            let is_dir = stats.is_directory;
-            let filter_level = self
-                .layer
-                .filter_set
-                .read()
-                .unwrap()
-                .matches(&relative_path, is_dir)
-                .unwrap();
-            if filter_level == FilterLevel::Deny {
-                continue;
-            } else if filter_level == FilterLevel::Allow {
-                let skip_push = self
-                    .lookup
-                    .get(&relative_path)
-                    .map(|s| s == &stats)
-                    .unwrap_or(false);
-                if !skip_push {
-                    self.push_and_send(
-                        chunks,
-                        FileInfo {
-                            path: portable_path,
-                            stats,
-                        },
-                    )
-                    .await?;
+            let is_symlink = Self::is_symlink(&entry).await?;
+            // Denied directories are not descended into, so nothing under them
+            // is even stat-ed
+            if self
+                .process_entry(&entry_path, is_symlink, content_only, &mut chunks)
+                .await?
+                && depth < max_depth
+            {
+                match Self::read_dir(&entry_path).await {
+                    Ok(entries) => stack.push((entries, depth + 1)),
+                    Err(e) if self.skip_unreadable => self.skip_unlistable(&entry_path, e)?,
+                    Err(e) => return Err(e),
                 }
             }
-
-            if !is_dir {
-                continue;
-            }
-
-            // Recursively walk subdirectories
-            self.walk_recursive(&entry_path, current_depth + 1, chunks)
-                .await?;
-        }
-
-        if !chunks.is_empty() {
-            self.write_chunks(chunks).await?;
         }
+        Ok(())
+    }
 
+    /// Collects the error of listing directory `dir_path`.
+    fn skip_unlistable(&self, dir_path: &StdPath, e: Error) -> Result<(), Error> {
+        debug!("skipping unlistable {}: {e}", dir_path.display());
+        let relative_path = dir_path
+            .strip_prefix(&self.strip_prefix)
+            .map_err(|e| Error::Read {
+                what: "strip_prefix".into(),
+                how: e.to_string(),
+            })?;
+        // Non UTF-8 directories got past `NonUtf8Policy` already
+        let portable_path = Path::try_from_lossy(relative_path)?;
+        self.errors.lock().unwrap().push((portable_path, e));
         Ok(())
     }
+
+    async fn read_dir(dir_path: &StdPath) -> Result<async_fs::ReadDir, Error> {
+        async_fs::read_dir(dir_path).await.map_err(|e| Error::Read {
+            what: dir_path.to_string_lossy().to_string(),
+            how: e.to_string(),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -212,6 +640,165 @@ mod tests {
         (root, flist)
     }
 
+    // Lists `dir` recursively, each directory followed by its subtree, in the
+    // order of `std::fs::read_dir`
+    fn preorder(base: &StdPath, dir: &StdPath, depth: usize, max_depth: usize) -> Vec<String> {
+        let mut ret = vec![];
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            ret.push(
+                Path::try_from(path.strip_prefix(base).unwrap())
+                    .unwrap()
+                    .to_string(),
+            );
+            if path.is_dir() && depth < max_depth {
+                ret.extend(preorder(base, &path, depth + 1, max_depth));
+            }
+        }
+        ret
+    }
+
+    #[tokio::test]
+    async fn test_walk_order_matches_recursive() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let full_path = root.root.path();
+        for max_depth in [None, Some(0), Some(1)] {
+            let layer = Arc::new(FsLayer::new(
+                Box::new(NullCache::new(NonZero::new(100).unwrap())),
+                FilterSet::new(),
+            ));
+            let walked: Vec<String> =
+                DirWalker::walk_dir(full_path, full_path, layer, 2, max_depth)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|item| item.path.to_string())
+                    .collect();
+            let expected = preorder(full_path, full_path, 0, max_depth.unwrap_or(usize::MAX));
+            assert_eq!(walked, expected, "for {max_depth:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deep_tree() {
+        const DEPTH: usize = 1000;
+        let deepest = vec!["d"; DEPTH].join("/");
+        let file = format!("{deepest}/file.txt");
+        let root = TestRoot::builder()
+            .entries(&[(&file, "deep", false)])
+            .build()
+            .await
+            .unwrap();
+        let full_path = root.root.path();
+        let layer = Arc::new(FsLayer::new(
+            Box::new(NullCache::new(NonZero::new(100).unwrap())),
+            FilterSet::new(),
+        ));
+
+        let items = DirWalker::walk_dir(full_path, full_path, layer.clone(), 20, None)
+            .await
+            .unwrap();
+        assert_eq!(items.len(), DEPTH + 1);
+        for (depth, item) in items.iter().enumerate() {
+            assert_eq!(item.path.canonical_components().len(), depth + 1);
+        }
+        assert_eq!(items.last().unwrap().path.display_with('/'), file);
+
+        let items = DirWalker::walk_dir(full_path, full_path, layer, 20, Some(9))
+            .await
+            .unwrap();
+        assert_eq!(items.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_parallel_walk_matches_sequential() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let full_path = root.root.path();
+        for max_depth in [None, Some(0), Some(1)] {
+            let layer = Arc::new(FsLayer::new(
+                Box::new(NullCache::new(NonZero::new(100).unwrap())),
+                FilterSet::new(),
+            ));
+            let sequential = DirWalker::walk_dir(full_path, full_path, layer.clone(), 2, max_depth)
+                .await
+                .unwrap();
+            for concurrency in [1, 4] {
+                let parallel = DirWalker::walk_dir_parallel(
+                    full_path,
+                    full_path,
+                    layer.clone(),
+                    2,
+                    max_depth,
+                    NonZero::new(concurrency).unwrap(),
+                )
+                .await
+                .unwrap();
+                let expected: Vec<String> = sequential.iter().map(|i| i.path.to_string()).collect();
+                let expected: Vec<&str> = expected.iter().map(|s| s.as_str()).collect();
+                assert_eq!(parallel.len(), sequential.len());
+                check_expected(&parallel, &expected);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_files_only() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let full_path = root.root.path();
+        let layer = FsLayer::new(
+            Box::new(NullCache::new(NonZero::new(100).unwrap())),
+            FilterSet::new(),
+        );
+        layer.include_directories.store(false, Ordering::Relaxed);
+        let flist = DirWalker::walk_dir(full_path, full_path, Arc::new(layer), 2, None)
+            .await
+            .unwrap();
+
+        let expected = [
+            "file1.txt",
+            "file2.txt",
+            "dir1/file3.txt",
+            "dir1/dir2/file4.txt",
+            "dir3/file6.txt",
+            "dir1/file7.md",
+            "dir1/file8.rs",
+        ];
+        check_expected(&flist, &expected);
+    }
+
+    #[tokio::test]
+    async fn test_include_empty_dirs() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let full_path = root.root.path();
+        let layer = Arc::new(FsLayer::new(
+            Box::new(NullCache::new(NonZero::new(100).unwrap())),
+            FilterSet::new(),
+        ));
+        let dirs = |flist: &[FileInfo]| {
+            let mut dirs: Vec<String> = flist
+                .iter()
+                .filter(|item| item.stats.is_directory)
+                .map(|item| item.path.display_with('/'))
+                .collect();
+            dirs.sort();
+            dirs
+        };
+
+        let flist = DirWalker::walk_dir(full_path, full_path, layer.clone(), 2, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            dirs(&flist),
+            ["dir1", "dir1/dir2", "dir1/dir2/dir_empty1", "dir3"]
+        );
+
+        layer.include_empty_dirs.store(false, Ordering::Relaxed);
+        let flist = DirWalker::walk_dir(full_path, full_path, layer, 2, None)
+            .await
+            .unwrap();
+        assert_eq!(dirs(&flist), ["dir1", "dir1/dir2", "dir3"]);
+    }
+
     #[tokio::test]
     async fn test_selective_allow() {
         let mut fset = FilterSet::new();
@@ -236,6 +823,26 @@ mod tests {
         assert!(expected.is_empty(), "{:?}", expected);
     }
 
+    #[tokio::test]
+    async fn test_allowed_root_depth() {
+        let mut fset = FilterSet::new();
+        fset.allow_path_with_depth("dir1", 1);
+        fset.allow_path("dir3");
+        let (_root, flist) = setup_test(fset).await;
+        check_expected(
+            &flist,
+            &[
+                "dir1",
+                "dir1/file3.txt",
+                "dir1/dir2",
+                "dir1/file7.md",
+                "dir1/file8.rs",
+                "dir3",
+                "dir3/file6.txt",
+            ],
+        );
+    }
+
     #[tokio::test]
     async fn test_selective_deny() {
         let mut fset = FilterSet::new();
@@ -298,6 +905,171 @@ mod tests {
         check_expected(&flist, &expected);
     }
 
+    #[tokio::test]
+    async fn test_backpressure() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let full_path = root.root.path().to_path_buf();
+        let layer = Arc::new(FsLayer::new(
+            Box::new(NullCache::new(NonZero::new(100).unwrap())),
+            FilterSet::new(),
+        ));
+        let (tx, mut rx) = mpsc::channel(1);
+        let walker = DirWalker::create(
+            full_path.clone(),
+            layer.clone(),
+            1,
+            None,
+            tx,
+            HashMap::new(),
+        );
+        let handle = tokio::spawn(async move { walker.walk_dir_stream(&full_path).await });
+
+        // The walker stalls with a single chunk buffered until it is consumed
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!handle.is_finished());
+        assert_eq!(rx.len(), 1);
+
+        let mut count = 0;
+        while let Some(chunk) = rx.recv().await {
+            assert_eq!(chunk.len(), 1);
+            count += 1;
+        }
+        handle.await.unwrap().unwrap();
+        assert_eq!(count, root.files.len());
+
+        // A walk with the smallest buffer returns the same entries
+        layer.walk_channel_capacity.store(1, Ordering::Relaxed);
+        let flist = DirWalker::walk_dir(root.root.path(), root.root.path(), layer, 1, None)
+            .await
+            .unwrap();
+        assert_eq!(flist.len(), root.files.len());
+    }
+
+    #[tokio::test]
+    async fn test_interned_components_bounded() {
+        // Binary trees of `a` and `b` directories with an `f.txt` in each
+        // leaf only ever use three distinct names
+        for depth in [3, 6] {
+            let root = tempdir::TempDir::new("").unwrap();
+            for i in 0..1usize << depth {
+                let mut leaf = root.path().to_path_buf();
+                for bit in 0..depth {
+                    leaf.push(if (i >> bit) & 1 == 0 { "a" } else { "b" });
+                }
+                std::fs::create_dir_all(&leaf).unwrap();
+                std::fs::write(leaf.join("f.txt"), "x").unwrap();
+            }
+            let full_path = root.path().to_path_buf();
+            let layer = Arc::new(FsLayer::new(
+                Box::new(NullCache::new(NonZero::new(100).unwrap())),
+                FilterSet::new(),
+            ));
+            let (tx, mut rx) = mpsc::channel(1);
+            let walker = DirWalker::create(full_path.clone(), layer, 4, None, tx, HashMap::new());
+            let handle = tokio::spawn(async move {
+                walker.walk_dir_stream(&full_path).await?;
+                Ok::<_, Error>(walker.interned.into_inner().unwrap())
+            });
+            let mut count = 0;
+            while let Some(chunk) = rx.recv().await {
+                count += chunk.len();
+            }
+            let interned = handle.await.unwrap().unwrap();
+            // All directories and leaves of the tree
+            assert_eq!(count, (1 << (depth + 1)) - 2 + (1 << depth));
+            let mut names: Vec<&str> = interned.iter().map(|n| n.as_ref()).collect();
+            names.sort();
+            assert_eq!(names, ["a", "b", "f.txt"], "for depth {depth}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_denied_dir_not_entered() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let full_path = root.root.path();
+        let mut fset = FilterSet::new();
+        fset.deny_path("dir1");
+        let layer = Arc::new(FsLayer::new(
+            Box::new(crate::native::FsCache::new(NonZero::new(100).unwrap())),
+            fset,
+        ));
+
+        for flist in [
+            DirWalker::walk_dir(full_path, full_path, layer.clone(), 2, None)
+                .await
+                .unwrap(),
+            DirWalker::walk_dir_parallel(
+                full_path,
+                full_path,
+                layer.clone(),
+                2,
+                None,
+                NonZero::new(4).unwrap(),
+            )
+            .await
+            .unwrap(),
+        ] {
+            check_expected(
+                &flist,
+                &["file1.txt", "file2.txt", "dir3", "dir3/file6.txt"],
+            );
+        }
+
+        // Only the denied directory itself was looked up, none of its entries
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+        for key in layer.cache.lock().unwrap().iter_keys() {
+            assert!(
+                key == dir1
+                    || !key
+                        .canonical_components()
+                        .starts_with(&["dir1".to_string()]),
+                "for {key}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_snapshot() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let full_path = root.root.path();
+        let mut fset = FilterSet::new();
+        fset.deny_path("dir1");
+        let layer = Arc::new(FsLayer::new(
+            Box::new(NullCache::new(NonZero::new(100).unwrap())),
+            fset,
+        ));
+        let (tx, mut rx) = mpsc::channel(100);
+        let dir_walker = DirWalker::create(full_path, layer.clone(), 1, None, tx, HashMap::new());
+
+        // Entries are matched against the filter set as of the walk's start,
+        // without taking its lock
+        let mut filter_set = layer.filter_set.write().unwrap();
+        *filter_set = FilterSet::new();
+        dir_walker.walk_dir_stream(&full_path).await.unwrap();
+        drop(filter_set);
+        drop(dir_walker);
+
+        let mut flist = vec![];
+        while let Some(mut chunk) = rx.recv().await {
+            flist.append(&mut chunk);
+        }
+        check_expected(
+            &flist,
+            &["file1.txt", "file2.txt", "dir3", "dir3/file6.txt"],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_directories_only() {
+        let mut fset = FilterSet::new();
+        fset.allow_extension("txt");
+        fset.set_directories_only(true);
+        let (_root, flist) = setup_test(fset).await;
+
+        let expected = ["dir1", "dir1/dir2", "dir1/dir2/dir_empty1", "dir3"];
+        check_expected(&flist, &expected);
+    }
+
     #[tokio::test]
     async fn test_allow_denied() {
         let mut fset = FilterSet::new();
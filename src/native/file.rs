@@ -1,11 +1,13 @@
+use std::fs::Metadata;
 use std::path::Path as StdPath;
+use std::path::PathBuf;
 
 use async_fs::DirEntry;
+use tokio::sync::OnceCell;
 
 use crate::FileStat;
+use crate::StatOptions;
 use crate::errors::Error;
-use crate::hash::Sha256Builder;
-use crate::hash::Sha256String;
 
 impl FileStat {
     /// Creates a `FileStat` from a directory entry, including digest for files.
@@ -18,16 +20,286 @@ impl FileStat {
     /// Creates a `FileStat` from a directory entry, including digest for files.
     #[cfg(not(target_arch = "wasm32"))]
     pub async fn from_path<P: AsRef<StdPath>>(path: P) -> Result<Self, Error> {
+        Self::from_path_with(path, &StatOptions::default()).await
+    }
+
+    /// Creates a `FileStat` from a path, including digest for files computed
+    /// with `options.hash_algorithm` and rendered in `options.digest_encoding`.
+    ///
+    /// When `options.capture_xattrs` is set, extended attributes of the path
+    /// are read into `xattrs`. This is supported only on unix with the
+    /// `xattr` feature enabled; elsewhere `xattrs` is left `None`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn from_path_with<P: AsRef<StdPath>>(
+        path: P,
+        options: &StatOptions,
+    ) -> Result<Self, Error> {
+        #[cfg_attr(not(all(unix, feature = "xattr")), allow(unused_mut))]
+        let mut stats = Self::load(path.as_ref(), options).await?;
+        #[cfg(all(unix, feature = "xattr"))]
+        if options.capture_xattrs {
+            stats.xattrs = Some(super::xattr::read_xattrs(path.as_ref()).await?);
+        }
+        Ok(stats)
+    }
+
+    /// Creates a `FileStat` describing `path` itself rather than what it
+    /// links to, if it is a symlink. The size is that of the link and nothing
+    /// is hashed, so `sha256` is `None`. See `from_path` to follow links.
+    pub async fn from_symlink_path<P: AsRef<StdPath>>(path: P) -> Result<Self, Error> {
+        Self::from_symlink_path_with(path, &StatOptions::default(), false).await
+    }
+
+    /// Like `from_symlink_path`, but if `hash_link` is set, the digest of a
+    /// symlink is that of its target path, computed as configured in
+    /// `options`. Other paths are never hashed.
+    pub async fn from_symlink_path_with<P: AsRef<StdPath>>(
+        path: P,
+        options: &StatOptions,
+        hash_link: bool,
+    ) -> Result<Self, Error> {
         let path = path.as_ref();
-        let metadata = tokio::fs::metadata(&path).await.map_err(|e| Error::Read {
-            what: "metadata".into(),
+        let read_error = |e: std::io::Error| Error::Read {
+            what: path.to_string_lossy().to_string(),
             how: e.to_string(),
-        })?;
-        if metadata.is_dir() {
-            Ok(FileStat::from_metadata(&metadata, Some("".to_string())))
+        };
+        let metadata = tokio::fs::symlink_metadata(path)
+            .await
+            .map_err(read_error)?;
+        let is_symlink = metadata.file_type().is_symlink();
+        let sha256 = if is_symlink && hash_link {
+            let target = tokio::fs::read_link(path).await.map_err(read_error)?;
+            let digest = options
+                .hash_algorithm
+                .digest_bytes(target.as_os_str().as_encoded_bytes())
+                .await?;
+            Some(options.digest_encoding.encode(&digest)?)
         } else {
-            let sha256 = path.sha256_build().await?.sha256_string().await?;
-            Ok(FileStat::from_metadata(&metadata, Some(sha256)))
+            None
+        };
+        Ok(FileStat {
+            is_symlink,
+            ..FileStat::from_metadata_with(&metadata, sha256, options.mtime_precision)
+        })
+    }
+
+    async fn metadata(path: &StdPath) -> Result<Metadata, Error> {
+        tokio::fs::metadata(&path).await.map_err(|e| Error::Read {
+            what: "metadata".into(),
+            how: e.to_string(),
+        })
+    }
+
+    /// Loads stats of `path`, hashing file contents.
+    ///
+    /// The file is stat-ed again after hashing. If its size or mtime changed
+    /// meanwhile, the file was modified while being hashed and the digest
+    /// can't be trusted. Hashing is retried once before giving up with
+    /// `Error::Read`.
+    async fn load(path: &StdPath, options: &StatOptions) -> Result<Self, Error> {
+        const MAX_ATTEMPTS: usize = 2;
+        for _ in 0..MAX_ATTEMPTS {
+            let metadata = Self::metadata(path).await?;
+            if metadata.is_dir() {
+                return Ok(FileStat::from_metadata_with(
+                    &metadata,
+                    Some("".to_string()),
+                    options.mtime_precision,
+                ));
+            }
+            let sha256 = digest_with(path, options).await?;
+            let sha256 = options.digest_encoding.encode(&sha256)?;
+            let after = Self::metadata(path).await?;
+            if metadata.len() == after.len() && metadata.modified().ok() == after.modified().ok() {
+                return Ok(FileStat::from_metadata_with(
+                    &metadata,
+                    Some(sha256),
+                    options.mtime_precision,
+                ));
+            }
         }
+        Err(Error::Read {
+            what: path.to_string_lossy().to_string(),
+            how: "file changed during hashing".into(),
+        })
+    }
+}
+
+/// Stats of a path whose digest is computed on first request rather than on
+/// load, for listings that rarely need digests.
+#[derive(Debug)]
+pub struct LazyFileStat {
+    path: PathBuf,
+    stats: FileStat,
+    options: StatOptions,
+    digest: OnceCell<String>,
+}
+
+impl LazyFileStat {
+    /// Loads the metadata of `path` without hashing it.
+    pub async fn from_path<P: AsRef<StdPath>>(path: P) -> Result<Self, Error> {
+        Self::from_path_with(path, &StatOptions::default()).await
+    }
+
+    /// Loads the metadata of `path` without hashing it. The digest will be
+    /// computed as configured in `options`.
+    pub async fn from_path_with<P: AsRef<StdPath>>(
+        path: P,
+        options: &StatOptions,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let metadata = FileStat::metadata(&path).await?;
+        let stats = FileStat::from_metadata_with(&metadata, None, options.mtime_precision);
+        // Directories have no contents to digest
+        let digest = if stats.is_directory {
+            OnceCell::new_with(Some(String::new()))
+        } else {
+            OnceCell::new()
+        };
+        Ok(Self {
+            path,
+            stats,
+            options: options.clone(),
+            digest,
+        })
+    }
+
+    /// Returns the stats loaded so far. `sha256` is `None` for files, see
+    /// `digest` and `resolve`.
+    pub fn stats(&self) -> &FileStat {
+        &self.stats
+    }
+
+    /// Returns the tagged digest of the file, hashing it on the first call.
+    ///
+    /// The digest is that of the contents at the time of the first call,
+    /// which may differ from those described by `stats` if the file changed
+    /// meanwhile.
+    pub async fn digest(&self) -> Result<&str, Error> {
+        self.digest
+            .get_or_try_init(|| async {
+                let digest = digest_with(&self.path, &self.options).await?;
+                self.options.digest_encoding.encode(&digest)
+            })
+            .await
+            .map(String::as_str)
+    }
+
+    /// Returns the complete stats, hashing the file if not done yet.
+    pub async fn resolve(self) -> Result<FileStat, Error> {
+        let digest = self.digest().await?.to_owned();
+        Ok(FileStat {
+            sha256: Some(digest),
+            ..self.stats
+        })
+    }
+}
+
+/// Returns the tagged digest of the file at `path`, hashed as configured by
+/// `options`.
+async fn digest_with(path: &StdPath, options: &StatOptions) -> Result<String, Error> {
+    #[cfg(feature = "mmap")]
+    if options.use_mmap
+        && tokio::fs::metadata(path)
+            .await
+            .is_ok_and(|m| m.len() >= crate::hash::MMAP_THRESHOLD)
+    {
+        return options.hash_algorithm.digest_mapped(path).await;
+    }
+    options
+        .hash_algorithm
+        .digest_path_with(path, options.hash_buffer_size, &options.retry_policy)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lazy_file_stat() {
+        let dir = tempdir::TempDir::new("").unwrap();
+        let path = dir.path().join("file.txt");
+        tokio::fs::write(&path, "before").await.unwrap();
+        let lazy = LazyFileStat::from_path(&path).await.unwrap();
+        assert_eq!(lazy.stats().size, 6);
+        assert_eq!(lazy.stats().sha256, None);
+
+        // Not hashed on load
+        tokio::fs::write(&path, "during").await.unwrap();
+        let during = FileStat::from_path(&path).await.unwrap().sha256.unwrap();
+        assert_eq!(lazy.digest().await.unwrap(), during);
+
+        // Hashed only once
+        tokio::fs::write(&path, "after!").await.unwrap();
+        assert_eq!(lazy.digest().await.unwrap(), during);
+        let stats = lazy.resolve().await.unwrap();
+        assert_eq!(stats.sha256, Some(during));
+        assert_eq!(stats.size, 6);
+
+        let lazy = LazyFileStat::from_path(dir.path()).await.unwrap();
+        assert_eq!(lazy.digest().await.unwrap(), "");
+        assert_eq!(
+            lazy.resolve().await.unwrap(),
+            FileStat::from_path(dir.path()).await.unwrap()
+        );
+    }
+
+    #[cfg(feature = "mmap")]
+    #[tokio::test]
+    async fn test_use_mmap() {
+        let dir = tempdir::TempDir::new("").unwrap();
+        let path = dir.path().join("large.bin");
+        let data: Vec<u8> = (0..crate::hash::MMAP_THRESHOLD + 1)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let chunked = FileStat::from_path(&path).await.unwrap();
+        let options = StatOptions {
+            use_mmap: true,
+            ..StatOptions::default()
+        };
+        let mapped = FileStat::from_path_with(&path, &options).await.unwrap();
+        assert_eq!(mapped, chunked);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_from_symlink_path() {
+        use crate::hash::HashAlgorithm;
+
+        let dir = tempdir::TempDir::new("").unwrap();
+        let target = dir.path().join("target.txt");
+        tokio::fs::write(&target, vec![b'a'; 1000]).await.unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink("target.txt", &link).unwrap();
+
+        let stats = FileStat::from_symlink_path(&link).await.unwrap();
+        assert!(stats.is_symlink);
+        assert!(!stats.is_directory);
+        assert_eq!(stats.size, "target.txt".len() as u64);
+        assert_eq!(stats.sha256, None);
+        assert_eq!(FileStat::from_path(&link).await.unwrap().size, 1000);
+
+        let stats = FileStat::from_symlink_path_with(&link, &StatOptions::default(), true)
+            .await
+            .unwrap();
+        assert_eq!(
+            stats.sha256,
+            Some(
+                HashAlgorithm::Sha256
+                    .digest_bytes(b"target.txt")
+                    .await
+                    .unwrap()
+            )
+        );
+
+        let stats = FileStat::from_symlink_path_with(&target, &StatOptions::default(), true)
+            .await
+            .unwrap();
+        assert!(!stats.is_symlink);
+        assert_eq!(stats.size, 1000);
+        assert_eq!(stats.sha256, None);
     }
 }
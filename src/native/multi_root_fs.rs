@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::FsCache;
+use crate::CacheStats;
+use crate::Directory;
+use crate::Error;
+use crate::FileInfo;
+use crate::FileNode;
+use crate::FilterSet;
+use crate::Path;
+use crate::PortableFs;
+use crate::portable_fs::FsLayer;
+
+/// Serves several named roots, e.g. one per tenant, sharing one stat cache
+/// and filter.
+///
+/// Each root is confined like a fs created with `PortableFs::new_checked`,
+/// so paths of one root can't reach into another. Cached stats are keyed by
+/// root name, so roots holding the same relative paths don't collide.
+#[derive(Clone)]
+pub struct MultiRootFs {
+    layer: Arc<FsLayer>,
+    roots: HashMap<String, PortableFs>,
+}
+
+impl MultiRootFs {
+    /// Creates a fs serving `roots`, pairs of root name and base directory,
+    /// with a shared cache.
+    ///
+    /// Returns `Error::InvalidArgument` if a name is not a valid path
+    /// component or is repeated, and `Error::InvalidPath` if a base
+    /// directory is not an existing directory.
+    pub fn new<I, S>(roots: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (S, PathBuf)>,
+        S: Into<String>,
+    {
+        Self::with_filter(roots, FilterSet::new())
+    }
+
+    /// Creates a fs serving `roots` with a shared cache, applying `filter`
+    /// to every root from the start.
+    pub fn with_filter<I, S>(roots: I, filter: FilterSet) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (S, PathBuf)>,
+        S: Into<String>,
+    {
+        let layer = Arc::new(FsLayer::new(
+            Box::new(FsCache::new(NonZeroUsize::new(1000).unwrap())),
+            filter,
+        ));
+        let mut fs = Self {
+            layer,
+            roots: HashMap::new(),
+        };
+        for (name, base_dir) in roots {
+            fs.add_root(name.into(), base_dir)?;
+        }
+        Ok(fs)
+    }
+
+    fn add_root(&mut self, name: String, base_dir: PathBuf) -> Result<(), Error> {
+        let namespace = Path::try_from([name.as_str()].as_slice())?;
+        if self.roots.contains_key(&name) {
+            return Err(Error::InvalidArgument(format!("duplicate root {name}")));
+        }
+        let mut root = PortableFs::new_checked(base_dir)?;
+        root.layer = Arc::new(FsLayer {
+            cache_namespace: Some(namespace),
+            ..(*self.layer).clone()
+        });
+        self.roots.insert(name, root);
+        Ok(())
+    }
+
+    /// Returns the names of the served roots, in no particular order.
+    pub fn root_names(&self) -> impl Iterator<Item = &str> {
+        self.roots.keys().map(String::as_str)
+    }
+
+    /// Returns the fs of root `name`, sharing the cache and filter of the
+    /// other roots.
+    ///
+    /// Returns `Error::InvalidArgument` if there is no such root.
+    pub fn root(&self, name: &str) -> Result<&PortableFs, Error> {
+        self.roots
+            .get(name)
+            .ok_or_else(|| Error::InvalidArgument(format!("unknown root {name}")))
+    }
+
+    /// Reads the directory at `path` under root `name`. See
+    /// `PortableFs::read_dir`.
+    pub async fn read_dir(&self, name: &str, path: &Path) -> Result<Directory, Error> {
+        self.root(name)?.read_dir(path).await
+    }
+
+    /// Lists the tree at `path` under root `name`. See
+    /// `PortableFs::read_dir_recurse`.
+    pub async fn read_dir_recurse(&self, name: &str, path: &Path) -> Result<Vec<FileInfo>, Error> {
+        self.root(name)?.read_dir_recurse(path).await
+    }
+
+    /// Reads the file at `path` under root `name`. See
+    /// `PortableFs::read_file`.
+    pub async fn read_file(&self, name: &str, path: &Path) -> Result<FileNode, Error> {
+        self.root(name)?.read_file(path).await
+    }
+
+    /// Replaces the filter of all roots. See `PortableFs::set_filter`.
+    pub fn set_filter(&self, filter: FilterSet) {
+        *self.layer.filter_set.write().unwrap() = filter;
+    }
+
+    /// Returns the counters of lookups in the shared stat cache, across all
+    /// roots.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.layer.cache.lock().unwrap().stats().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestRoot;
+
+    fn path(components: &[&str]) -> Path {
+        Path::try_from(components).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unknown_root() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = MultiRootFs::new([("a", root.root.path().join("dir1"))]).unwrap();
+        assert!(fs.read_dir("a", &Path::empty()).await.is_ok());
+        assert!(matches!(
+            fs.read_dir("b", &Path::empty()).await,
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let dup = [
+            ("a", root.root.path().join("dir1")),
+            ("a", root.root.path().join("dir3")),
+        ];
+        assert!(matches!(
+            MultiRootFs::new(dup),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            MultiRootFs::new([("a/b", root.root.path().join("dir1"))]),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_roots_are_confined() {
+        let root = TestRoot::new(None).await.unwrap();
+        let a = root.root.path().join("dir1");
+        let b = root.root.path().join("dir3");
+        std::os::unix::fs::symlink(&b, a.join("to_b")).unwrap();
+        let fs = MultiRootFs::new([("a", a), ("b", b)]).unwrap();
+
+        assert!(fs.read_file("b", &path(&["file6.txt"])).await.is_ok());
+        assert!(matches!(
+            fs.read_file("a", &path(&["to_b", "file6.txt"])).await,
+            Err(Error::InvalidPath { .. })
+        ));
+        assert!(matches!(
+            fs.read_dir("a", &path(&["to_b"])).await,
+            Err(Error::InvalidPath { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shared_cache() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let a = root.root.path().join("dir1");
+        let b = root.root.path().join("dir3");
+        // Same relative path and size in both roots
+        std::fs::write(a.join("same.txt"), "aaaa").unwrap();
+        std::fs::write(b.join("same.txt"), "bbbb").unwrap();
+        let fs = MultiRootFs::new([("a", a), ("b", b)]).unwrap();
+        let same = path(&["same.txt"]);
+
+        for _ in 0..2 {
+            assert_eq!(fs.read_file("a", &same).await.unwrap().contents, b"aaaa");
+            assert_eq!(fs.read_file("b", &same).await.unwrap().contents, b"bbbb");
+        }
+        let stats = fs.cache_stats();
+        assert_eq!(stats, fs.root("a").unwrap().cache_stats());
+        assert_eq!(stats.hits, 2);
+
+        // Each root only sees its own keys
+        assert_eq!(fs.root("a").unwrap().cache_keys(), vec![same.clone()]);
+        assert_eq!(fs.root("b").unwrap().cache_keys(), vec![same]);
+        let a_stats = fs.read_dir("a", &Path::empty()).await.unwrap();
+        let b_stats = fs.read_dir("b", &Path::empty()).await.unwrap();
+        let digest = |dir: &Directory| {
+            let entry = dir.items.iter().find(|e| e.name == "same.txt").unwrap();
+            entry.stats.sha256.clone()
+        };
+        assert_ne!(digest(&a_stats), digest(&b_stats));
+
+        let filter: FilterSet = serde_json::from_str(
+            r#"{
+                "allowed_roots": [],
+                "denied_roots": [],
+                "allowed_extensions": ["md"],
+                "allowed_filenames": []
+            }"#,
+        )
+        .unwrap();
+        fs.set_filter(filter.clone());
+        assert_eq!(fs.root("a").unwrap().filter(), filter);
+        let dir = fs.read_dir("b", &Path::empty()).await.unwrap();
+        assert!(dir.items.is_empty());
+    }
+}
@@ -36,11 +36,18 @@ impl Cache for FsCache {
         self.lru.put(key, value);
     }
 
-    #[cfg(test)]
+    fn peek(&self, key: &Path) -> Option<&FileStat> {
+        self.lru.peek(key)
+    }
+
     fn stats(&self) -> &CacheStats {
         &self.stats
     }
 
+    fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
     #[cfg(test)]
     fn len(&self) -> u64 {
         self.lru.len() as u64
@@ -50,6 +57,14 @@ impl Cache for FsCache {
         self.lru.pop(key)
     }
 
+    fn iter_keys(&self) -> Vec<Path> {
+        self.lru.iter().map(|(k, _v)| k.clone()).collect()
+    }
+
+    fn capacity(&self) -> usize {
+        self.lru.cap().get()
+    }
+
     #[cfg(test)]
     fn dump_keys(&self) -> String {
         self.lru.iter().for_each(|(k, _v)| println!("\"{}\"", k));
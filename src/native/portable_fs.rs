@@ -1,39 +1,225 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use std::path::Component;
 use std::path::Path as StdPath;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::MutexGuard;
+use std::sync::RwLock;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use std::time::SystemTime;
 
 use log::debug;
 use log::error;
+use log::warn;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc::Sender;
 
 use super::dir_walker::DirWalker;
+use crate::DirListChunk;
 use crate::Directory;
+use crate::DiskUsage;
 use crate::Error;
 use crate::FileInfo;
+use crate::FileNode;
 use crate::FileStat;
+use crate::FilterSet;
+use crate::Manifest;
 use crate::Path;
 use crate::PortableFs;
 use crate::RecursiveDirList;
+use crate::SCHEMA_VERSION;
+use crate::SortKey;
+use crate::SymlinkPolicy;
 use crate::cache::Cache;
+use crate::filter::FilterLevel;
+use crate::hash::DIR_DIGEST_TAG;
+use crate::hash::DigestEncoding;
+use crate::hash::Sha256String;
 use crate::portable_fs::FsLayer;
+use crate::utils::parse_system_time;
 
 pub(crate) async fn lookup_or_load(
     layer: Arc<FsLayer>,
     path: &StdPath,
     portable_path: &Path,
 ) -> Result<FileStat, Error> {
-    if let Some(stats) = layer.cache.lock().unwrap().get(portable_path) {
-        Ok(stats.clone())
-    } else {
-        use crate::FileStat;
-
-        let stats = FileStat::from_path(path).await?;
-        layer
-            .cache
-            .lock()
-            .unwrap()
-            .put(portable_path.clone(), stats.clone());
-        Ok(stats)
+    let start = Instant::now();
+    let key = layer.cache_key(portable_path);
+    let cached = layer.cache.lock().unwrap().get(&key).cloned();
+    if let Some(stats) = cached {
+        if let Some(observer) = layer.observer() {
+            observer.on_stat(portable_path, start.elapsed(), true);
+        }
+        return Ok(stats);
+    }
+    // Wait for concurrent loads of the same path, which likely cached it
+    let in_flight = layer
+        .in_flight
+        .lock()
+        .unwrap()
+        .entry(key.clone())
+        .or_default()
+        .clone();
+    let guard = in_flight.lock().await;
+    let cached = layer.cache.lock().unwrap().peek(&key).cloned();
+    let hit = cached.is_some();
+    let ret = match cached {
+        Some(stats) => Ok(stats),
+        None => {
+            let options = layer.stat_options.read().unwrap().clone();
+            #[cfg(test)]
+            layer.loads.fetch_add(1, Ordering::Relaxed);
+            let loading = Instant::now();
+            FileStat::from_path_with(path, &options)
+                .await
+                .inspect(|stats| {
+                    layer.cache.lock().unwrap().put(key.clone(), stats.clone());
+                    // Directories are loaded with an empty digest
+                    let hashed = !stats.is_directory && stats.sha256.is_some();
+                    if let Some(observer) = layer.observer().filter(|_| hashed) {
+                        observer.on_hash(portable_path, loading.elapsed(), stats.size);
+                    }
+                    if !stats.is_directory && layer.sibling_prefetch.load(Ordering::Relaxed) {
+                        tokio::spawn(prefetch_siblings(
+                            layer.clone(),
+                            path.to_path_buf(),
+                            portable_path.clone(),
+                        ));
+                    }
+                })
+        }
+    };
+    drop(guard);
+    let mut in_flight_map = layer.in_flight.lock().unwrap();
+    // Only this lookup and the map hold the lock, so no one is waiting on it
+    if Arc::strong_count(&in_flight) == 2 {
+        in_flight_map.remove(&key);
+    }
+    drop(in_flight_map);
+    if let Some(observer) = layer.observer().filter(|_| ret.is_ok()) {
+        observer.on_stat(portable_path, start.elapsed(), hit);
+    }
+    ret
+}
+
+// Caches the stats of the files next to `path`, whose portable path is
+// `portable_path`, that pass the filter and aren't cached yet. Stops before
+// evicting `path` from the cache.
+async fn prefetch_siblings(layer: Arc<FsLayer>, path: PathBuf, portable_path: Path) {
+    let (Some(dir), Some(portable_dir)) = (path.parent(), portable_path.parent()) else {
+        return;
+    };
+    let limit = layer.cache.lock().unwrap().capacity().saturating_sub(1);
+    let options = layer.stat_options.read().unwrap().clone();
+    let filter = layer.filter_set.read().unwrap().compile();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return;
+    };
+    let mut prefetched = 0;
+    while prefetched < limit {
+        let Ok(Some(entry)) = entries.next_entry().await else {
+            break;
+        };
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        if !entry.file_type().await.is_ok_and(|t| t.is_file()) {
+            continue;
+        }
+        let mut sibling = portable_dir.clone();
+        sibling.push(&name);
+        let relative = sibling.append_to(StdPath::new(""));
+        if !filter
+            .matches(&relative, false)
+            .is_ok_and(|level| level == FilterLevel::Allow)
+        {
+            continue;
+        }
+        let key = layer.cache_key(&sibling);
+        if layer.cache.lock().unwrap().peek(&key).is_some() {
+            continue;
+        }
+        match FileStat::from_path_with(entry.path(), &options).await {
+            Ok(stats) => layer.cache.lock().unwrap().put(key, stats),
+            Err(e) => debug!("failed to prefetch {sibling}: {e}"),
+        }
+        prefetched += 1;
+    }
+}
+
+// Sets the size of each directory in `items` to the sum of the sizes of the
+// files under it. Files are attributed to every ancestor directory present in
+// `items`.
+fn backfill_dir_sizes(items: &mut [FileInfo]) {
+    let dirs: HashMap<Path, usize> = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item.stats.is_directory)
+        .map(|(index, item)| (item.path.clone(), index))
+        .collect();
+    let mut sizes = vec![0; items.len()];
+    for item in items.iter().filter(|item| !item.stats.is_directory) {
+        let mut ancestor = item.path.parent();
+        while let Some(path) = ancestor {
+            if let Some(index) = dirs.get(&path) {
+                sizes[*index] += item.stats.size;
+            }
+            ancestor = path.parent();
+        }
+    }
+    for index in dirs.into_values() {
+        items[index].stats.size = sizes[index];
+    }
+}
+
+// Sets the digest of each directory in `items` to a digest of its children
+// in `items`, bottom-up, so that directories with identical filtered subtrees
+// get identical digests. Children are fed sorted by name, each followed by
+// its digest. Directory digests are rendered in `encoding`.
+fn backfill_dir_digests(items: &mut [FileInfo], encoding: DigestEncoding) {
+    let dirs: HashMap<Path, usize> = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item.stats.is_directory)
+        .map(|(index, item)| (item.path.clone(), index))
+        .collect();
+    let mut children: HashMap<usize, Vec<(String, usize)>> = HashMap::new();
+    for (index, item) in items.iter().enumerate() {
+        let parent = item.path.parent().and_then(|parent| dirs.get(&parent));
+        if let (Some(parent), Some(name)) = (parent, item.path.basename()) {
+            children
+                .entry(*parent)
+                .or_default()
+                .push((name.to_owned(), index));
+        }
+    }
+    // Deepest first, so that subdirectories are digested before their parent
+    let mut order: Vec<usize> = dirs.into_values().collect();
+    order.sort_by_key(|index| std::cmp::Reverse(items[*index].path.canonical_components().len()));
+    for dir in order {
+        let mut entries: Vec<(String, String)> = children
+            .remove(&dir)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, index)| (name, items[index].stats.sha256.clone().unwrap_or_default()))
+            .collect();
+        entries.sort();
+        let mut context = Sha256::new();
+        for (name, digest) in entries {
+            context.update(name.as_bytes());
+            context.update(b"\0");
+            context.update(digest.as_bytes());
+            context.update(b"\n");
+        }
+        let digest = format!("{DIR_DIGEST_TAG}{:x}", context.finalize());
+        // A sha256 hex digest renders in any encoding
+        items[dir].stats.sha256 = Some(encoding.encode(&digest).unwrap_or(digest));
     }
 }
 
@@ -41,6 +227,9 @@ impl PortableFs {
     /// Read the contents of the given directory path and returns its
     /// entries.
     ///
+    /// Entries that can't be read, e.g. for lack of permissions, are listed in
+    /// `Directory::errors` instead of failing the whole listing.
+    ///
     /// # Arguments
     /// * `path` - The path to the directory to browse.
     ///
@@ -48,33 +237,104 @@ impl PortableFs {
     /// * `Result<Directory, Error>` - The directory entries or an error
     ///   message.
     pub async fn read_dir(&self, path: &Path) -> Result<Directory, Error> {
-        let full_path = self.as_abs_path(path);
-        let mut items = Vec::new();
-        for item in DirWalker::walk_dir(
+        self.read_dir_with(path, true).await
+    }
+
+    /// Like `read_dir`, but digests files only if `include_digests` is set.
+    /// Otherwise `FileStat::sha256` is `None` and the listing costs one
+    /// metadata lookup per entry, e.g. for clients that only display names.
+    /// Digests, when included, are served from the cache when available.
+    pub async fn read_dir_with(
+        &self,
+        path: &Path,
+        include_digests: bool,
+    ) -> Result<Directory, Error> {
+        use crate::DirectoryEntry;
+        use crate::EntryError;
+
+        let start = Instant::now();
+        let full_path = self.confined_path(path)?;
+        let (found, failed) = DirWalker::walk_dir_lenient(
             full_path,
             self.base_dir.clone(),
             self.layer.clone(),
             20,
             Some(0),
+            include_digests,
         )
-        .await?
-        {
-            use crate::DirectoryEntry;
-
+        .await?;
+        let mut items = Vec::new();
+        for item in found {
             items.push(DirectoryEntry::try_from(&item)?);
         }
+        let errors = failed
+            .into_iter()
+            .map(|(path, error)| EntryError {
+                name: path.basename().unwrap_or_default().to_owned(),
+                error: error.to_string(),
+            })
+            .collect();
 
-        // Sort: directories first, then files, both alphabetically
-        items.sort_by(|a, b| match (a.stats.is_directory, b.stats.is_directory) {
-            (true, false) => std::cmp::Ordering::Less,
-            (false, true) => std::cmp::Ordering::Greater,
-            _ => a.name.cmp(&b.name),
-        });
-
-        Ok(Directory {
+        let mut dir = Directory {
+            schema_version: Some(SCHEMA_VERSION),
             current_path: path.clone(),
+            parent: path.parent(),
             items,
-        })
+            next_cursor: None,
+            errors,
+        };
+        dir.sort_by(SortKey::DirectoriesFirst);
+        if let Some(observer) = self.layer.observer() {
+            observer.on_read_dir(path, start.elapsed(), dir.items.len());
+        }
+        Ok(dir)
+    }
+
+    /// Returns a page of up to `limit` entries of directory `path` whose names
+    /// sort after `after_name`, sorted by name. Pass the returned
+    /// `Directory::next_cursor` as `after_name` to fetch the next page.
+    ///
+    /// Unlike numeric offsets, cursors don't skip or repeat entries when the
+    /// directory is modified between pages.
+    ///
+    /// Returns `Error::InvalidArgument` if `limit` is zero.
+    pub async fn read_dir_after(
+        &self,
+        path: &Path,
+        after_name: Option<&str>,
+        limit: usize,
+    ) -> Result<Directory, Error> {
+        if limit == 0 {
+            return Err(Error::InvalidArgument("limit must be non zero".into()));
+        }
+        let mut dir = self.read_dir(path).await?;
+        dir.items.sort_by(|a, b| a.name.cmp(&b.name));
+        if let Some(after_name) = after_name {
+            dir.retain(|entry| entry.name.as_str() > after_name);
+        }
+        if dir.items.len() > limit {
+            dir.items.truncate(limit);
+            dir.next_cursor = dir.items.last().map(|entry| entry.name.clone());
+        }
+        Ok(dir)
+    }
+
+    /// Like `read_dir` but returns only the entries whose names match
+    /// `name_glob`, e.g. `*.txt` or `*.{md,rs}`, in the syntax of
+    /// `globset::Glob`.
+    ///
+    /// Returns `Error::InvalidArgument` if `name_glob` is malformed.
+    pub async fn read_dir_matching(
+        &self,
+        path: &Path,
+        name_glob: &str,
+    ) -> Result<Directory, Error> {
+        let glob = globset::Glob::new(name_glob)
+            .map_err(|e| Error::InvalidArgument(format!("glob {name_glob}: {e}")))?
+            .compile_matcher();
+        let mut dir = self.read_dir(path).await?;
+        dir.retain(|entry| glob.is_match(&entry.name));
+        Ok(dir)
     }
 
     /// Recursively walks directory `path` and returns files and their metadata
@@ -87,14 +347,334 @@ impl PortableFs {
     /// * `Result<Vec<FileInfo>, Error>` - The directory entries or an error
     ///   message.
     pub async fn read_dir_recurse(&self, path: &Path) -> Result<Vec<FileInfo>, Error> {
-        DirWalker::walk_dir(
-            self.as_abs_path(path),
+        let start = Instant::now();
+        let items = DirWalker::walk_dir(
+            self.confined_path(path)?,
             self.base_dir.clone(),
             self.layer.clone(),
             20,
             None,
         )
-        .await
+        .await?;
+        if let Some(observer) = self.layer.observer() {
+            observer.on_read_dir(path, start.elapsed(), items.len());
+        }
+        Ok(self.with_dir_summaries(items, true))
+    }
+
+    /// Like `read_dir_recurse` but walks up to `concurrency` subdirectories
+    /// concurrently. This helps on wide trees on fast storage. The returned
+    /// entries are the same as `read_dir_recurse`'s, possibly in a different
+    /// order.
+    pub async fn read_dir_recurse_parallel(
+        &self,
+        path: &Path,
+        concurrency: NonZeroUsize,
+    ) -> Result<Vec<FileInfo>, Error> {
+        let items = DirWalker::walk_dir_parallel(
+            self.confined_path(path)?,
+            self.base_dir.clone(),
+            self.layer.clone(),
+            20,
+            None,
+            concurrency,
+        )
+        .await?;
+        Ok(self.with_dir_summaries(items, true))
+    }
+
+    /// Recursively walks directory `path` like `read_dir_recurse`, but filters
+    /// entries with `filter` instead of the installed filter. The installed
+    /// filter is left untouched, so concurrent callers sharing this fs are
+    /// not affected.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the directory to browse.
+    /// * `filter` - The filter to apply for this call only.
+    /// * `max_depth` - How deep to descend; `Some(0)` lists only the entries
+    ///   of `path` and `None` walks the whole tree.
+    /// * `max_entries` - Stop after this many entries, setting
+    ///   `RecursiveDirList::truncated` if more were found. `None` returns all
+    ///   entries.
+    /// * `include_digests` - Whether to digest files. When unset,
+    ///   `FileStat::sha256` is `None`, including for directories digested
+    ///   with `set_compute_dir_digests`.
+    pub async fn read_dir_recursive_with(
+        &self,
+        path: &Path,
+        filter: &FilterSet,
+        max_depth: Option<usize>,
+        max_entries: Option<usize>,
+        include_digests: bool,
+    ) -> Result<RecursiveDirList, Error> {
+        let layer = FsLayer {
+            filter_set: Arc::new(RwLock::new(filter.clone())),
+            ..self.layer.as_ref().clone()
+        };
+        let (deltas, truncated) = DirWalker::walk_dir_capped(
+            self.confined_path(path)?,
+            self.base_dir.clone(),
+            Arc::new(layer),
+            20,
+            max_depth,
+            max_entries,
+            include_digests,
+        )
+        .await?;
+        Ok(RecursiveDirList {
+            schema_version: Some(SCHEMA_VERSION),
+            base_dir: path.clone(),
+            deltas: self.with_dir_summaries(deltas, include_digests),
+            digests: vec![],
+            truncated,
+        })
+    }
+
+    /// Recursively walks directory `path` like `read_dir_recurse`, but never
+    /// fails as a whole. Entries that can't be read and directories that
+    /// can't be listed are returned along with their errors, next to the
+    /// entries that could be read.
+    ///
+    /// If `path` itself can't be walked, the list is empty and the only error
+    /// is that of `path`.
+    pub async fn read_dir_recursive_lenient(
+        &self,
+        path: &Path,
+    ) -> (RecursiveDirList, Vec<(Path, Error)>) {
+        let walked = match self.confined_path(path) {
+            Ok(full_path) => {
+                DirWalker::walk_dir_lenient(
+                    full_path,
+                    self.base_dir.clone(),
+                    self.layer.clone(),
+                    20,
+                    None,
+                    true,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        let (deltas, errors) = match walked {
+            Ok((deltas, errors)) => (self.with_dir_summaries(deltas, true), errors),
+            Err(e) => (vec![], vec![(path.clone(), e)]),
+        };
+        let list = RecursiveDirList {
+            schema_version: Some(SCHEMA_VERSION),
+            base_dir: path.clone(),
+            deltas,
+            digests: vec![],
+            truncated: false,
+        };
+        (list, errors)
+    }
+
+    /// Recursively walks directory `path` like `read_dir_recurse`, but
+    /// returns only the entries modified after `since`, along with their
+    /// ancestor directories, e.g. to poll for changes. Directories are
+    /// traversed regardless of their own mtime.
+    ///
+    /// Only the returned files are digested. Directory sizes and digests are
+    /// not computed, as most of their children are left out.
+    pub async fn read_dir_recursive_since(
+        &self,
+        path: &Path,
+        since: SystemTime,
+    ) -> Result<RecursiveDirList, Error> {
+        let items = DirWalker::walk_dir_without_digests(
+            self.confined_path(path)?,
+            self.base_dir.clone(),
+            self.layer.clone(),
+            20,
+            None,
+        )
+        .await?;
+        let mut changed = HashSet::new();
+        for item in &items {
+            if parse_system_time(&item.stats.mtime)? <= since {
+                continue;
+            }
+            let mut ancestor = Some(item.path.clone());
+            while let Some(path) = ancestor {
+                // Ancestors of a path already kept are kept too
+                if !changed.insert(path.clone()) {
+                    break;
+                }
+                ancestor = path.parent();
+            }
+        }
+        let report_only = *self.layer.symlink_policy.read().unwrap() == SymlinkPolicy::ReportOnly;
+        let mut deltas = Vec::with_capacity(changed.len());
+        for mut item in items {
+            if !changed.contains(&item.path) {
+                continue;
+            }
+            if !(item.stats.is_directory || (item.stats.is_symlink && report_only)) {
+                let full_path = self.confined_path(&item.path)?;
+                item.stats = FileStat {
+                    is_symlink: item.stats.is_symlink,
+                    ..lookup_or_load(self.layer.clone(), &full_path, &item.path).await?
+                };
+            }
+            deltas.push(item);
+        }
+        Ok(RecursiveDirList {
+            schema_version: Some(SCHEMA_VERSION),
+            base_dir: path.clone(),
+            deltas,
+            digests: vec![],
+            truncated: false,
+        })
+    }
+
+    /// Returns true if directory `path` has no entries that pass the
+    /// installed filter.
+    pub async fn is_dir_empty(&self, path: &Path) -> Result<bool, Error> {
+        let (items, truncated) = DirWalker::walk_dir_capped(
+            self.confined_path(path)?,
+            self.base_dir.clone(),
+            self.layer.clone(),
+            1,
+            Some(0),
+            Some(0),
+            false,
+        )
+        .await?;
+        Ok(items.is_empty() && !truncated)
+    }
+
+    /// Recursively walks directory `path` only to populate the cache, e.g. in
+    /// the background at startup so that the first requests are fast.
+    ///
+    /// # Returns
+    /// * `Result<usize, Error>` - The number of entries walked or an error
+    ///   message.
+    pub async fn warm(&self, path: &Path) -> Result<usize, Error> {
+        let items = DirWalker::walk_dir(
+            self.confined_path(path)?,
+            self.base_dir.clone(),
+            self.layer.clone(),
+            20,
+            None,
+        )
+        .await?;
+        Ok(items.len())
+    }
+
+    /// Caches the stats of the entries of directory `path`, without
+    /// recursing, so that `stat` calls on them that follow are cache hits.
+    /// `read_dir` caches them too, this only skips building the listing.
+    ///
+    /// # Returns
+    /// * `Result<usize, Error>` - The number of entries cached or an error
+    ///   message.
+    pub async fn prefetch_dir(&self, path: &Path) -> Result<usize, Error> {
+        let items = DirWalker::walk_dir(
+            self.confined_path(path)?,
+            self.base_dir.clone(),
+            self.layer.clone(),
+            20,
+            Some(0),
+        )
+        .await?;
+        Ok(items.len())
+    }
+
+    /// Returns the space used by the entries under directory `path` that pass
+    /// the installed filter. Files are not hashed, so this stays cheap on
+    /// large trees.
+    pub async fn disk_usage(&self, path: &Path) -> Result<DiskUsage, Error> {
+        let items = DirWalker::walk_dir_without_digests(
+            self.confined_path(path)?,
+            self.base_dir.clone(),
+            self.layer.clone(),
+            20,
+            None,
+        )
+        .await?;
+        Ok(items.iter().collect())
+    }
+
+    fn with_dir_summaries(&self, mut items: Vec<FileInfo>, include_digests: bool) -> Vec<FileInfo> {
+        if self.layer.compute_dir_sizes.load(Ordering::Relaxed) {
+            backfill_dir_sizes(&mut items);
+        }
+        if include_digests && self.layer.compute_dir_digests.load(Ordering::Relaxed) {
+            let encoding = self.layer.stat_options.read().unwrap().digest_encoding;
+            backfill_dir_digests(&mut items, encoding);
+        }
+        items
+    }
+
+    /// Recursively walks directory `path` and exports a manifest of its files,
+    /// mapping each file's path to its sha256 and size. Directories are
+    /// omitted.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the directory to export.
+    ///
+    /// # Returns
+    /// * `Result<Manifest, Error>` - The manifest or an error message.
+    pub async fn manifest(&self, path: &Path) -> Result<Manifest, Error> {
+        let entries = self
+            .read_dir_recurse(path)
+            .await?
+            .into_iter()
+            .filter(|item| !item.stats.is_directory)
+            .map(|item| {
+                (
+                    item.path,
+                    item.stats.sha256.unwrap_or_default(),
+                    item.stats.size,
+                )
+            })
+            .collect();
+        Ok(Manifest {
+            base: path.clone(),
+            entries,
+        })
+    }
+
+    /// Recursively walks directory `path` and returns a single sha256 over its
+    /// filtered tree, e.g. to cheaply check whether two trees are identical.
+    ///
+    /// Entries are fed in sorted order as their path relative to `path`, with
+    /// `/` separators, followed by their digest. Directories contribute an
+    /// empty digest so that empty directories are accounted for. Sizes and
+    /// mtimes do not contribute.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the directory to digest.
+    ///
+    /// # Returns
+    /// * `Result<String, Error>` - The hex digest or an error message.
+    pub async fn tree_digest(&self, path: &Path) -> Result<String, Error> {
+        let prefix_len = path.canonical_components().len();
+        let mut entries: Vec<(Vec<String>, String)> = self
+            .read_dir_recurse(path)
+            .await?
+            .into_iter()
+            .map(|item| {
+                let digest = if item.stats.is_directory {
+                    String::new()
+                } else {
+                    item.stats.sha256.unwrap_or_default()
+                };
+                (
+                    item.path.canonical_components()[prefix_len..].to_vec(),
+                    digest,
+                )
+            })
+            .collect();
+        entries.sort();
+        let mut context = Sha256::new();
+        for (components, digest) in entries {
+            context.update(components.join("/").as_bytes());
+            context.update(b"\0");
+            context.update(digest.as_bytes());
+            context.update(b"\n");
+        }
+        context.sha256_string().await
     }
 
     /// Exchanges file deltas by sending FileInfo objects for the given
@@ -108,13 +688,20 @@ impl PortableFs {
     pub async fn exchange_deltas(
         &self,
         tx: Sender<Vec<FileInfo>>,
-        delta: RecursiveDirList,
+        mut delta: RecursiveDirList,
         chunk_size: usize,
     ) {
-        use std::collections::HashMap;
-        use std::path::PathBuf;
-
-        let full_path = self.as_abs_path(&delta.base_dir);
+        if let Err(e) = delta.expand() {
+            error!("exchange_deltas error: {}", e);
+            return;
+        }
+        let full_path = match self.confined_path(&delta.base_dir) {
+            Ok(full_path) => full_path,
+            Err(e) => {
+                error!("exchange_deltas error: {}", e);
+                return;
+            }
+        };
         let strip_prefix = if let Some(parent) = delta.base_dir.parent() {
             self.as_abs_path(&parent)
         } else {
@@ -144,8 +731,111 @@ impl PortableFs {
         }
     }
 
+    /// Recursively walks directory `path` and sends its entries over `tx` in
+    /// chunks of up to `chunk_size` entries, numbered from 0. The final chunk
+    /// is flagged `last`, so that the receiver can detect dropped chunks and
+    /// rebuild the list with `RecursiveDirList::reassemble`.
+    ///
+    /// No chunk is flagged `last` if the walk fails.
+    pub async fn read_dir_chunked(
+        &self,
+        path: &Path,
+        chunk_size: usize,
+        tx: Sender<DirListChunk>,
+    ) -> Result<(), Error> {
+        let full_path = self.confined_path(path)?;
+        let (walk_tx, mut rx) =
+            tokio::sync::mpsc::channel(self.layer.walk_channel_capacity.load(Ordering::Relaxed));
+        let dir_walker = DirWalker::create(
+            self.base_dir.clone(),
+            self.layer.clone(),
+            chunk_size,
+            None,
+            walk_tx,
+            HashMap::new(),
+        );
+        let walk = tokio::spawn(async move { dir_walker.walk_dir_stream(&full_path).await });
+        let send = |seq: u64, deltas: Vec<FileInfo>, last: bool| {
+            let tx = tx.clone();
+            let chunk = DirListChunk {
+                seq,
+                base_dir: path.clone(),
+                deltas,
+                last,
+            };
+            async move {
+                tx.send(chunk).await.map_err(|e| Error::Sync {
+                    what: "failed to tx".to_owned(),
+                    how: e.to_string(),
+                })
+            }
+        };
+
+        // Hold back a chunk until the next one shows whether it is the last
+        let mut seq = 0;
+        let mut pending = None;
+        while let Some(deltas) = rx.recv().await {
+            if let Some(previous) = pending.replace(deltas) {
+                send(seq, previous, false).await?;
+                seq += 1;
+            }
+        }
+        walk.await.map_err(|e| Error::Read {
+            what: "failed to join walk_dir thread".to_owned(),
+            how: e.to_string(),
+        })??;
+        send(seq, pending.unwrap_or_default(), true).await
+    }
+
+    /// Recursively walks directory `path` and writes each entry to `writer`
+    /// as a line of JSON as soon as the walk produces it, e.g. to pipe
+    /// listings into line oriented tools like `jq`.
+    ///
+    /// Entries are the same as `read_dir_recurse`'s, possibly in a different
+    /// order, but directories are not summarized as configured with
+    /// `set_compute_dir_sizes` and `set_compute_dir_digests`.
+    pub async fn read_dir_ndjson<W: AsyncWrite + Unpin>(
+        &self,
+        path: &Path,
+        mut writer: W,
+    ) -> Result<(), Error> {
+        fn write_error<E: ToString>(e: E) -> Error {
+            Error::Write {
+                what: "ndjson".into(),
+                how: e.to_string(),
+            }
+        }
+
+        let full_path = self.confined_path(path)?;
+        let (walk_tx, mut rx) =
+            tokio::sync::mpsc::channel(self.layer.walk_channel_capacity.load(Ordering::Relaxed));
+        let dir_walker = DirWalker::create(
+            self.base_dir.clone(),
+            self.layer.clone(),
+            20,
+            None,
+            walk_tx,
+            HashMap::new(),
+        );
+        let walk = tokio::spawn(async move { dir_walker.walk_dir_stream(&full_path).await });
+        let mut buf = Vec::new();
+        while let Some(deltas) = rx.recv().await {
+            buf.clear();
+            for item in &deltas {
+                serde_json::to_writer(&mut buf, item).map_err(write_error)?;
+                buf.push(b'\n');
+            }
+            writer.write_all(&buf).await.map_err(write_error)?;
+        }
+        walk.await.map_err(|e| Error::Read {
+            what: "failed to join walk_dir thread".to_owned(),
+            how: e.to_string(),
+        })??;
+        writer.flush().await.map_err(write_error)
+    }
+
     async fn create_all(&self, path: &Path) -> Result<(), String> {
-        let full_path = self.as_abs_path(path);
+        let full_path = self.confined_path(path).map_err(|e| e.to_string())?;
         tokio::fs::create_dir_all(&full_path).await.map_err(|e| {
             error!("Failed to create directory {} {}", e, full_path.display());
             format!("Failed to create directories: {e}")
@@ -154,7 +844,8 @@ impl PortableFs {
     }
 
     /// Writes data to a file at the specified path, optionally overwriting if
-    /// the file exists.
+    /// the file exists. The file is replaced atomically, see
+    /// `PortableFs::set_fsync` for durability.
     ///
     /// # Arguments
     /// * `path` - The path to the file to write.
@@ -171,9 +862,7 @@ impl PortableFs {
         overwrite: bool,
         stats: &FileStat,
     ) -> Result<(), Error> {
-        use crate::utils::parse_system_time;
-
-        let full_path = self.as_abs_path(path);
+        let full_path = self.confined_path(path)?;
         if full_path.exists() && !overwrite {
             return Err(Error::FileExists(full_path.to_string_lossy().to_string()));
         }
@@ -184,39 +873,134 @@ impl PortableFs {
                 how: e,
             })?;
         }
-        tokio::fs::write(&full_path, data)
-            .await
-            .map_err(|e| Error::Write {
-                what: full_path.to_str().unwrap().into(),
-                how: e.to_string(),
-            })?;
+        self.write_atomic(path, data, stats).await
+    }
+
+    /// Persists `nodes` under `base`, e.g. a batch received from a peer.
+    /// `paths[i]` is the path of `nodes[i]` relative to `base`.
+    ///
+    /// Directories are created and files are written atomically, replacing
+    /// existing ones, after verifying their digest against their contents.
+    /// Entries that are filtered out and files whose digest doesn't match are
+    /// skipped.
+    ///
+    /// # Returns
+    /// * `Result<usize, Error>` - The number of nodes written or an error
+    ///   message.
+    pub async fn apply_nodes(
+        &self,
+        base: &Path,
+        nodes: &[FileNode],
+        paths: &[Path],
+    ) -> Result<usize, Error> {
+        if nodes.len() != paths.len() {
+            return Err(Error::InvalidArgument(format!(
+                "{} nodes but {} paths",
+                nodes.len(),
+                paths.len()
+            )));
+        }
+        let mut written = 0;
+        for (node, path) in nodes.iter().zip(paths) {
+            let path = base.join(path);
+            let filter_level = self
+                .layer
+                .filter_set
+                .read()
+                .unwrap()
+                .matches(self.as_relative_path(&path), node.stats.is_directory)?;
+            if filter_level == FilterLevel::Deny {
+                debug!("apply_nodes skipping filtered out {}", path);
+                continue;
+            }
+            if node.stats.is_directory {
+                self.create_all(&path).await.map_err(|e| Error::Create {
+                    what: path.to_string(),
+                    how: e,
+                })?;
+                written += 1;
+                continue;
+            }
+            let verified = match &node.stats.sha256 {
+                Some(digest) => {
+                    let encoding = self.layer.stat_options.read().unwrap().digest_encoding;
+                    // Malformed digests are mismatches
+                    crate::hash::verify_encoded(&node.contents, digest, encoding)
+                        .await
+                        .unwrap_or(false)
+                }
+                None => false,
+            };
+            if !verified {
+                warn!("apply_nodes skipping {} with mismatched digest", path);
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                self.create_all(&parent).await.map_err(|e| Error::Create {
+                    what: parent.to_string(),
+                    how: e,
+                })?;
+            }
+            self.write_atomic(&path, &node.contents, &node.stats)
+                .await?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// Writes `data` to a temporary file next to `path` and renames it over
+    /// `path`, so readers never observe a partially written file.
+    ///
+    /// With fsync enabled, the file is flushed to disk before the rename and
+    /// the parent directory after it, so that the write survives a crash.
+    async fn write_atomic(&self, path: &Path, data: &[u8], stats: &FileStat) -> Result<(), Error> {
+        let full_path = self.confined_path(path)?;
+        let mut tmp_name = full_path.file_name().unwrap_or_default().to_owned();
+        tmp_name.push(".pfs-tmp");
+        let tmp_path = full_path.with_file_name(tmp_name);
+        let write_error = |e: std::io::Error| Error::Write {
+            what: full_path.to_str().unwrap().into(),
+            how: e.to_string(),
+        };
         let mtime = parse_system_time(&stats.mtime)?;
-        let full_path_clone = full_path.clone();
-        // Update mtime of the file if stats provided
+        let data = data.to_vec();
+        let fsync = self.layer.fsync.load(Ordering::Relaxed);
+        let (tmp, dest) = (tmp_path.clone(), full_path.clone());
         let ret = tokio::task::spawn_blocking(move || -> Result<(), std::io::Error> {
-            let file = std::fs::File::options()
-                .append(true)
-                .open(&full_path_clone)?;
-            file.set_modified(mtime)
+            use std::io::Write;
+
+            let mut file = std::fs::File::create(&tmp)?;
+            file.write_all(&data)?;
+            file.set_modified(mtime)?;
+            if fsync {
+                file.sync_all()?;
+            }
+            std::fs::rename(&tmp, &dest)?;
+            // Directories can't be opened for syncing on windows
+            #[cfg(unix)]
+            if fsync && let Some(parent) = dest.parent() {
+                std::fs::File::open(parent)?.sync_all()?;
+            }
+            Ok(())
         })
         .await
         .map_err(|e| Error::Write {
             what: full_path.to_str().unwrap().into(),
             how: e.to_string(),
         })?
-        .map_err(|e| Error::Write {
-            what: full_path.to_str().unwrap().into(),
-            how: e.to_string(),
-        });
-        if ret.is_ok() {
-            self.get_cache().put(path.clone(), stats.clone());
+        .map_err(write_error);
+        if ret.is_err() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        } else {
+            self.get_cache()
+                .put(self.layer.cache_key(path), stats.clone());
         }
         ret
     }
 
     /// Deletes the file at the specified path.
     pub async fn delete_file(&self, path: &Path) -> Result<(), Error> {
-        let full_path = self.as_abs_path(path);
+        let full_path = self.confined_path(path)?;
         if !full_path.exists() {
             return Err(Error::InvalidArgument("File does not exist".to_string()));
         }
@@ -230,203 +1014,1953 @@ impl PortableFs {
                 how: e.to_string(),
             });
         if ret.is_ok() {
-            self.get_cache().pop(path);
+            self.get_cache().pop(&self.layer.cache_key(path));
+        }
+        ret
+    }
+
+    /// Creates the file at `path` if it does not exist and sets its mtime to
+    /// now, returning its new stats.
+    ///
+    /// Returns `Error::InvalidArgument` if `path` is a directory or is
+    /// filtered out.
+    pub async fn touch(&self, path: &Path) -> Result<FileStat, Error> {
+        let full_path = self.confined_path(path)?;
+        if full_path.is_dir() {
+            return Err(Error::InvalidArgument("Path is a directory".to_string()));
+        }
+        let filter_level = self
+            .layer
+            .filter_set
+            .read()
+            .unwrap()
+            .matches(self.as_relative_path(path), false)?;
+        if filter_level == FilterLevel::Deny {
+            return Err(Error::InvalidArgument("Path is filtered out".to_string()));
+        }
+        if let Some(parent) = path.parent() {
+            self.create_all(&parent).await.map_err(|e| Error::Create {
+                what: parent.to_string(),
+                how: e,
+            })?;
+        }
+        let full_path_clone = full_path.clone();
+        tokio::task::spawn_blocking(move || -> Result<(), std::io::Error> {
+            std::fs::File::options()
+                .create(true)
+                .append(true)
+                .open(&full_path_clone)?;
+            filetime::set_file_mtime(&full_path_clone, filetime::FileTime::now())
+        })
+        .await
+        .map_err(|e| Error::Write {
+            what: full_path.to_str().unwrap().into(),
+            how: e.to_string(),
+        })?
+        .map_err(|e| Error::Write {
+            what: full_path.to_str().unwrap().into(),
+            how: e.to_string(),
+        })?;
+        self.get_cache().pop(&self.layer.cache_key(path));
+        lookup_or_load(self.layer.clone(), &full_path, path).await
+    }
+
+    /// Returns the absolute path of `path`. For file systems created with
+    /// `PortableFs::new_checked`, also verifies that the path doesn't resolve
+    /// outside the base directory through symlinks, see `resolve_safe`.
+    pub(crate) fn confined_path(&self, path: &Path) -> Result<PathBuf, Error> {
+        if self.confined {
+            self.resolve_safe(path)
+        } else {
+            self.lexically_confined_path(path)
+        }
+    }
+
+    /// Returns the absolute path of `path` after verifying that it is under
+    /// the base directory, both lexically and once symlinks are resolved.
+    ///
+    /// The deepest existing ancestor is resolved, as `path` may not exist yet.
+    /// Dangling symlinks are rejected as their target can't be checked.
+    pub(crate) fn resolve_safe(&self, path: &Path) -> Result<PathBuf, Error> {
+        let full_path = self.lexically_confined_path(path)?;
+        let escapes = || Error::InvalidPath {
+            what: format!("{path} resolves outside of the base directory"),
+        };
+        let base_dir = if self.confined {
+            self.base_dir.clone()
+        } else {
+            self.base_dir
+                .canonicalize()
+                .map_err(|e| Error::InvalidPath {
+                    what: format!("{}: {e}", self.base_dir.display()),
+                })?
+        };
+        let mut existing = full_path.as_path();
+        let resolved = loop {
+            match existing.canonicalize() {
+                Ok(resolved) => break resolved,
+                Err(_) if existing.symlink_metadata().is_ok() => return Err(escapes()),
+                Err(_) => existing = existing.parent().ok_or_else(escapes)?,
+            }
+        };
+        if !resolved.starts_with(&base_dir) {
+            return Err(escapes());
+        }
+        Ok(full_path)
+    }
+
+    // Returns the absolute path of `path` after verifying that it only
+    // descends from the base directory, which `Path`'s invariants guarantee
+    // unless it was built bypassing them.
+    fn lexically_confined_path(&self, path: &Path) -> Result<PathBuf, Error> {
+        let full_path = self.as_abs_path(path);
+        let descends = full_path
+            .strip_prefix(&self.base_dir)
+            .is_ok_and(|relative| {
+                relative
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            });
+        if !descends {
+            return Err(Error::InvalidPath {
+                what: format!("{path} is not under the base directory"),
+            });
+        }
+        Ok(full_path)
+    }
+
+    /// Returns the absolute path of `path` after verifying that it is an
+    /// existing file that is not filtered out.
+    fn readable_file_path(&self, path: &Path) -> Result<PathBuf, Error> {
+        let full_path = self.confined_path(path)?;
+        if !full_path.exists() {
+            return Err(Error::InvalidArgument("File does not exist".to_string()));
+        }
+        if full_path.is_dir() {
+            return Err(Error::InvalidArgument("Path is a directory".to_string()));
+        }
+        let filter_level = self
+            .layer
+            .filter_set
+            .read()
+            .unwrap()
+            .matches(self.as_relative_path(path), false)?;
+        if filter_level == FilterLevel::Deny {
+            return Err(Error::InvalidArgument("Path is filtered out".to_string()));
+        }
+        Ok(full_path)
+    }
+
+    /// Returns the stats of the file or directory at `path`, from the cache
+    /// if they were loaded before, e.g. by listing its parent.
+    ///
+    /// Returns `Error::InvalidArgument` if the path does not exist or is
+    /// filtered out.
+    pub async fn stat(&self, path: &Path) -> Result<FileStat, Error> {
+        let full_path = self.confined_path(path)?;
+        if !full_path.exists() {
+            return Err(Error::InvalidArgument("File does not exist".to_string()));
+        }
+        let filter_level = self
+            .layer
+            .filter_set
+            .read()
+            .unwrap()
+            .matches(self.as_relative_path(path), full_path.is_dir())?;
+        if filter_level == FilterLevel::Deny {
+            return Err(Error::InvalidArgument("Path is filtered out".to_string()));
+        }
+        lookup_or_load(self.layer.clone(), &full_path, path).await
+    }
+
+    /// Reads the contents of the file at the specified path along with its
+    /// stats.
+    ///
+    /// Cached stats are reused if the file's size and mtime still match them.
+    pub async fn read_file(&self, path: &Path) -> Result<FileNode, Error> {
+        let full_path = self.readable_file_path(path)?;
+        let metadata = tokio::fs::metadata(&full_path)
+            .await
+            .map_err(|e| Error::Read {
+                what: full_path.to_str().unwrap().into(),
+                how: e.to_string(),
+            })?;
+        let precision = self.layer.stat_options.read().unwrap().mtime_precision;
+        let on_disk = FileStat::from_metadata_with(&metadata, None, precision);
+        let cached = self
+            .get_cache()
+            .get(&self.layer.cache_key(path))
+            .filter(|s| s.size == on_disk.size && s.mtime == on_disk.mtime)
+            .cloned();
+        let stats = match cached {
+            Some(stats) => stats,
+            None => {
+                self.get_cache().pop(&self.layer.cache_key(path));
+                lookup_or_load(self.layer.clone(), &full_path, path).await?
+            }
+        };
+        let contents = tokio::fs::read(&full_path).await.map_err(|e| Error::Read {
+            what: full_path.to_str().unwrap().into(),
+            how: e.to_string(),
+        })?;
+        if contents.len() as u64 != stats.size {
+            self.get_cache().pop(&self.layer.cache_key(path));
+            return Err(Error::Read {
+                what: full_path.to_str().unwrap().into(),
+                how: "file changed while reading".into(),
+            });
+        }
+        Ok(FileNode::new(stats, contents))
+    }
+
+    /// Reads `len` bytes starting at `offset` from the file at the specified
+    /// path.
+    ///
+    /// Returns `Error::InvalidArgument` if the range extends past the end of
+    /// the file.
+    pub async fn read_file_range(
+        &self,
+        path: &Path,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, Error> {
+        use tokio::io::AsyncReadExt;
+        use tokio::io::AsyncSeekExt;
+
+        let full_path = self.readable_file_path(path)?;
+        let read_error = |e: std::io::Error| Error::Read {
+            what: full_path.to_str().unwrap().into(),
+            how: e.to_string(),
+        };
+        let mut file = tokio::fs::File::open(&full_path)
+            .await
+            .map_err(read_error)?;
+        let size = file.metadata().await.map_err(read_error)?.len();
+        match offset.checked_add(len) {
+            Some(end) if end <= size => {}
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "Range {offset}+{len} is out of bounds for file of size {size}"
+                )));
+            }
+        }
+        file.seek(std::io::SeekFrom::Start(offset))
+            .await
+            .map_err(read_error)?;
+        let mut buf = vec![0; len as usize];
+        file.read_exact(&mut buf).await.map_err(read_error)?;
+        Ok(buf)
+    }
+
+    pub(crate) fn get_cache(&'_ self) -> MutexGuard<'_, Box<dyn Cache>> {
+        self.layer.cache.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    use tokio::sync::mpsc;
+    use tokio::task::JoinSet;
+
+    use super::*;
+    use crate::FileInfo;
+    use crate::FileStat;
+    use crate::RecursiveDirList;
+    use crate::TestRoot;
+    use crate::cache::CacheStats;
+    use crate::filter::FilterSet;
+    use crate::hash::Sha256Builder;
+    use crate::hash::Sha256String;
+    use crate::utils::format_system_time;
+    use crate::utils::parse_system_time;
+
+    fn temp_files(root: &TestRoot) -> Vec<String> {
+        root.files
+            .keys()
+            .map(|e| e.as_os_str().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_recurse_path() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+
+        let r = fs
+            .read_dir_recurse(&Path::try_from(&StdPath::new("").to_owned()).unwrap())
+            .await
+            .unwrap();
+
+        println!("r: {r:#?}");
+        root.are_synced(&fs, &r).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recurse_path_parallel() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+
+        let r = fs
+            .read_dir_recurse_parallel(&Path::empty(), NonZeroUsize::new(4).unwrap())
+            .await
+            .unwrap();
+        root.are_synced(&fs, &r).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compute_dir_digests() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        for dir in ["a", "b"] {
+            let dir = root.root.path().join(dir).join("x");
+            tokio::fs::create_dir_all(&dir).await.unwrap();
+            tokio::fs::write(dir.join("f.txt"), "same").await.unwrap();
+        }
+        let mut fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        fs.set_compute_dir_digests(true);
+        let digests = |items: Vec<FileInfo>| -> HashMap<String, String> {
+            items
+                .into_iter()
+                .filter(|i| i.stats.is_directory)
+                .map(|i| (i.path.display_with('/'), i.stats.sha256.unwrap()))
+                .collect()
+        };
+
+        let before = digests(fs.read_dir_recurse(&Path::empty()).await.unwrap());
+        assert!(before.values().all(|d| d.starts_with(DIR_DIGEST_TAG)));
+        assert_eq!(before["a"], before["b"]);
+        assert_eq!(before["a/x"], before["b/x"]);
+        assert_ne!(before["a"], before["a/x"]);
+        assert_ne!(before["dir1"], before["dir3"]);
+        let parallel = fs
+            .read_dir_recurse_parallel(&Path::empty(), NonZeroUsize::new(4).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(digests(parallel), before);
+
+        tokio::fs::write(root.root.path().join("a/x/f.txt"), "changed")
+            .await
+            .unwrap();
+        let after = digests(fs.read_dir_recurse(&Path::empty()).await.unwrap());
+        assert_ne!(after["a/x"], before["a/x"]);
+        assert_ne!(after["a"], before["a"]);
+        assert_eq!(after["b"], before["b"]);
+        assert_eq!(after["dir1"], before["dir1"]);
+
+        fs.set_compute_dir_digests(false);
+        let items = fs.read_dir_recurse(&Path::empty()).await.unwrap();
+        let a = items
+            .iter()
+            .find(|i| i.path.display_with('/') == "a")
+            .unwrap();
+        assert_eq!(a.stats.sha256.as_deref(), Some(""));
+    }
+
+    #[tokio::test]
+    async fn test_compute_dir_sizes() {
+        let mut root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        root.create_file("dir1/file3.txt", Some("three"))
+            .await
+            .unwrap();
+        root.create_file("dir1/dir2/file4.txt", Some("four"))
+            .await
+            .unwrap();
+        root.create_file("dir3/file6.txt", Some("six"))
+            .await
+            .unwrap();
+        let mut fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        fs.set_compute_dir_sizes(true);
+
+        let size_of = |items: &[FileInfo], name: &[&str]| {
+            let path = Path::try_from(name).unwrap();
+            items.iter().find(|i| i.path == path).unwrap().stats.size
+        };
+        let expected: u64 = root
+            .files
+            .iter()
+            .filter(|(path, node)| !node.stats.is_directory && path.starts_with("dir1"))
+            .map(|(_, node)| node.stats.size)
+            .sum();
+        assert_eq!(expected, 9);
+        let items = fs.read_dir_recurse(&Path::empty()).await.unwrap();
+        assert_eq!(size_of(&items, &["dir1"]), expected);
+        assert_eq!(size_of(&items, &["dir1", "dir2"]), 4);
+        assert_eq!(size_of(&items, &["dir1", "dir2", "dir_empty1"]), 0);
+        assert_eq!(size_of(&items, &["dir3"]), 3);
+        assert_eq!(size_of(&items, &["dir1", "file3.txt"]), 5);
+
+        let items = fs
+            .read_dir_recurse_parallel(&Path::empty(), NonZeroUsize::new(4).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(size_of(&items, &["dir1"]), expected);
+    }
+
+    #[tokio::test]
+    async fn test_read_dir_recursive_with() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let filter_for = |ext: &str| {
+            let mut filter = FilterSet::new();
+            filter.allow_extension(ext);
+            filter
+        };
+        let (txt, md) = (filter_for("txt"), filter_for("md"));
+        let file_names = |list: &RecursiveDirList| {
+            let mut names: Vec<String> = list
+                .deltas
+                .iter()
+                .filter(|item| !item.stats.is_directory)
+                .map(|item| item.path.to_string())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let mut tasks = JoinSet::new();
+        for i in 0..8 {
+            let (fs, filter) = (
+                fs.clone(),
+                if i % 2 == 0 { txt.clone() } else { md.clone() },
+            );
+            tasks.spawn(async move {
+                let list = fs
+                    .read_dir_recursive_with(&Path::empty(), &filter, None, None, true)
+                    .await
+                    .unwrap();
+                (i, list)
+            });
+        }
+        while let Some(ret) = tasks.join_next().await {
+            let (i, list) = ret.unwrap();
+            if i % 2 == 0 {
+                assert_eq!(
+                    file_names(&list),
+                    [
+                        "dir1/dir2/file4.txt",
+                        "dir1/file3.txt",
+                        "dir3/file6.txt",
+                        "file1.txt",
+                        "file2.txt"
+                    ]
+                );
+            } else {
+                assert_eq!(file_names(&list), ["dir1/file7.md"]);
+            }
+        }
+
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+        let list = fs
+            .read_dir_recursive_with(&dir1, &txt, Some(0), None, true)
+            .await
+            .unwrap();
+        assert_eq!(list.base_dir, dir1);
+        assert!(!list.truncated);
+        assert_eq!(file_names(&list), ["dir1/file3.txt"]);
+
+        // The installed filter is unaffected.
+        let items = fs.read_dir_recurse(&Path::empty()).await.unwrap();
+        assert_eq!(items.len(), root.files.len());
+    }
+
+    #[tokio::test]
+    async fn test_read_dir_recursive_with_max_entries() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let filter = FilterSet::new();
+        let cap = root.files.len() - 2;
+
+        let list = fs
+            .read_dir_recursive_with(&Path::empty(), &filter, None, Some(cap), true)
+            .await
+            .unwrap();
+        assert!(list.truncated);
+        assert_eq!(list.deltas.len(), cap);
+
+        let list = fs
+            .read_dir_recursive_with(&Path::empty(), &filter, None, Some(root.files.len()), true)
+            .await
+            .unwrap();
+        assert!(!list.truncated);
+        assert_eq!(list.deltas.len(), root.files.len());
+    }
+
+    #[tokio::test]
+    async fn test_warm() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+
+        check_len(fs.get_cache().as_ref(), 0);
+        let warmed = fs.warm(&Path::empty()).await.unwrap();
+        assert_eq!(warmed, root.files.len());
+        check_len(fs.get_cache().as_ref(), root.files.len() as u64);
+
+        // Subsequent walks are served from cache
+        let stats = fs.get_cache().stats().clone();
+        fs.read_dir_recurse(&Path::empty()).await.unwrap();
+        let after = fs.get_cache().stats().clone();
+        assert_eq!(after.hits, stats.hits + root.files.len() as u64);
+        assert_eq!(after.misses, stats.misses);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_same_file_hardlink() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let original = root.root.path().join("file1.txt");
+        let link = root.root.path().join("link.txt");
+        std::fs::hard_link(&original, &link).unwrap();
+
+        let a = FileStat::from_path(&original).await.unwrap();
+        let b = FileStat::from_path(&link).await.unwrap();
+        assert!(a.same_file(&b));
+
+        let other = FileStat::from_path(root.root.path().join("file2.txt"))
+            .await
+            .unwrap();
+        assert!(!a.same_file(&other));
+    }
+
+    #[tokio::test]
+    async fn test_tree_digest() {
+        let mut root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let other = TestRoot::new(None).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let other_fs = PortableFs::without_cache(other.root.path().to_path_buf());
+
+        let digest = fs.tree_digest(&Path::empty()).await.unwrap();
+        assert_eq!(digest.len(), 64);
+        assert_eq!(digest, other_fs.tree_digest(&Path::empty()).await.unwrap());
+
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+        let dir3 = Path::try_from(["dir3"].as_slice()).unwrap();
+        let dir3_digest = fs.tree_digest(&dir3).await.unwrap();
+        assert_ne!(fs.tree_digest(&dir1).await.unwrap(), dir3_digest);
+
+        root.modify_file("dir1/file3.txt", "x").await.unwrap();
+        assert_ne!(fs.tree_digest(&Path::empty()).await.unwrap(), digest);
+        assert_eq!(fs.tree_digest(&dir3).await.unwrap(), dir3_digest);
+    }
+
+    #[tokio::test]
+    async fn test_browse_path() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+
+        // Browse the directory
+        let portable_path = Path::try_from(&StdPath::new("").to_owned()).unwrap();
+        let directory = fs.read_dir(&portable_path).await.unwrap();
+
+        // Assert the directory contains the file
+        let mut entries: HashSet<String> = ["file1.txt", "file2.txt", "dir1", "dir3"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(directory.items.len(), 4);
+        for entry in &directory.items {
+            println!("entry name: {}", entry.name);
+            assert!(entries.remove(&entry.name));
+        }
+        assert!(entries.is_empty());
+        root.match_entries(&fs, &directory);
+    }
+
+    #[tokio::test]
+    async fn test_read_dir_matching() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+
+        let directory = fs.read_dir_matching(&dir1, "*.txt").await.unwrap();
+        assert_eq!(directory.current_path, dir1);
+        let names: Vec<&str> = directory.items.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["file3.txt"]);
+        root.match_entries(&fs, &directory);
+
+        let directory = fs.read_dir_matching(&dir1, "*.{md,rs}").await.unwrap();
+        let names: Vec<&str> = directory.items.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["file7.md", "file8.rs"]);
+
+        let err = fs.read_dir_matching(&dir1, "[a-").await.unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{err:?}");
+    }
+
+    async fn get_deltas(
+        path: &str,
+        sync_items: Vec<FileInfo>,
+    ) -> (HashSet<String>, Vec<FileInfo>, Vec<String>) {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let (a, b) = get_deltas_with(path, sync_items, &fs).await;
+        (a, b, temp_files(&root))
+    }
+
+    async fn get_deltas_with(
+        path: &str,
+        sync_items: Vec<FileInfo>,
+        fs: &PortableFs,
+    ) -> (HashSet<String>, Vec<FileInfo>) {
+        // Set up the channel
+        let (tx, mut rx) = mpsc::channel(10);
+        let delta = RecursiveDirList {
+            schema_version: None,
+            base_dir: Path::try_from(&StdPath::new(path).to_owned()).unwrap(),
+            deltas: sync_items,
+            digests: vec![],
+            truncated: false,
+        };
+
+        // Call exchange_deltas
+        fs.exchange_deltas(tx, delta, 10).await;
+
+        // Assert the channel received the correct FileInfo
+        let mut received_items = Vec::new();
+        while let Some(items) = rx.recv().await {
+            received_items.extend(items);
+        }
+        println!("received_items: {:#?}", received_items);
+        let mut received_files = HashSet::new();
+        received_items.iter().for_each(|i| {
+            received_files.insert(i.path.to_string());
+        });
+        (received_files, received_items)
+    }
+
+    #[tokio::test]
+    async fn test_exchange_deltas_without_digests() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let mut snapshot = fs
+            .read_dir_recursive_with(&Path::empty(), &FilterSet::new(), None, None, false)
+            .await
+            .unwrap()
+            .deltas;
+        assert!(snapshot.iter().all(|item| item.stats.sha256.is_none()));
+
+        // Unchanged entries are not resent though the snapshot lacks digests
+        let (received, _) = get_deltas_with("", snapshot.clone(), &fs).await;
+        assert!(received.is_empty(), "{received:?}");
+
+        let file1 = snapshot
+            .iter_mut()
+            .find(|item| item.path.basename() == Some("file1.txt"))
+            .unwrap();
+        file1.stats.size += 1;
+        let (received, _) = get_deltas_with("", snapshot, &fs).await;
+        assert_eq!(received, HashSet::from(["file1.txt".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_deltas_rootdir() {
+        let (expected_files, _, temp_files) = get_deltas("", vec![]).await;
+        let mut files_found = 0;
+        for file in &temp_files {
+            files_found += 1;
+            assert!(
+                expected_files.contains(file),
+                "{:?} Missing file: {} ",
+                expected_files,
+                file
+            );
+        }
+        assert_eq!(
+            files_found,
+            expected_files.len(),
+            "Expected files: {:?}",
+            expected_files
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exchange_deltas_subdir() {
+        let (expected_files, _, temp_files) = get_deltas("dir1", vec![]).await;
+        let mut files_found = 0;
+        for file in &temp_files {
+            if file.contains("dir1") && file != "dir1" {
+                files_found += 1;
+                assert!(
+                    expected_files.contains(file),
+                    "{:?} Missing file: {} ",
+                    expected_files,
+                    file
+                );
+            }
+        }
+        assert_eq!(
+            files_found,
+            expected_files.len(),
+            "Expected files: {:?}",
+            expected_files
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exchange_deltas_sends_empty() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let (expected_files, sync_items) = get_deltas_with("dir1", vec![], &fs).await;
+        let mut files_found = 0;
+        for file in &temp_files(&root) {
+            if file.contains("dir1") && file != "dir1" {
+                files_found += 1;
+                assert!(
+                    expected_files.contains(file),
+                    "{:?} Missing file: {} ",
+                    expected_files,
+                    file
+                );
+            }
+        }
+        assert_eq!(
+            files_found,
+            expected_files.len(),
+            "Expected files: {:?}",
+            expected_files
+        );
+        let (expected_files, sync_items) = get_deltas_with("dir1", sync_items, &fs).await;
+        assert!(expected_files.is_empty());
+        assert!(sync_items.is_empty());
+    }
+    #[tokio::test]
+    async fn test_exchange_deltas_content_only() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let (_, mut sync_items) = get_deltas_with("dir1", vec![], &fs).await;
+        assert!(!sync_items.is_empty());
+        for item in &mut sync_items {
+            item.stats.mtime = format_system_time(SystemTime::UNIX_EPOCH);
+        }
+
+        let (received, _) = get_deltas_with("dir1", sync_items.clone(), &fs).await;
+        assert_eq!(received.len(), sync_items.len());
+
+        fs.set_content_only_deltas(true);
+        let (received, _) = get_deltas_with("dir1", sync_items, &fs).await;
+        assert!(received.is_empty(), "{received:?}");
+    }
+
+    #[tokio::test]
+    async fn test_read_file() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+
+        let portable_path = Path::try_from(["dir1", "file3.txt"].as_slice()).unwrap();
+        let data: &[u8] = b"Hello, world!";
+        tokio::fs::write(fs.as_abs_path(&portable_path), data)
+            .await
+            .unwrap();
+
+        let node = fs.read_file(&portable_path).await.unwrap();
+        assert_eq!(node.contents, data);
+        assert_eq!(node.stats.size, data.len() as u64);
+        assert_eq!(
+            node.stats.sha256.as_ref().unwrap(),
+            &data
+                .sha256_build()
+                .await
+                .unwrap()
+                .sha256_string()
+                .await
+                .unwrap()
+        );
+
+        // Second read is served from cache
+        let hits = fs.get_cache().stats().hits;
+        assert_eq!(fs.read_file(&portable_path).await.unwrap(), node);
+        assert_eq!(fs.get_cache().stats().hits, hits + 1);
+    }
+
+    #[tokio::test]
+    async fn test_mtime_precision() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let whole = parse_system_time("2018-01-26T18:30:09Z").unwrap();
+        let (a, b) = (
+            Path::try_from(["file1.txt"].as_slice()).unwrap(),
+            Path::try_from(["file2.txt"].as_slice()).unwrap(),
+        );
+        for (path, millis) in [(&a, 100), (&b, 900)] {
+            filetime::set_file_mtime(
+                fs.as_abs_path(path),
+                filetime::FileTime::from_system_time(
+                    whole + std::time::Duration::from_millis(millis),
+                ),
+            )
+            .unwrap();
+        }
+        let stats = async |fs: &PortableFs, path: &Path| fs.read_file(path).await.unwrap().stats;
+
+        assert_ne!(stats(&fs, &a).await, stats(&fs, &b).await);
+
+        fs.set_mtime_precision(crate::utils::Precision::Seconds);
+        let (stats_a, stats_b) = (stats(&fs, &a).await, stats(&fs, &b).await);
+        assert_eq!(stats_a.mtime, "2018-01-26T18:30:09Z");
+        assert_eq!(stats_a, stats_b);
+    }
+
+    #[tokio::test]
+    async fn test_apply_nodes() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let node = async |contents: &[u8]| {
+            let digest = contents
+                .sha256_build()
+                .await
+                .unwrap()
+                .sha256_string()
+                .await
+                .unwrap();
+            let stats = FileStat {
+                size: contents.len() as u64,
+                mtime: "2018-01-26T18:30:09.453Z".into(),
+                is_directory: false,
+                sha256: Some(digest),
+                xattrs: None,
+                dev: None,
+                ino: None,
+                is_symlink: false,
+            };
+            FileNode::new(stats, contents.to_vec())
+        };
+        let mut dir = node(b"").await;
+        dir.stats.is_directory = true;
+        let base = Path::try_from(["dir4"].as_slice()).unwrap();
+        let paths = [
+            Path::try_from(["empty"].as_slice()).unwrap(),
+            Path::try_from(["sub", "one.txt"].as_slice()).unwrap(),
+            Path::try_from(["two.txt"].as_slice()).unwrap(),
+        ];
+        let nodes = [dir, node(b"one").await, node(b"two").await];
+
+        assert_eq!(fs.apply_nodes(&base, &nodes, &paths).await, Ok(3));
+        assert!(fs.as_abs_path(&base.join(&paths[0])).is_dir());
+        for (node, path) in nodes.iter().zip(&paths).skip(1) {
+            let read = fs.read_file(&base.join(path)).await.unwrap();
+            assert_eq!(&read, node);
+        }
+
+        // A mismatched digest aborts only its own entry
+        let mut bad = node(b"three").await;
+        bad.contents = b"tampered".to_vec();
+        let nodes = [bad, node(b"four").await];
+        let paths = [
+            Path::try_from(["three.txt"].as_slice()).unwrap(),
+            Path::try_from(["four.txt"].as_slice()).unwrap(),
+        ];
+        assert_eq!(fs.apply_nodes(&base, &nodes, &paths).await, Ok(1));
+        assert!(!fs.as_abs_path(&base.join(&paths[0])).exists());
+        assert!(fs.as_abs_path(&base.join(&paths[1])).is_file());
+
+        assert!(matches!(
+            fs.apply_nodes(&base, &nodes, &paths[..1]).await,
+            Err(Error::InvalidArgument(_))
+        ));
+
+        // Denied directories are not created
+        let mut filter = FilterSet::new();
+        filter.deny_path("dir4/denied");
+        fs.set_filter(filter);
+        let mut dir = node(b"").await;
+        dir.stats.is_directory = true;
+        let paths = [Path::try_from(["denied"].as_slice()).unwrap()];
+        assert_eq!(fs.apply_nodes(&base, &[dir], &paths).await, Ok(0));
+        assert!(!fs.as_abs_path(&base.join(&paths[0])).exists());
+    }
+
+    #[tokio::test]
+    async fn test_read_dir_after() {
+        let mut root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+
+        let mut names = vec![];
+        let mut cursor = None;
+        loop {
+            let page = fs
+                .read_dir_after(&dir1, cursor.as_deref(), 2)
+                .await
+                .unwrap();
+            assert!(page.items.len() <= 2);
+            names.extend(page.items.into_iter().map(|entry| entry.name));
+            if names.len() == 2 {
+                // Added before the cursor, hence not listed
+                root.create_file("dir1/a.txt", Some("")).await.unwrap();
+                // Added after the cursor, hence listed
+                root.create_file("dir1/file5.txt", Some("")).await.unwrap();
+            }
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            names,
+            ["dir2", "file3.txt", "file5.txt", "file7.md", "file8.rs"]
+        );
+
+        assert!(fs.read_dir_after(&dir1, None, 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cache_stats() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        assert_eq!(fs.cache_stats().hit_rate(), 0.0);
+
+        let file1 = Path::try_from(["file1.txt"].as_slice()).unwrap();
+        let missing = Path::try_from(["missing.txt"].as_slice()).unwrap();
+        fs.read_file(&file1).await.unwrap();
+        fs.read_file(&file1).await.unwrap();
+        fs.read_file(&file1).await.unwrap();
+        fs.get_cache().get(&missing);
+        // The first read misses both when validating and when loading
+        let stats = fs.cache_stats();
+        assert_eq!(stats, CacheStats { hits: 2, misses: 3 });
+        assert_eq!(stats.hit_rate(), 0.4);
+
+        fs.reset_cache_stats();
+        assert_eq!(fs.cache_stats(), CacheStats::default());
+        assert_eq!(fs.cache_keys(), [file1]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlink_policy() {
+        use crate::SymlinkPolicy;
+
+        let root = TestRoot::new(None).await.unwrap();
+        let base = root.root.path();
+        std::os::unix::fs::symlink("../dir3", base.join("dir1/link")).unwrap();
+        std::os::unix::fs::symlink("..", base.join("dir1/up")).unwrap();
+        std::os::unix::fs::symlink("/", base.join("dir1/out")).unwrap();
+        let mut fs = PortableFs::without_cache(base.to_path_buf());
+        let walk = async |fs: &PortableFs| {
+            let mut items: Vec<(String, bool)> = fs
+                .read_dir_recurse(&Path::empty())
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|item| {
+                    let path = item.path.display_with('/');
+                    ["dir1/link", "dir1/up", "dir1/out"]
+                        .iter()
+                        .any(|link| path.starts_with(link))
+                })
+                .map(|item| (item.path.display_with('/'), item.stats.is_directory))
+                .collect();
+            items.sort();
+            items
+        };
+
+        fs.set_symlink_policy(SymlinkPolicy::Deny);
+        assert_eq!(walk(&fs).await, []);
+
+        fs.set_symlink_policy(SymlinkPolicy::ReportOnly);
+        assert_eq!(
+            walk(&fs).await,
+            [
+                ("dir1/link".to_string(), false),
+                ("dir1/out".to_string(), false),
+                ("dir1/up".to_string(), false)
+            ]
+        );
+
+        // Links out of the root and to ancestors are skipped
+        fs.set_symlink_policy(SymlinkPolicy::Follow);
+        assert_eq!(
+            walk(&fs).await,
+            [
+                ("dir1/link".to_string(), true),
+                ("dir1/link/file6.txt".to_string(), false)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_filter() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+        let names = |dir: Directory| -> Vec<String> {
+            dir.items.into_iter().map(|entry| entry.name).collect()
+        };
+
+        let mut md = FilterSet::new();
+        md.allow_extension("md");
+        fs.set_filter(md.clone());
+        assert_eq!(fs.filter(), md);
+        assert_eq!(names(fs.read_dir(&dir1).await.unwrap()), ["file7.md"]);
+
+        let mut rs = FilterSet::new();
+        rs.allow_extension("rs");
+        fs.set_filter(rs);
+        assert_eq!(names(fs.read_dir(&dir1).await.unwrap()), ["file8.rs"]);
+
+        fs.set_filter(FilterSet::new());
+        assert_eq!(
+            names(fs.read_dir(&dir1).await.unwrap()),
+            ["dir2", "file3.txt", "file7.md", "file8.rs"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_is_dir_empty() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let path = |components: &[&str]| Path::try_from(components).unwrap();
+
+        let stats = fs.read_file(&path(&["file1.txt"])).await.unwrap().stats;
+        assert!(stats.is_empty());
+
+        assert!(
+            fs.is_dir_empty(&path(&["dir1", "dir2", "dir_empty1"]))
+                .await
+                .unwrap()
+        );
+        assert!(!fs.is_dir_empty(&path(&["dir1", "dir2"])).await.unwrap());
+        assert!(fs.is_dir_empty(&path(&["missing"])).await.is_err());
+
+        // Entries that are filtered out don't count
+        fs.allow_extension("rs");
+        assert!(fs.is_dir_empty(&path(&["dir3"])).await.unwrap());
+        assert!(!fs.is_dir_empty(&path(&["dir1"])).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_write_fsync() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        fs.set_fsync(true);
+
+        let path = Path::try_from(["dir4", "synced.txt"].as_slice()).unwrap();
+        let data: &[u8] = b"durable";
+        let stats = write_file(&fs, &path, data).await;
+        fs.get_cache().pop(&path);
+        let node = fs.read_file(&path).await.unwrap();
+        assert_eq!(node.contents, data);
+        assert_eq!(node.stats.sha256, stats.sha256);
+        // No temporary files are left behind
+        let mut entries = tokio::fs::read_dir(fs.as_abs_path(&path.parent().unwrap()))
+            .await
+            .unwrap();
+        let mut names = vec![];
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, ["synced.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_read_dir_unreadable_entry() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let locked = root.root.path().join("dir3").join("locked.txt");
+        std::fs::write(&locked, "secret").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions don't apply to root
+        if std::fs::File::open(&locked).is_ok() {
+            return;
+        }
+
+        let dir3 = Path::try_from(["dir3"].as_slice()).unwrap();
+        let dir = fs.read_dir(&dir3).await.unwrap();
+        let names: Vec<&str> = dir.items.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["file6.txt"]);
+        assert_eq!(dir.errors.len(), 1);
+        assert_eq!(dir.errors[0].name, "locked.txt");
+
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o644)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_read_dir_recursive_lenient() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let locked_file = root.root.path().join("dir3").join("locked.txt");
+        std::fs::write(&locked_file, "secret").unwrap();
+        let locked_dir = root.root.path().join("dir1").join("dir2");
+        for locked in [&locked_file, &locked_dir] {
+            std::fs::set_permissions(locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        }
+        // Permissions don't apply to root
+        if std::fs::File::open(&locked_file).is_ok() {
+            return;
+        }
+
+        let (list, errors) = fs.read_dir_recursive_lenient(&Path::empty()).await;
+        std::fs::set_permissions(&locked_file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        std::fs::set_permissions(&locked_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut found: Vec<String> = list.deltas.iter().map(|d| d.path.to_string()).collect();
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                "dir1",
+                "dir1/dir2",
+                "dir1/file3.txt",
+                "dir1/file7.md",
+                "dir1/file8.rs",
+                "dir3",
+                "dir3/file6.txt",
+                "file1.txt",
+                "file2.txt",
+            ]
+        );
+        let mut failed: Vec<String> = errors.iter().map(|(path, _)| path.to_string()).collect();
+        failed.sort();
+        assert_eq!(failed, ["dir1/dir2", "dir3/locked.txt"]);
+
+        let missing = Path::try_from(["missing"].as_slice()).unwrap();
+        let (list, errors) = fs.read_dir_recursive_lenient(&missing).await;
+        assert!(list.deltas.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, missing);
+    }
+
+    #[tokio::test]
+    async fn test_read_dir_recursive_since() {
+        use crate::hash::HashAlgorithm;
+
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let past = filetime::FileTime::from_system_time(
+            SystemTime::now() - std::time::Duration::from_secs(3600),
+        );
+        for item in fs.read_dir_recurse(&Path::empty()).await.unwrap() {
+            let full_path = fs.confined_path(&item.path).unwrap();
+            filetime::set_file_mtime(full_path, past).unwrap();
+        }
+        let since = SystemTime::now() - std::time::Duration::from_secs(60);
+        let list = fs
+            .read_dir_recursive_since(&Path::empty(), since)
+            .await
+            .unwrap();
+        assert!(list.deltas.is_empty());
+
+        let touched = Path::try_from(["dir1", "dir2", "file4.txt"].as_slice()).unwrap();
+        fs.touch(&touched).await.unwrap();
+        let list = fs
+            .read_dir_recursive_since(&Path::empty(), since)
+            .await
+            .unwrap();
+        let mut found: Vec<String> = list.deltas.iter().map(|d| d.path.to_string()).collect();
+        found.sort();
+        assert_eq!(found, ["dir1", "dir1/dir2", "dir1/dir2/file4.txt"]);
+        let file = list.deltas.iter().find(|d| d.path == touched).unwrap();
+        assert_eq!(
+            file.stats.sha256,
+            Some(HashAlgorithm::Sha256.digest_bytes(b"").await.unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_checked() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let base = root.root.path().join("dir1").join("..").join("dir3");
+        let fs = PortableFs::new_checked(base).unwrap();
+        assert_eq!(
+            fs.base_dir,
+            root.root.path().join("dir3").canonicalize().unwrap()
+        );
+        let file6 = Path::try_from(["file6.txt"].as_slice()).unwrap();
+        assert!(fs.read_file(&file6).await.is_ok());
+
+        // Portable paths can't climb out of the root
+        assert!(Path::try_from(["..", "file1.txt"].as_slice()).is_err());
+
+        let missing = root.root.path().join("missing");
+        assert!(matches!(
+            PortableFs::new_checked(missing),
+            Err(Error::InvalidPath { .. })
+        ));
+        let file = root.root.path().join("file1.txt");
+        assert!(matches!(
+            PortableFs::new_checked(file),
+            Err(Error::InvalidPath { .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_new_checked_escaping_symlink() {
+        let root = TestRoot::new(None).await.unwrap();
+        let base = root.root.path().join("dir1");
+        std::os::unix::fs::symlink(root.root.path().join("dir3"), base.join("out")).unwrap();
+        std::os::unix::fs::symlink(root.root.path().join("file1.txt"), base.join("out.txt"))
+            .unwrap();
+        std::os::unix::fs::symlink(base.join("file3.txt"), base.join("in.txt")).unwrap();
+        let path = |components: &[&str]| Path::try_from(components).unwrap();
+
+        // Without checks the links are followed
+        let fs = PortableFs::without_cache(base.clone());
+        assert!(fs.read_file(&path(&["out.txt"])).await.is_ok());
+
+        let fs = PortableFs::new_checked(base).unwrap();
+        assert!(fs.read_file(&path(&["in.txt"])).await.is_ok());
+        for escaping in [&["out.txt"][..], &["out", "file6.txt"], &["out", "new.txt"]] {
+            assert!(matches!(
+                fs.read_file(&path(escaping)).await,
+                Err(Error::InvalidPath { .. })
+            ));
+        }
+        assert!(matches!(
+            fs.touch(&path(&["out", "new.txt"])).await,
+            Err(Error::InvalidPath { .. })
+        ));
+        assert!(!root.root.path().join("dir3").join("new.txt").exists());
+        assert!(matches!(
+            fs.read_dir(&path(&["out"])).await,
+            Err(Error::InvalidPath { .. })
+        ));
+    }
+
+    #[test]
+    fn test_adversarial_paths_rejected() {
+        for components in [
+            r#"[".."]"#,
+            r#"["a", ".."]"#,
+            r#"["."]"#,
+            r#"[""]"#,
+            r#"["/etc"]"#,
+            r#"["a/../.."]"#,
+            r#"["..\\.."]"#,
+        ] {
+            let json = format!(r#"{{"components":{components}}}"#);
+            assert!(serde_json::from_str::<Path>(&json).is_err(), "for {json}");
+        }
+        for std_path in ["..", "a/../..", "../etc"] {
+            assert!(
+                Path::try_from(StdPath::new(std_path)).is_err(),
+                "for {std_path}"
+            );
+        }
+
+        let fs = PortableFs::without_cache(PathBuf::from("/base"));
+        let path = Path::try_from(StdPath::new("/etc/passwd")).unwrap();
+        assert_eq!(
+            fs.lexically_confined_path(&path).unwrap(),
+            StdPath::new("/base/etc/passwd")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_resolve_safe() {
+        use std::os::unix::fs::symlink;
+
+        let root = TestRoot::new(None).await.unwrap();
+        let base = root.root.path().join("dir1");
+        std::fs::create_dir(base.join("inner")).unwrap();
+        symlink("..", base.join("up")).unwrap();
+        symlink("/", base.join("abs")).unwrap();
+        symlink("../../dir3", base.join("inner").join("out")).unwrap();
+        symlink("inner", base.join("chain")).unwrap();
+        symlink("self_loop", base.join("self_loop")).unwrap();
+        symlink("/nonexistent/target", base.join("dangling")).unwrap();
+        symlink("file3.txt", base.join("in.txt")).unwrap();
+        symlink("dir2", base.join("in_dir")).unwrap();
+        let path = |components: &[&str]| Path::try_from(components).unwrap();
+
+        let escaping: &[&[&str]] = &[
+            &["up"],
+            &["up", "file1.txt"],
+            &["up", "dir3", "file6.txt"],
+            &["abs"],
+            &["abs", "etc", "passwd"],
+            &["inner", "out"],
+            &["inner", "out", "file6.txt"],
+            &["chain", "out", "file6.txt"],
+            &["chain", "out", "new.txt"],
+            &["self_loop"],
+            &["self_loop", "new.txt"],
+            &["dangling"],
+            &["dangling", "new.txt"],
+        ];
+        let contained: &[&[&str]] = &[
+            &[],
+            &["file3.txt"],
+            &["in.txt"],
+            &["in_dir", "file4.txt"],
+            &["chain"],
+            &["new", "dir", "new.txt"],
+        ];
+        for fs in [
+            PortableFs::without_cache(base.clone()),
+            PortableFs::new_checked(base.clone()).unwrap(),
+        ] {
+            for components in escaping {
+                assert!(
+                    matches!(
+                        fs.resolve_safe(&path(components)),
+                        Err(Error::InvalidPath { .. })
+                    ),
+                    "for {components:?}"
+                );
+            }
+            for components in contained {
+                assert!(
+                    fs.resolve_safe(&path(components)).is_ok(),
+                    "for {components:?}"
+                );
+            }
+        }
+
+        let fs = PortableFs::new_checked(base).unwrap();
+        for components in escaping {
+            assert!(fs.read_file(&path(components)).await.is_err());
+            assert!(fs.read_dir(&path(components)).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_dir_parent() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let path = |components: &[&str]| Path::try_from(components).unwrap();
+
+        let dir = fs.read_dir(&path(&["dir1", "dir2"])).await.unwrap();
+        assert_eq!(dir.parent, Some(path(&["dir1"])));
+        let dir = fs.read_dir(&path(&["dir1"])).await.unwrap();
+        assert_eq!(dir.parent, Some(Path::empty()));
+        let dir = fs.read_dir(&Path::empty()).await.unwrap();
+        assert_eq!(dir.parent, None);
+    }
+
+    #[tokio::test]
+    async fn test_disk_usage() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+
+        let mut expected = DiskUsage::default();
+        let mut pending = vec![fs.as_abs_path(&dir1)];
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let entry = entry.unwrap();
+                let metadata = entry.metadata().unwrap();
+                if metadata.is_dir() {
+                    expected.dir_count += 1;
+                    pending.push(entry.path());
+                } else {
+                    expected.file_count += 1;
+                    expected.total_bytes += metadata.len();
+                }
+            }
+        }
+        assert_eq!(fs.disk_usage(&dir1).await.unwrap(), expected);
+        assert_eq!(expected.file_count, 4);
+        assert_eq!(expected.dir_count, 2);
+        // Nothing was hashed, so nothing was cached
+        assert_eq!(fs.cache_stats(), CacheStats::default());
+
+        fs.allow_extension("md");
+        let usage = fs.disk_usage(&dir1).await.unwrap();
+        assert_eq!(usage.file_count, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_lookups_load_once() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let path = Path::try_from(["dir4", "cold.bin"].as_slice()).unwrap();
+        fs.create_all(&path.parent().unwrap()).await.unwrap();
+        tokio::fs::write(fs.as_abs_path(&path), vec![7u8; 1 << 20])
+            .await
+            .unwrap();
+
+        let mut tasks = JoinSet::new();
+        for _ in 0..16 {
+            let (layer, full_path, path) = (fs.layer.clone(), fs.as_abs_path(&path), path.clone());
+            tasks.spawn(async move { lookup_or_load(layer, &full_path, &path).await });
+        }
+        let stats: Vec<FileStat> = tasks
+            .join_all()
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert!(stats.iter().all(|s| s == &stats[0]));
+        assert_eq!(fs.layer.loads.load(Ordering::Relaxed), 1);
+        assert!(fs.layer.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn assert_send<T: Send>(_: &T) {}
+
+        assert_send_sync::<PortableFs>();
+        let fs = PortableFs::without_cache(PathBuf::new());
+        assert_send(&fs.read_dir(&Path::empty()));
+        assert_send(&fs.read_dir_recurse(&Path::empty()));
+        assert_send(&fs.stat(&Path::empty()));
+        assert_send(&fs.read_file(&Path::empty()));
+        let filter = FilterSet::new();
+        assert_send(&fs.read_dir_recursive_with(&Path::empty(), &filter, None, None, true));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_access() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let md_only: FilterSet = serde_json::from_str(
+            r#"{
+                "allowed_roots": [],
+                "denied_roots": [],
+                "allowed_extensions": ["md"],
+                "allowed_filenames": []
+            }"#,
+        )
+        .unwrap();
+        let listing = |fs: PortableFs| async move {
+            let mut items = fs.read_dir_recurse(&Path::empty()).await.unwrap();
+            items.sort();
+            items
+        };
+        let all = listing(fs.clone()).await;
+        fs.set_filter(md_only.clone());
+        let md = listing(fs.clone()).await;
+        fs.set_filter(FilterSet::new());
+        assert_ne!(all, md);
+        let file7 = Path::try_from(["dir1", "file7.md"].as_slice()).unwrap();
+        let file7_stats = fs.stat(&file7).await.unwrap();
+
+        let mut tasks = JoinSet::new();
+        for i in 0..32 {
+            let (fs, all, md, md_only, file7, file7_stats) = (
+                fs.clone(),
+                all.clone(),
+                md.clone(),
+                md_only.clone(),
+                file7.clone(),
+                file7_stats.clone(),
+            );
+            tasks.spawn(async move {
+                for _ in 0..20 {
+                    // Each walk sees one filter or the other, never a mix
+                    let items = listing(fs.clone()).await;
+                    assert!(items == all || items == md);
+                    let dir = fs.read_dir(&Path::empty()).await.unwrap();
+                    assert!(dir.errors.is_empty());
+                    assert_eq!(fs.stat(&file7).await.unwrap(), file7_stats);
+                    if i % 8 == 0 {
+                        fs.set_filter(md_only.clone());
+                        fs.set_filter(FilterSet::new());
+                    }
+                }
+            });
+        }
+        let results = tokio::time::timeout(std::time::Duration::from_secs(60), tasks.join_all())
+            .await
+            .expect("concurrent accesses deadlocked");
+        assert_eq!(results.len(), 32);
+        assert!(fs.layer.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_dir_chunked() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+
+        let (tx, mut rx) = mpsc::channel(100);
+        fs.read_dir_chunked(&dir1, 2, tx).await.unwrap();
+        let mut chunks = vec![];
+        while let Some(chunk) = rx.recv().await {
+            chunks.push(chunk);
+        }
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.deltas.len() <= 2));
+        let seqs: Vec<u64> = chunks.iter().map(|c| c.seq).collect();
+        assert_eq!(seqs, (0..chunks.len() as u64).collect::<Vec<_>>());
+        assert!(chunks.last().unwrap().last);
+
+        let list = RecursiveDirList::reassemble(chunks).unwrap();
+        assert_eq!(list.base_dir, dir1);
+        let mut expected = fs.read_dir_recurse(&dir1).await.unwrap();
+        let mut found = list.deltas;
+        expected.sort();
+        found.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[tokio::test]
+    async fn test_with_filter() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let filter: FilterSet = serde_json::from_str(
+            r#"{
+                "allowed_roots": [],
+                "denied_roots": ["dir1/dir2"],
+                "allowed_extensions": ["txt"],
+                "allowed_filenames": []
+            }"#,
+        )
+        .unwrap();
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+        for fs in [
+            PortableFs::with_filter(root.root.path().to_path_buf(), filter.clone()),
+            PortableFs::with_cache_and_filter(root.root.path().to_path_buf(), filter.clone()),
+        ] {
+            assert_eq!(fs.filter(), filter);
+            let dir = fs.read_dir(&dir1).await.unwrap();
+            let names: Vec<&str> = dir.items.iter().map(|e| e.name.as_str()).collect();
+            assert_eq!(names, ["file3.txt"]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_non_utf8_policy() {
+        use std::os::unix::ffi::OsStrExt;
+
+        use crate::NonUtf8Policy;
+
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"bad\xff.txt");
+        std::fs::write(root.root.path().join("dir3").join(name), "data").unwrap();
+        let mut fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let dir3 = Path::try_from(["dir3"].as_slice()).unwrap();
+        let names = |items: Vec<FileInfo>| -> Vec<String> {
+            let mut names: Vec<String> = items.iter().map(|i| i.path.display_with('/')).collect();
+            names.sort();
+            names
+        };
+
+        // Skipped by default
+        let items = fs.read_dir_recurse(&dir3).await.unwrap();
+        assert_eq!(names(items), ["dir3/file6.txt"]);
+
+        fs.set_non_utf8_policy(NonUtf8Policy::Error);
+        assert!(matches!(
+            fs.read_dir_recurse(&dir3).await,
+            Err(Error::InvalidPath { .. })
+        ));
+
+        fs.set_non_utf8_policy(NonUtf8Policy::Lossy);
+        let items = fs.read_dir_recurse(&dir3).await.unwrap();
+        assert_eq!(names(items), ["dir3/bad\u{FFFD}.txt", "dir3/file6.txt"]);
+
+        fs.set_non_utf8_policy(NonUtf8Policy::Skip);
+        let items = fs.read_dir_recurse(&dir3).await.unwrap();
+        assert_eq!(names(items), ["dir3/file6.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_listing_caches_stats() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+
+        let dir = fs.read_dir(&dir1).await.unwrap();
+        let before = fs.cache_stats();
+        for entry in &dir.items {
+            let stats = fs.stat(&entry.full_path(&dir1).unwrap()).await.unwrap();
+            assert_eq!(stats, entry.stats);
+        }
+        let after = fs.cache_stats();
+        assert_eq!(after.hits, before.hits + dir.items.len() as u64);
+        assert_eq!(after.misses, before.misses);
+
+        let items = fs.read_dir_recurse(&Path::empty()).await.unwrap();
+        let before = fs.cache_stats();
+        for item in &items {
+            fs.stat(&item.path).await.unwrap();
+        }
+        let after = fs.cache_stats();
+        assert_eq!(after.hits, before.hits + items.len() as u64);
+        assert_eq!(after.misses, before.misses);
+
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let dir3 = Path::try_from(["dir3"].as_slice()).unwrap();
+        assert_eq!(fs.prefetch_dir(&dir3).await.unwrap(), 1);
+        let before = fs.cache_stats();
+        fs.stat(&dir3.join(&Path::try_from(["file6.txt"].as_slice()).unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(fs.cache_stats().hits, before.hits + 1);
+        assert!(matches!(
+            fs.stat(&Path::try_from(["missing"].as_slice()).unwrap())
+                .await,
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stat_filtered() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+        let file1 = Path::try_from(["file1.txt"].as_slice()).unwrap();
+        fs.stat(&dir1).await.unwrap();
+        fs.stat(&file1).await.unwrap();
+
+        fs.deny_path("dir1");
+        fs.allow_extension("rs");
+        let filtered = Err(Error::InvalidArgument("Path is filtered out".to_string()));
+        assert_eq!(fs.stat(&dir1).await, filtered);
+        // Even when its stats are cached
+        assert_eq!(fs.stat(&file1).await, filtered);
+    }
+
+    #[tokio::test]
+    async fn test_sibling_prefetch() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let path = |components: &[&str]| Path::try_from(components).unwrap();
+        let file3 = path(&["dir1", "file3.txt"]);
+        let siblings = [path(&["dir1", "file7.md"]), path(&["dir1", "file8.rs"])];
+
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        fs.stat(&file3).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(fs.cache_keys(), vec![file3.clone()]);
+
+        let mut fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        fs.set_sibling_prefetch(true);
+        fs.stat(&file3).await.unwrap();
+        for _ in 0..100 {
+            if fs.cache_keys().len() == 3 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let mut keys = fs.cache_keys();
+        keys.sort();
+        assert_eq!(keys, [file3, siblings[0].clone(), siblings[1].clone()]);
+
+        let before = fs.cache_stats();
+        for sibling in &siblings {
+            fs.stat(sibling).await.unwrap();
         }
-        ret
+        assert_eq!(fs.cache_stats().hits, before.hits + 2);
+        assert_eq!(fs.cache_stats().misses, before.misses);
     }
 
-    /// Reads the contents of the file at the specified path.
-    pub async fn read_file(&self, path: &Path) -> Result<Vec<u8>, Error> {
-        let full_path = self.as_abs_path(path);
-        if !full_path.exists() {
-            return Err(Error::InvalidArgument("File does not exist".to_string()));
+    #[derive(Default)]
+    struct RecordingObserver {
+        read_dirs: std::sync::Mutex<Vec<(Path, std::time::Duration, usize)>>,
+        stats: std::sync::Mutex<Vec<(Path, std::time::Duration, bool)>>,
+        hashes: std::sync::Mutex<Vec<(Path, std::time::Duration, u64)>>,
+    }
+
+    impl crate::Observer for RecordingObserver {
+        fn on_read_dir(&self, path: &Path, duration: std::time::Duration, count: usize) {
+            self.read_dirs
+                .lock()
+                .unwrap()
+                .push((path.clone(), duration, count));
         }
-        if full_path.is_dir() {
-            return Err(Error::InvalidArgument("Path is a directory".to_string()));
+
+        fn on_stat(&self, path: &Path, duration: std::time::Duration, hit: bool) {
+            self.stats
+                .lock()
+                .unwrap()
+                .push((path.clone(), duration, hit));
         }
-        tokio::fs::read(&full_path).await.map_err(|e| Error::Read {
-            what: full_path.to_str().unwrap().into(),
-            how: e.to_string(),
-        })
-    }
 
-    pub(crate) fn get_cache(&'_ self) -> MutexGuard<'_, Box<dyn Cache>> {
-        self.layer.cache.lock().unwrap()
+        fn on_hash(&self, path: &Path, duration: std::time::Duration, bytes: u64) {
+            self.hashes
+                .lock()
+                .unwrap()
+                .push((path.clone(), duration, bytes));
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashSet;
-    use std::path::PathBuf;
-    use std::time::SystemTime;
+    #[tokio::test]
+    async fn test_observer() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        std::fs::write(root.root.path().join("dir1").join("file3.txt"), "hello").unwrap();
+        let mut fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let observer = Arc::new(RecordingObserver::default());
+        fs.set_observer(observer.clone());
+        let dir1 = Path::try_from(["dir1"].as_slice()).unwrap();
+        let file3 = Path::try_from(["dir1", "file3.txt"].as_slice()).unwrap();
 
-    use tokio::sync::mpsc;
+        let start = Instant::now();
+        let dir = fs.read_dir(&dir1).await.unwrap();
+        let elapsed = start.elapsed();
+        {
+            let read_dirs = observer.read_dirs.lock().unwrap();
+            assert_eq!(read_dirs.len(), 1);
+            let (path, duration, count) = &read_dirs[0];
+            assert_eq!((path, *count), (&dir1, dir.items.len()));
+            assert!(*duration <= elapsed);
 
-    use super::*;
-    use crate::FileInfo;
-    use crate::FileStat;
-    use crate::RecursiveDirList;
-    use crate::TestRoot;
-    use crate::cache::CacheStats;
-    use crate::hash::Sha256Builder;
-    use crate::hash::Sha256String;
-    use crate::utils::format_system_time;
-    use crate::utils::parse_system_time;
+            let stats = observer.stats.lock().unwrap();
+            assert_eq!(stats.len(), dir.items.len());
+            assert!(
+                stats
+                    .iter()
+                    .all(|(_, duration, hit)| !hit && *duration <= elapsed)
+            );
 
-    fn temp_files(root: &TestRoot) -> Vec<String> {
-        root.files
-            .keys()
-            .map(|e| e.as_os_str().to_string_lossy().to_string())
-            .collect()
+            let mut hashes = observer.hashes.lock().unwrap().clone();
+            hashes.sort_by(|a, b| a.0.cmp(&b.0));
+            let hashed: Vec<(String, u64)> = hashes
+                .iter()
+                .map(|(path, _, bytes)| (path.to_string(), *bytes))
+                .collect();
+            assert_eq!(
+                hashed,
+                [
+                    ("dir1/file3.txt".to_owned(), 5),
+                    ("dir1/file7.md".to_owned(), 0),
+                    ("dir1/file8.rs".to_owned(), 0),
+                ]
+            );
+        }
+
+        fs.stat(&file3).await.unwrap();
+        let stats = observer.stats.lock().unwrap();
+        assert_eq!(stats.last().unwrap().0, file3);
+        assert!(stats.last().unwrap().2);
+        assert_eq!(observer.hashes.lock().unwrap().len(), 3);
     }
 
     #[tokio::test]
-    async fn test_recurse_path() {
+    async fn test_read_dir_include_digests() {
         let root = TestRoot::new(std::thread::current().name()).await.unwrap();
         let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let file_digests = |dir: &Directory| -> Vec<Option<String>> {
+            dir.items
+                .iter()
+                .filter(|e| !e.stats.is_directory)
+                .map(|e| e.stats.sha256.clone())
+                .collect()
+        };
 
-        let r = fs
-            .read_dir_recurse(&Path::try_from(&StdPath::new("").to_owned()).unwrap())
+        let cheap = fs.read_dir_with(&Path::empty(), false).await.unwrap();
+        assert!(!cheap.items.is_empty());
+        assert!(file_digests(&cheap).iter().all(Option::is_none));
+        assert!(fs.cache_keys().is_empty());
+
+        let full = fs.read_dir_with(&Path::empty(), true).await.unwrap();
+        assert_eq!(full.items.len(), cheap.items.len());
+        assert!(file_digests(&full).iter().all(Option::is_some));
+        let before = fs.cache_stats();
+        assert_eq!(fs.read_dir(&Path::empty()).await.unwrap(), full);
+        assert_eq!(fs.cache_stats().hits, before.hits + full.items.len() as u64);
+
+        let list = fs
+            .read_dir_recursive_with(&Path::empty(), &FilterSet::new(), None, None, false)
             .await
             .unwrap();
-
-        println!("r: {r:#?}");
-        root.are_synced(&fs, &r).await.unwrap();
+        assert_eq!(list.deltas.len(), root.files.len());
+        assert!(list.deltas.iter().all(|item| item.stats.sha256.is_none()));
     }
 
     #[tokio::test]
-    async fn test_browse_path() {
+    async fn test_read_dir_ndjson() {
         let root = TestRoot::new(std::thread::current().name()).await.unwrap();
         let fs = PortableFs::with_cache(root.root.path().to_path_buf());
 
-        // Browse the directory
-        let portable_path = Path::try_from(&StdPath::new("").to_owned()).unwrap();
-        let directory = fs.read_dir(&portable_path).await.unwrap();
-
-        // Assert the directory contains the file
-        let mut entries: HashSet<String> = ["file1.txt", "file2.txt", "dir1", "dir3"]
-            .iter()
-            .map(|s| s.to_string())
+        let mut buf = Vec::new();
+        fs.read_dir_ndjson(&Path::empty(), &mut buf).await.unwrap();
+        let mut streamed: Vec<FileInfo> = String::from_utf8(buf)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
             .collect();
-        assert_eq!(directory.items.len(), 4);
-        for entry in &directory.items {
-            println!("entry name: {}", entry.name);
-            assert!(entries.remove(&entry.name));
-        }
-        assert!(entries.is_empty());
-        root.match_entries(&fs, &directory);
+        let mut expected = fs.read_dir_recurse(&Path::empty()).await.unwrap();
+        assert_eq!(streamed.len(), root.files.len());
+        streamed.sort();
+        expected.sort();
+        assert_eq!(streamed, expected);
+
+        let mut buf = Vec::new();
+        let missing = Path::try_from(["missing"].as_slice()).unwrap();
+        assert!(fs.read_dir_ndjson(&missing, &mut buf).await.is_err());
     }
 
-    async fn get_deltas(
-        path: &str,
-        sync_items: Vec<FileInfo>,
-    ) -> (HashSet<String>, Vec<FileInfo>, Vec<String>) {
-        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
-        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
-        let (a, b) = get_deltas_with(path, sync_items, &fs).await;
-        (a, b, temp_files(&root))
+    #[tokio::test]
+    async fn test_set_base_dir() {
+        let old_root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut new_root = TestRoot::new(None).await.unwrap();
+        new_root
+            .modify_file("file1.txt", "remounted")
+            .await
+            .unwrap();
+        let mut fs = PortableFs::with_cache(old_root.root.path().to_path_buf());
+        let file1 = Path::try_from(["file1.txt"].as_slice()).unwrap();
+
+        let old = fs.read_file(&file1).await.unwrap();
+        fs.read_dir_recurse(&Path::empty()).await.unwrap();
+        assert!(!fs.cache_keys().is_empty());
+
+        fs.set_base_dir(new_root.root.path().to_path_buf()).unwrap();
+        assert!(fs.cache_keys().is_empty());
+        let dir = fs.read_dir(&Path::empty()).await.unwrap();
+        let entry = dir.items.iter().find(|e| e.name == "file1.txt").unwrap();
+        assert_ne!(entry.stats, old.stats);
+        assert_eq!(entry.stats, new_root.files[StdPath::new("file1.txt")].stats);
+        assert_eq!(fs.read_file(&file1).await.unwrap().contents, b"remounted");
+
+        let mut fs = PortableFs::new_checked(old_root.root.path().to_path_buf()).unwrap();
+        let missing = old_root.root.path().join("missing");
+        assert!(matches!(
+            fs.set_base_dir(missing),
+            Err(Error::InvalidPath { .. })
+        ));
+        assert!(fs.read_file(&file1).await.is_ok());
     }
 
-    async fn get_deltas_with(
-        path: &str,
-        sync_items: Vec<FileInfo>,
-        fs: &PortableFs,
-    ) -> (HashSet<String>, Vec<FileInfo>) {
-        // Set up the channel
-        let (tx, mut rx) = mpsc::channel(10);
-        let delta = RecursiveDirList {
-            base_dir: Path::try_from(&StdPath::new(path).to_owned()).unwrap(),
-            deltas: sync_items,
-        };
+    #[tokio::test]
+    async fn test_digest_encoding() {
+        use crate::hash::HashAlgorithm;
+        use crate::hash::verify_encoded;
 
-        // Call exchange_deltas
-        fs.exchange_deltas(tx, delta, 10).await;
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        fs.set_digest_encoding(DigestEncoding::Multihash);
+        let data: &[u8] = b"Hello, world!";
+        write_file(
+            &fs,
+            &Path::try_from(["test_file.txt"].as_slice()).unwrap(),
+            data,
+        )
+        .await;
 
-        // Assert the channel received the correct FileInfo
-        let mut received_items = Vec::new();
-        while let Some(items) = rx.recv().await {
-            received_items.extend(items);
-        }
-        println!("received_items: {:#?}", received_items);
-        let mut received_files = HashSet::new();
-        received_items.iter().for_each(|i| {
-            received_files.insert(i.path.to_string());
-        });
-        (received_files, received_items)
+        let dir = fs.read_dir(&Path::empty()).await.unwrap();
+        let entry = dir
+            .items
+            .iter()
+            .find(|e| e.name == "test_file.txt")
+            .unwrap();
+        let digest = entry.stats.sha256.as_ref().unwrap();
+        assert!(digest.starts_with('u'));
+        assert!(digest.len() < 64);
+        assert!(
+            verify_encoded(data, digest, DigestEncoding::Multihash)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            DigestEncoding::Multihash.decode(digest).unwrap(),
+            HashAlgorithm::Sha256.digest_bytes(data).await.unwrap()
+        );
     }
 
     #[tokio::test]
-    async fn test_exchange_deltas_rootdir() {
-        let (expected_files, _, temp_files) = get_deltas("", vec![]).await;
-        let mut files_found = 0;
-        for file in &temp_files {
-            files_found += 1;
-            assert!(
-                expected_files.contains(file),
-                "{:?} Missing file: {} ",
-                expected_files,
-                file
-            );
-        }
+    async fn test_touch() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::with_cache(root.root.path().to_path_buf());
+
+        let new_file = Path::try_from(["dir4", "new.txt"].as_slice()).unwrap();
+        let stats = fs.touch(&new_file).await.unwrap();
+        assert!(fs.as_abs_path(&new_file).is_file());
+        assert_eq!(stats.size, 0);
+        assert_eq!(fs.get_cache().get(&new_file), Some(&stats));
+
+        let existing = Path::try_from(["file1.txt"].as_slice()).unwrap();
+        let old = root.files[&PathBuf::from("file1.txt")].stats.clone();
+        // Move the mtime back so that the touch is detectable regardless of
+        // the filesystem's timestamp granularity.
+        let past = parse_system_time(&old.mtime).unwrap() - std::time::Duration::from_secs(10);
+        filetime::set_file_mtime(
+            fs.as_abs_path(&existing),
+            filetime::FileTime::from_system_time(past),
+        )
+        .unwrap();
+        let stats = fs.touch(&existing).await.unwrap();
+        assert!(parse_system_time(&stats.mtime).unwrap() > past);
+        assert_eq!(stats.sha256, old.sha256);
+
+        let dir = Path::try_from(["dir1"].as_slice()).unwrap();
+        assert!(fs.touch(&dir).await.is_err());
+
+        fs.allow_extension("rs");
+        let filtered = Path::try_from(["new.txt"].as_slice()).unwrap();
         assert_eq!(
-            files_found,
-            expected_files.len(),
-            "Expected files: {:?}",
-            expected_files
+            fs.touch(&filtered).await,
+            Err(Error::InvalidArgument("Path is filtered out".to_string()))
         );
+        assert!(!fs.as_abs_path(&filtered).exists());
     }
 
     #[tokio::test]
-    async fn test_exchange_deltas_subdir() {
-        let (expected_files, _, temp_files) = get_deltas("dir1", vec![]).await;
-        let mut files_found = 0;
-        for file in &temp_files {
-            if file.contains("dir1") && file != "dir1" {
-                files_found += 1;
-                assert!(
-                    expected_files.contains(file),
-                    "{:?} Missing file: {} ",
-                    expected_files,
-                    file
-                );
-            }
-        }
+    async fn test_read_file_errors() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::with_cache(root.root.path().to_path_buf());
+
+        let dir = Path::try_from(["dir1"].as_slice()).unwrap();
         assert_eq!(
-            files_found,
-            expected_files.len(),
-            "Expected files: {:?}",
-            expected_files
+            fs.read_file(&dir).await,
+            Err(Error::InvalidArgument("Path is a directory".to_string()))
+        );
+
+        let missing = Path::try_from(["missing.txt"].as_slice()).unwrap();
+        assert!(fs.read_file(&missing).await.is_err());
+
+        fs.allow_extension("rs");
+        let filtered = Path::try_from(["file1.txt"].as_slice()).unwrap();
+        assert_eq!(
+            fs.read_file(&filtered).await,
+            Err(Error::InvalidArgument("Path is filtered out".to_string()))
         );
     }
 
     #[tokio::test]
-    async fn test_exchange_deltas_sends_empty() {
+    async fn test_read_file_range() {
         let root = TestRoot::new(std::thread::current().name()).await.unwrap();
         let fs = PortableFs::with_cache(root.root.path().to_path_buf());
-        let (expected_files, sync_items) = get_deltas_with("dir1", vec![], &fs).await;
-        let mut files_found = 0;
-        for file in &temp_files(&root) {
-            if file.contains("dir1") && file != "dir1" {
-                files_found += 1;
-                assert!(
-                    expected_files.contains(file),
-                    "{:?} Missing file: {} ",
-                    expected_files,
-                    file
-                );
-            }
-        }
+
+        let portable_path = Path::try_from(["file1.txt"].as_slice()).unwrap();
+        tokio::fs::write(fs.as_abs_path(&portable_path), b"Hello, world!")
+            .await
+            .unwrap();
+
         assert_eq!(
-            files_found,
-            expected_files.len(),
-            "Expected files: {:?}",
-            expected_files
+            fs.read_file_range(&portable_path, 7, 5).await.unwrap(),
+            b"world"
         );
-        let (expected_files, sync_items) = get_deltas_with("dir1", sync_items, &fs).await;
-        assert!(expected_files.is_empty());
-        assert!(sync_items.is_empty());
+        assert_eq!(
+            fs.read_file_range(&portable_path, 0, 13).await.unwrap(),
+            b"Hello, world!"
+        );
+        assert!(
+            fs.read_file_range(&portable_path, 13, 0)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(matches!(
+            fs.read_file_range(&portable_path, 7, 7).await,
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            fs.read_file_range(&portable_path, u64::MAX, 1).await,
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_hash_buffer_size() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let portable_path = Path::try_from(["file1.txt"].as_slice()).unwrap();
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(fs.as_abs_path(&portable_path), &data)
+            .await
+            .unwrap();
+
+        let expected = fs.read_file(&portable_path).await.unwrap().stats;
+        assert!(matches!(
+            fs.set_hash_buffer_size(0),
+            Err(Error::InvalidArgument(_))
+        ));
+        fs.set_hash_buffer_size(3).unwrap();
+        assert_eq!(fs.read_file(&portable_path).await.unwrap().stats, expected);
     }
+
     async fn write_file(fs: &PortableFs, portable_path: &Path, data: &[u8]) -> FileStat {
         let modified = SystemTime::now();
         let stats = FileStat {
@@ -441,6 +2975,10 @@ mod tests {
                     .await
                     .unwrap(),
             ),
+            xattrs: None,
+            dev: None,
+            ino: None,
+            is_symlink: false,
         };
 
         fs.write(portable_path, data, true, &stats).await.unwrap();
@@ -553,6 +3091,41 @@ mod tests {
         assert_eq!(fs.get_cache().stats(), &cstats);
     }
 
+    #[tokio::test]
+    async fn test_cache_keys() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        assert!(fs.cache_keys().is_empty());
+
+        let first = Path::try_from(["first.txt"].as_slice()).unwrap();
+        let second = Path::try_from(["second.txt"].as_slice()).unwrap();
+        write_file(&fs, &first, b"first").await;
+        write_file(&fs, &second, b"second").await;
+        assert_eq!(fs.cache_keys(), [second.clone(), first.clone()]);
+
+        fs.get_cache().get(&first);
+        assert_eq!(fs.cache_keys(), [first.clone(), second.clone()]);
+
+        fs.delete_file(&first).await.unwrap();
+        assert_eq!(fs.cache_keys(), [second]);
+
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        write_file(&fs, &first, b"first").await;
+        assert!(fs.cache_keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_merge_filter() {
+        let mut pfs = PortableFs::without_cache("./".into());
+        let mut fset = FilterSet::new();
+        fset.allow_extension("toml");
+        fset.deny_path("rustfmt.toml");
+        pfs.merge_filter(&fset);
+        let dir = pfs.read_dir(&Path::empty()).await.unwrap();
+        let names: Vec<&str> = dir.items.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["Cargo.toml"]);
+    }
+
     #[tokio::test]
     async fn test_filtering() {
         let mut pfs = PortableFs::without_cache("./".into());
@@ -564,4 +3137,109 @@ mod tests {
         }
         assert_eq!(dir.items.len(), toml_files.len());
     }
+
+    #[tokio::test]
+    async fn test_manifest() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+
+        let manifest = fs.manifest(&Path::empty()).await.unwrap();
+        assert_eq!(manifest.base, Path::empty());
+
+        let mut expected: HashSet<String> = root
+            .files
+            .iter()
+            .filter(|(_, node)| !node.stats.is_directory)
+            .map(|(path, _)| path.to_string_lossy().to_string())
+            .collect();
+        for (path, sha256, size) in &manifest.entries {
+            assert!(expected.remove(&path.to_string()), "for {path}");
+            assert!(!sha256.is_empty(), "for {path}");
+            assert_eq!(
+                *size,
+                root.files[&fs.as_relative_path(path)].stats.size,
+                "for {path}"
+            );
+        }
+        assert!(expected.is_empty(), "{expected:?}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_no_stale_digest_while_appending() {
+        use std::io::Write;
+        use std::sync::atomic::AtomicBool;
+        use std::sync::atomic::Ordering;
+
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let portable_path = Path::try_from(["growing.bin"].as_slice()).unwrap();
+        let full_path = fs.as_abs_path(&portable_path);
+        std::fs::write(&full_path, vec![b'a'; 8 * 1024 * 1024]).unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let stop = stop.clone();
+            let full_path = full_path.clone();
+            std::thread::spawn(move || {
+                let mut file = std::fs::File::options()
+                    .append(true)
+                    .open(&full_path)
+                    .unwrap();
+                while !stop.load(Ordering::Relaxed) {
+                    file.write_all(b"more data").unwrap();
+                    std::thread::yield_now();
+                }
+            })
+        };
+
+        for _ in 0..5 {
+            let ret = lookup_or_load(fs.layer.clone(), &full_path, &portable_path).await;
+            let cached = fs.get_cache().pop(&portable_path);
+            match ret {
+                Ok(stats) => {
+                    assert_eq!(cached.as_ref(), Some(&stats));
+                    // The file is append only, so the digest must be of the
+                    // first `size` bytes.
+                    let contents = std::fs::read(&full_path).unwrap();
+                    let prefix = &contents[..stats.size as usize];
+                    let sha256 = prefix
+                        .sha256_build()
+                        .await
+                        .unwrap()
+                        .sha256_string()
+                        .await
+                        .unwrap();
+                    assert_eq!(stats.sha256.unwrap(), sha256);
+                }
+                Err(_) => assert_eq!(cached, None),
+            }
+        }
+        stop.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+    }
+
+    #[cfg(feature = "blake3")]
+    #[tokio::test]
+    async fn test_blake3_digest() {
+        use crate::hash::HashAlgorithm;
+        use crate::hash::verify;
+
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        fs.set_hash_algorithm(HashAlgorithm::Blake3);
+
+        let portable_path = Path::try_from(["test_file.txt"].as_slice()).unwrap();
+        let data: &[u8] = b"Hello, world!";
+        write_file(&fs, &portable_path, data).await;
+
+        let dir = fs.read_dir(&Path::empty()).await.unwrap();
+        let entry = dir
+            .items
+            .iter()
+            .find(|e| e.name == "test_file.txt")
+            .unwrap();
+        let digest = entry.stats.sha256.as_ref().unwrap();
+        assert_eq!(HashAlgorithm::of_digest(digest).0, HashAlgorithm::Blake3);
+        assert!(verify(data, digest).await.unwrap());
+    }
 }
@@ -3,6 +3,7 @@ use std::fs;
 use std::fs::create_dir_all;
 use std::path::Path as StdPath;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use async_walkdir::WalkDir;
 use cross_check::get_recursive_files;
@@ -17,6 +18,7 @@ use crate::FileInfo;
 use crate::FileNode;
 use crate::FileStat;
 use crate::PortableFs;
+use crate::utils::parse_system_time;
 
 // File paths and optional contents to create in the temporary test
 pub(crate) static TEMP_FILES: &[(&str, &str, bool)] = &[
@@ -44,19 +46,45 @@ pub struct TestRoot {
     save_path: Option<PathBuf>,
 }
 
-impl TestRoot {
-    /// Creates a new `TestRoot` instance with a temporary directory.
-    pub async fn new(save_path: Option<&str>) -> Result<Self, Error> {
+/// Builds a `TestRoot` holding a custom layout of files and directories.
+#[derive(Debug, Default)]
+pub struct TestRootBuilder {
+    save_path: Option<String>,
+    entries: Vec<(String, String, bool)>,
+}
+
+impl TestRootBuilder {
+    /// Preserves the test directory under `/tmp/<save_path>` when the
+    /// `TestRoot` is dropped, see `TestRoot::new`.
+    pub fn save_path(mut self, save_path: Option<&str>) -> Self {
+        self.save_path = save_path.map(str::to_owned);
+        self
+    }
+
+    /// Adds `entries`, tuples of a relative path, the contents of the file
+    /// and whether it is a directory, whose contents are then ignored.
+    /// Missing parent directories are created.
+    pub fn entries(mut self, entries: &[(&str, &str, bool)]) -> Self {
+        self.entries.extend(
+            entries
+                .iter()
+                .map(|(path, contents, is_dir)| (path.to_string(), contents.to_string(), *is_dir)),
+        );
+        self
+    }
+
+    /// Creates the temporary directory and its entries.
+    pub async fn build(self) -> Result<TestRoot, Error> {
         let root = TempDir::new("").map_err(|e| Error::Create {
             what: "temporary directory".into(),
             how: e.to_string(),
         })?;
-        let mut ret = Self {
+        let mut ret = TestRoot {
             root,
             files: BTreeMap::new(),
-            save_path: save_path.map(|p| StdPath::new("/tmp/").join(p)),
+            save_path: self.save_path.map(|p| StdPath::new("/tmp/").join(p)),
         };
-        for (relative_path, contents, is_dir) in TEMP_FILES {
+        for (relative_path, contents, is_dir) in &self.entries {
             let dir = if *is_dir {
                 StdPath::new(relative_path)
             } else {
@@ -75,6 +103,23 @@ impl TestRoot {
         ret.reload_files().await?;
         Ok(ret)
     }
+}
+
+impl TestRoot {
+    /// Creates a new `TestRoot` instance with a temporary directory.
+    pub async fn new(save_path: Option<&str>) -> Result<Self, Error> {
+        Self::builder()
+            .save_path(save_path)
+            .entries(TEMP_FILES)
+            .build()
+            .await
+    }
+
+    /// Returns a builder of a `TestRoot` with a custom layout instead of the
+    /// default one.
+    pub fn builder() -> TestRootBuilder {
+        TestRootBuilder::default()
+    }
 
     /// Creates a new file with the specified relative path and content in the
     /// temporary test directory.
@@ -108,6 +153,47 @@ impl TestRoot {
         Ok(())
     }
 
+    /// Rewrites an existing file with `new_content` and refreshes its recorded
+    /// `FileNode`.
+    ///
+    /// The file's mtime is advanced by exactly one second from the recorded
+    /// mtime, so that the change is detectable regardless of the filesystem's
+    /// timestamp granularity.
+    pub async fn modify_file(
+        &mut self,
+        relative_path: &str,
+        new_content: &str,
+    ) -> Result<(), Error> {
+        let full_path = self.root.path().join(relative_path);
+        let old = self
+            .files
+            .get(StdPath::new(relative_path))
+            .ok_or_else(|| Error::InvalidArgument(format!("Unknown file: {relative_path}")))?;
+        if old.stats.is_directory {
+            return Err(Error::InvalidArgument(format!(
+                "Path is a directory: {relative_path}"
+            )));
+        }
+        let mtime = parse_system_time(&old.stats.mtime)? + Duration::from_secs(1);
+        let write_error = |e: std::io::Error| Error::Write {
+            what: full_path.display().to_string(),
+            how: e.to_string(),
+        };
+        fs::write(&full_path, new_content).map_err(write_error)?;
+        fs::File::options()
+            .append(true)
+            .open(&full_path)
+            .and_then(|file| file.set_modified(mtime))
+            .map_err(write_error)?;
+
+        let stat = FileStat::from_path(&full_path).await?;
+        self.files.insert(
+            relative_path.into(),
+            FileNode::new(stat, new_content.as_bytes().to_vec()),
+        );
+        Ok(())
+    }
+
     async fn get_contents(&self, path: &StdPath, stats: &FileStat) -> Result<Vec<u8>, Error> {
         if stats.is_directory {
             Ok(vec![])
@@ -182,9 +268,21 @@ impl TestRoot {
                 match less.get(path) {
                     Some(other_stat) => {
                         if rep != other_stat && !rep.stats.is_directory {
+                            let mut diffs: Vec<String> = rep
+                                .stats
+                                .diff(&other_stat.stats)
+                                .iter()
+                                .map(|d| d.to_string())
+                                .collect();
+                            if rep.contents != other_stat.contents {
+                                diffs.push("contents".to_string());
+                            }
+                            if diffs.is_empty() {
+                                diffs.push(format!("expected: {rep:?}, found: {other_stat:?}"));
+                            }
                             return Err(Error::Sync {
                                 what: format!("File stats do not match for {}", path.display()),
-                                how: format!("expected: {rep:?}, found: {other_stat:?}"),
+                                how: diffs.join(", "),
                             });
                         }
                     }
@@ -265,6 +363,80 @@ impl Drop for TestRoot {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Path;
+
+    #[tokio::test]
+    async fn test_modify_file() {
+        let mut root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let snapshot = fs.read_dir_recurse(&Path::empty()).await.unwrap();
+        root.are_synced(&fs, &snapshot).await.unwrap();
+
+        let old = root.files[StdPath::new("dir1/file3.txt")].clone();
+        root.modify_file("dir1/file3.txt", "new contents")
+            .await
+            .unwrap();
+        let new = &root.files[StdPath::new("dir1/file3.txt")];
+        assert_eq!(new.contents, b"new contents");
+        assert_eq!(
+            parse_system_time(&new.stats.mtime).unwrap(),
+            parse_system_time(&old.stats.mtime).unwrap() + Duration::from_secs(1)
+        );
+
+        assert!(root.are_synced(&fs, &snapshot).await.is_err());
+        let fresh = fs.read_dir_recurse(&Path::empty()).await.unwrap();
+        root.are_synced(&fs, &fresh).await.unwrap();
+
+        assert!(root.modify_file("dir1", "").await.is_err());
+        assert!(root.modify_file("missing.txt", "").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let large = "x".repeat(1 << 20);
+        let root = TestRoot::builder()
+            .save_path(std::thread::current().name())
+            .entries(&[
+                ("a/b/c/d/deep.txt", "deep", false),
+                ("large.bin", &large, false),
+                ("empty", "", true),
+            ])
+            .build()
+            .await
+            .unwrap();
+        let paths: Vec<&StdPath> = root.files.keys().map(PathBuf::as_path).collect();
+        assert_eq!(
+            paths,
+            [
+                "a",
+                "a/b",
+                "a/b/c",
+                "a/b/c/d",
+                "a/b/c/d/deep.txt",
+                "empty",
+                "large.bin"
+            ]
+            .map(StdPath::new)
+        );
+        assert_eq!(
+            root.files[StdPath::new("a/b/c/d/deep.txt")].contents,
+            b"deep"
+        );
+        assert_eq!(
+            root.files[StdPath::new("large.bin")].stats.size,
+            large.len() as u64
+        );
+        assert!(root.files[StdPath::new("empty")].stats.is_directory);
+
+        let fs = PortableFs::without_cache(root.root.path().to_path_buf());
+        let items = fs.read_dir_recurse(&Path::empty()).await.unwrap();
+        root.are_synced(&fs, &items).await.unwrap();
+    }
+}
+
 // The functions in the mod are intentionally written with an
 // alternative approach to ensure that the main logic of accessing
 // fs is not broken.
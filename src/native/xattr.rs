@@ -0,0 +1,77 @@
+//! Reads extended attributes of files on unix platforms.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path as StdPath;
+
+use crate::Error;
+
+fn read_xattrs_sync(path: &StdPath) -> Result<BTreeMap<String, Vec<u8>>, io::Error> {
+    let names = match ::xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut xattrs = BTreeMap::new();
+    for name in names {
+        // Attribute was removed after listing
+        let Some(value) = ::xattr::get(path, &name)? else {
+            continue;
+        };
+        xattrs.insert(name.to_string_lossy().to_string(), value);
+    }
+    Ok(xattrs)
+}
+
+/// Reads all extended attributes of `path`.
+///
+/// Returns an empty map if the underlying filesystem doesn't support
+/// extended attributes.
+pub(crate) async fn read_xattrs(path: &StdPath) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+    let path = path.to_path_buf();
+    let what = path.to_string_lossy().to_string();
+    tokio::task::spawn_blocking(move || read_xattrs_sync(&path))
+        .await
+        .map_err(|e| Error::Read {
+            what: what.clone(),
+            how: e.to_string(),
+        })?
+        .map_err(|e| Error::Read {
+            what,
+            how: e.to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Path;
+    use crate::PortableFs;
+    use crate::TestRoot;
+
+    #[tokio::test]
+    async fn test_xattr_round_trip() {
+        let root = TestRoot::new(std::thread::current().name()).await.unwrap();
+        let mut fs = PortableFs::with_cache(root.root.path().to_path_buf());
+        let path = Path::try_from(["file1.txt"].as_slice()).unwrap();
+        let full_path = fs.as_abs_path(&path);
+        if let Err(e) = ::xattr::set(&full_path, "user.comment", b"hello") {
+            println!("skipping, xattrs unsupported: {e}");
+            return;
+        }
+
+        let dir = fs.read_dir(&Path::empty()).await.unwrap();
+        let entry = dir.items.iter().find(|e| e.name == "file1.txt").unwrap();
+        assert_eq!(entry.stats.xattrs, None);
+
+        fs.set_capture_xattrs(true);
+        fs.get_cache().pop(&path);
+        let dir = fs.read_dir(&Path::empty()).await.unwrap();
+        let entry = dir.items.iter().find(|e| e.name == "file1.txt").unwrap();
+        let xattrs = entry.stats.xattrs.as_ref().unwrap();
+        assert_eq!(
+            xattrs.get("user.comment").map(|v| v.as_slice()),
+            Some(b"hello".as_slice())
+        );
+    }
+}
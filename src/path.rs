@@ -1,6 +1,8 @@
 use std::fmt::Display;
 use std::path::Path as StdPath;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 
 #[cfg(feature = "poem")]
 use poem_openapi::Object;
@@ -17,35 +19,88 @@ use crate::errors::Error;
 #[cfg(not(target_arch = "wasm32"))]
 use crate::file::FileInfo;
 
-/// A custom deserializer function for a Vec<String> that checks for ".."
-/// components.
-fn deserialize_components<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let components = Vec::<String>::deserialize(deserializer)?;
+/// Maximum number of components of a `Path` unless configured otherwise, see
+/// `Path::set_max_components`.
+pub const DEFAULT_MAX_COMPONENTS: usize = 1024;
+
+static MAX_COMPONENTS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_COMPONENTS);
+
+/// Validates that a path of `count` components is not deeper than allowed.
+fn validate_component_count(count: usize) -> Result<(), Error> {
+    let max = Path::max_components();
+    if count > max {
+        return Err(Error::InvalidArgument(format!(
+            "Path has {count} components, more than {max}"
+        )));
+    }
+    Ok(())
+}
 
-    if components.iter().any(|c| c == ".." || c == ".") {
-        // If an invalid component is found, return a custom error
-        Err(de::Error::custom("Path component cannot contain '..'"))
-    } else {
-        // If all components are valid, return the result
-        Ok(components)
+/// Validates that `s` is a single, non no-op path component.
+fn validate_component(s: &str) -> Result<(), Error> {
+    if s.contains('/') || s.contains('\\') || s == "." || s == ".." || s.is_empty() {
+        return Err(Error::InvalidArgument(format!(
+            "Invalid path component: {s}"
+        )));
+    }
+    Ok(())
+}
+
+/// Builds the canonical form of `components`: no-op `.` and empty components
+/// are dropped and the remaining ones are validated.
+///
+/// This is the single canonicalization path shared by the constructors and
+/// `Path::simplify`.
+fn canonicalize<I: IntoIterator<Item = String>>(components: I) -> Result<Vec<String>, Error> {
+    let mut ret = Vec::new();
+    for comp in components {
+        if comp.is_empty() || comp == "." {
+            continue;
+        }
+        validate_component(&comp)?;
+        validate_component_count(ret.len() + 1)?;
+        ret.push(comp);
     }
+    Ok(ret)
 }
 
 /// Represents a filesystem path as a vector of its portable components.
 /// `Path` in itself is useless. It is a base/root path to be useful.
+///
+/// Paths order lexically by component, so a directory sorts right before its
+/// children.
 #[cfg_attr(feature = "json_schema", derive(JsonSchema))]
 #[cfg_attr(feature = "poem", derive(Object))]
 #[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Hash, Eq)]
+#[derive(Debug, Clone, Serialize, PartialEq, Hash, Eq, PartialOrd, Ord)]
 pub struct Path {
     /// The components of the portable path as a vector of strings.
-    #[serde(deserialize_with = "deserialize_components")]
     components: Vec<String>,
 }
 
+/// The shapes a `Path` deserializes from: the serialized struct, or a bare
+/// sequence of components.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PathRepr {
+    Struct { components: Vec<String> },
+    Components(Vec<String>),
+}
+
+impl<'de> Deserialize<'de> for Path {
+    /// Rejects components that are not a single, non no-op path component,
+    /// like `..` or `/etc`.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (PathRepr::Struct { components } | PathRepr::Components(components)) =
+            PathRepr::deserialize(deserializer)?;
+        validate_component_count(components.len()).map_err(de::Error::custom)?;
+        for component in &components {
+            validate_component(component).map_err(de::Error::custom)?;
+        }
+        Ok(Path { components })
+    }
+}
+
 impl Display for Path {
     /// Format the portable `Path` for display by converting it into a
     /// platform `PathBuf` and delegating to its display implementation.
@@ -61,11 +116,58 @@ impl Display for Path {
 }
 
 impl Path {
+    /// Sets the maximum number of components of paths built with `TryFrom`
+    /// or deserialized, e.g. to bound the cost of paths from untrusted input.
+    /// Defaults to `DEFAULT_MAX_COMPONENTS`.
+    ///
+    /// The limit is global to the process and only applies when parsing.
+    /// Paths built before the change are not checked again, and `push` and
+    /// `join` may grow a path past the limit.
+    pub fn set_max_components(max: usize) {
+        MAX_COMPONENTS.store(max, Ordering::Relaxed);
+    }
+
+    /// Returns the maximum number of components of paths built with
+    /// `TryFrom` or deserialized, see `set_max_components`.
+    pub fn max_components() -> usize {
+        MAX_COMPONENTS.load(Ordering::Relaxed)
+    }
+
     /// Creates empty path
     pub fn empty() -> Self {
         Self { components: vec![] }
     }
 
+    /// Returns the components of the path.
+    ///
+    /// Paths are kept canonical: components are never empty, `.` or `..`
+    /// and never contain separators.
+    pub fn canonical_components(&self) -> &[String] {
+        &self.components
+    }
+
+    /// Consumes the path and returns its components, see
+    /// `canonical_components`.
+    pub fn into_components(self) -> Vec<String> {
+        self.components
+    }
+
+    /// Removes no-op components, like `.` and empty ones, and validates that
+    /// the remaining ones hold the `Path` invariants.
+    ///
+    /// Returns `Error::InvalidArgument`, leaving the path unchanged, if an
+    /// invalid component is found.
+    pub fn simplify(&mut self) -> Result<(), Error> {
+        self.components = canonicalize(self.components.iter().cloned())?;
+        Ok(())
+    }
+
+    /// Renders the path with components joined by `sep`, regardless of the
+    /// platform. Unlike `Display`, the output is the same on every platform.
+    pub fn display_with(&self, sep: char) -> String {
+        self.components.join(sep.encode_utf8(&mut [0; 4]))
+    }
+
     /// Returns the last component of the portable path, typically the file or
     /// directory name.
     pub fn basename(&self) -> Option<&str> {
@@ -112,9 +214,63 @@ impl Path {
         Ok(FileInfo {
             path: self.clone(),
             stats: self.get_file_stat(base_dir).await?,
+            digest_index: None,
         })
     }
 
+    /// Resolves the target of the symlink at this path under `base_dir` into a
+    /// portable `Path` relative to `base_dir`. Returns `None` if this path is
+    /// not a symlink.
+    ///
+    /// Returns `Error::InvalidPath` if the target escapes `base_dir`. The
+    /// target is resolved lexically and need not exist.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn resolve_link(&self, base_dir: &StdPath) -> Result<Option<Path>, Error> {
+        use std::path::Component;
+
+        let full_path = self.append_to(base_dir);
+        let read_error = |e: std::io::Error| Error::Read {
+            what: full_path.to_string_lossy().to_string(),
+            how: e.to_string(),
+        };
+        let metadata = tokio::fs::symlink_metadata(&full_path)
+            .await
+            .map_err(read_error)?;
+        if !metadata.is_symlink() {
+            return Ok(None);
+        }
+        let target = tokio::fs::read_link(&full_path).await.map_err(read_error)?;
+        let escapes = || Error::InvalidPath {
+            what: format!("link {} escapes the root: {}", self, target.display()),
+        };
+        let (mut components, relative) = if target.is_absolute() {
+            (
+                vec![],
+                target.strip_prefix(base_dir).map_err(|_| escapes())?,
+            )
+        } else {
+            let parent = self.parent().unwrap_or_else(Path::empty);
+            (parent.components, target.as_path())
+        };
+        for component in relative.components() {
+            match component {
+                Component::Normal(name) => components.push(
+                    name.to_str()
+                        .ok_or_else(|| Error::InvalidPath {
+                            what: format!("non utf-8 link target {}", target.display()),
+                        })?
+                        .to_owned(),
+                ),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    components.pop().ok_or_else(escapes)?;
+                }
+                Component::RootDir | Component::Prefix(_) => return Err(escapes()),
+            }
+        }
+        Ok(Some(Path { components }))
+    }
+
     /// Returns the parent path of the current `PortablePath`, or `None` if
     /// there is no parent.
     pub fn parent(&self) -> Option<Path> {
@@ -129,7 +285,8 @@ impl Path {
         }
     }
 
-    /// Appends a new component to the end of the portable path.
+    /// Appends a new component to the end of the portable path. The component
+    /// count is not checked against `Path::max_components`.
     ///
     /// # Arguments
     ///
@@ -138,7 +295,8 @@ impl Path {
         self.components.push(component.to_owned());
     }
 
-    /// Join two PortablePaths together into a new PortablePath.
+    /// Join two PortablePaths together into a new PortablePath. The component
+    /// count is not checked against `Path::max_components`.
     ///
     /// # Arguments
     /// * `other` - The other PortablePath to join with.
@@ -153,10 +311,105 @@ impl Path {
         ret
     }
 
+    /// Returns the number of components of this path beyond `ancestor`, e.g.
+    /// to indent entries of a tree below a displayed root, or `None` if
+    /// `ancestor` is not a prefix of this path.
+    pub fn depth_from(&self, ancestor: &Path) -> Option<usize> {
+        self.components
+            .strip_prefix(ancestor.components.as_slice())
+            .map(|rest| rest.len())
+    }
+
+    /// Returns true if `base` is this path or one of its ancestors. Only
+    /// whole components match, so `dir1` doesn't start with `dir`.
+    pub fn starts_with(&self, base: &Path) -> bool {
+        self.components.starts_with(&base.components)
+    }
+
+    /// Returns true if `candidate` refers to this path rooted at `base_dir`.
+    ///
+    /// Both paths are compared component wise, so redundant separators,
+    /// trailing separators and `.` components don't affect the result.
+    pub fn matches_std(&self, base_dir: &StdPath, candidate: &StdPath) -> bool {
+        candidate
+            .components()
+            .eq(self.append_to(base_dir).components())
+    }
+
     /// Verifies if the file exists
+    ///
+    /// This blocks on the filesystem; prefer `exists_at` in async code.
     pub fn is_valid(&self, base_dir: &StdPath) -> bool {
         self.append_to(base_dir).exists()
     }
+
+    /// Verifies if the file exists without blocking the async runtime.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn exists_at(&self, base_dir: &StdPath) -> bool {
+        tokio::fs::metadata(self.append_to(base_dir)).await.is_ok()
+    }
+}
+
+/// Alternate representation of a `Path` that serializes as a single
+/// `/`-joined string, for example `"dir1/dir2/file.txt"`, instead of
+/// `{"components":[...]}`.
+///
+/// Deserialization splits the string on `/` and validates each component
+/// like `Path::try_from` does. The empty string is the empty path.
+#[cfg_attr(feature = "json_schema", derive(JsonSchema))]
+#[cfg_attr(feature = "json_schema", schemars(with = "String"))]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "utoipa", schema(value_type = String))]
+#[derive(Debug, Clone, PartialEq, Hash, Eq)]
+pub struct StringPath(pub Path);
+
+impl From<Path> for Vec<String> {
+    fn from(path: Path) -> Self {
+        path.into_components()
+    }
+}
+
+impl From<Path> for StringPath {
+    fn from(path: Path) -> Self {
+        Self(path)
+    }
+}
+
+impl From<StringPath> for Path {
+    fn from(path: StringPath) -> Self {
+        path.0
+    }
+}
+
+impl TryFrom<&str> for StringPath {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        if s.is_empty() {
+            return Ok(Self(Path::empty()));
+        }
+        let components: Vec<&str> = s.split('/').collect();
+        Ok(Self(Path::try_from(components.as_slice())?))
+    }
+}
+
+impl Display for StringPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.components.join("/"))
+    }
+}
+
+impl Serialize for StringPath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for StringPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        StringPath::try_from(s.as_str()).map_err(de::Error::custom)
+    }
 }
 
 impl<T> TryFrom<&[T]> for Path
@@ -169,21 +422,13 @@ where
     ///
     /// Each component is validated to not contain directory separators and to
     /// not equal `.` or `..`. Returns `Error::InvalidArgument` on invalid
-    /// components.
+    /// components or if there are more than `Path::max_components`.
     fn try_from(components: &[T]) -> std::result::Result<Self, Self::Error> {
+        validate_component_count(components.len())?;
         let mut c = Vec::new();
         for comp in components {
             let s = comp.as_ref();
-            if s.contains('/') || s.contains('\\') {
-                return Err(Error::InvalidArgument(format!(
-                    "Invalid path component: {s}"
-                )));
-            }
-            if s == "." || s == ".." || s.is_empty() {
-                return Err(Error::InvalidArgument(format!(
-                    "Invalid path component: {s}"
-                )));
-            }
+            validate_component(s)?;
             c.push(s.to_string());
         }
         Ok(Path { components: c })
@@ -216,24 +461,41 @@ impl TryFrom<&StdPath> for Path {
                 "Path cannot contain '.' or '..' components".to_string(),
             ));
         }
-        let components = path
-            .components()
-            .filter_map(|comp| {
-                let s = comp.as_os_str().to_str()?;
-                if s == std::path::Component::RootDir.as_os_str().to_str().unwrap() {
-                    None
-                } else {
-                    Some(s.to_string())
-                }
-            })
-            .collect();
-        Ok(Path { components })
+        let components = path.components().filter_map(|comp| {
+            let s = comp.as_os_str().to_str()?;
+            if s == std::path::Component::RootDir.as_os_str().to_str().unwrap() {
+                None
+            } else {
+                Some(s.to_string())
+            }
+        });
+        Ok(Path {
+            components: canonicalize(components)?,
+        })
+    }
+}
+
+impl Path {
+    /// Like `TryFrom<&StdPath>` but replaces invalid UTF-8 sequences in
+    /// components with `U+FFFD` instead of skipping the components.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn try_from_lossy(path: &StdPath) -> Result<Self, Error> {
+        let components = path.components().filter_map(|comp| match comp {
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => None,
+            comp => Some(comp.as_os_str().to_string_lossy().into_owned()),
+        });
+        Ok(Path {
+            components: canonicalize(components)?,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path as StdPath;
+
     use crate::Path;
+    use crate::StringPath;
 
     #[test]
     fn root_path_display() {
@@ -262,4 +524,221 @@ mod tests {
             "a/b/c"
         );
     }
+
+    #[test]
+    fn three_components_path_display_with() {
+        let path = Path::try_from(["a", "b", "c"].as_slice()).unwrap();
+        assert_eq!(path.display_with('/'), "a/b/c");
+        assert_eq!(path.display_with('\\'), "a\\b\\c");
+        assert_eq!(Path::empty().display_with('/'), "");
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn exists_at() {
+        let root = crate::TestRoot::new(None).await.unwrap();
+        let file = Path::try_from(["dir1", "file3.txt"].as_slice()).unwrap();
+        assert!(file.exists_at(root.root.path()).await);
+        let missing = Path::try_from(["dir1", "missing.txt"].as_slice()).unwrap();
+        assert!(!missing.exists_at(root.root.path()).await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn resolve_link() {
+        let root = crate::TestRoot::new(None).await.unwrap();
+        let base = root.root.path();
+        std::os::unix::fs::symlink("../dir1/./file3.txt", base.join("dir3/link")).unwrap();
+        std::os::unix::fs::symlink(base.join("dir1/dir2"), base.join("dir3/abs")).unwrap();
+        std::os::unix::fs::symlink("../../outside", base.join("dir3/escape")).unwrap();
+
+        let link = Path::try_from(["dir3", "link"].as_slice()).unwrap();
+        assert_eq!(
+            link.resolve_link(base).await.unwrap(),
+            Some(Path::try_from(["dir1", "file3.txt"].as_slice()).unwrap())
+        );
+        let abs = Path::try_from(["dir3", "abs"].as_slice()).unwrap();
+        assert_eq!(
+            abs.resolve_link(base).await.unwrap(),
+            Some(Path::try_from(["dir1", "dir2"].as_slice()).unwrap())
+        );
+        let escape = Path::try_from(["dir3", "escape"].as_slice()).unwrap();
+        assert!(matches!(
+            escape.resolve_link(base).await,
+            Err(crate::Error::InvalidPath { .. })
+        ));
+        let file = Path::try_from(["dir1", "file3.txt"].as_slice()).unwrap();
+        assert_eq!(file.resolve_link(base).await.unwrap(), None);
+    }
+
+    #[test]
+    fn into_components_round_trip() {
+        let path = Path::try_from(["a", "b", "c"].as_slice()).unwrap();
+        let components = path.clone().into_components();
+        assert_eq!(components, ["a", "b", "c"]);
+        assert_eq!(Path::try_from(components.as_slice()).unwrap(), path);
+
+        let components: Vec<String> = path.clone().into();
+        assert_eq!(components, path.canonical_components());
+    }
+
+    #[test]
+    fn matches_std_path() {
+        let path = Path::try_from(["a", "b"].as_slice()).unwrap();
+        let base = StdPath::new("/base");
+        assert!(path.matches_std(base, StdPath::new("/base/a/b")));
+        assert!(path.matches_std(base, StdPath::new("/base/a/b/")));
+        assert!(path.matches_std(base, StdPath::new("/base//a/./b")));
+        assert!(path.matches_std(StdPath::new("/base/"), StdPath::new("/base/a/b")));
+        assert!(!path.matches_std(base, StdPath::new("/base/a")));
+        assert!(!path.matches_std(base, StdPath::new("/base/a/b/c")));
+        assert!(!path.matches_std(base, StdPath::new("/other/a/b")));
+        assert!(!path.matches_std(base, StdPath::new("a/b")));
+        assert!(Path::empty().matches_std(base, StdPath::new("/base/")));
+    }
+
+    #[test]
+    fn string_path_round_trip() {
+        for (components, json) in [
+            (vec![], "\"\""),
+            (vec!["a"], "\"a\""),
+            (vec!["a", "b", "file.txt"], "\"a/b/file.txt\""),
+        ] {
+            let path = StringPath(Path::try_from(components.as_slice()).unwrap());
+            assert_eq!(serde_json::to_string(&path).unwrap(), json);
+            assert_eq!(serde_json::from_str::<StringPath>(json).unwrap(), path);
+        }
+    }
+
+    #[test]
+    fn string_path_validation() {
+        for json in [
+            "\"/a\"",
+            "\"a/\"",
+            "\"a//b\"",
+            "\"a/../b\"",
+            "\"./a\"",
+            "\"a\\\\b\"",
+            "[\"a\"]",
+        ] {
+            assert!(
+                serde_json::from_str::<StringPath>(json).is_err(),
+                "for {json}"
+            );
+        }
+    }
+
+    #[test]
+    fn std_path_is_canonicalized() {
+        for (input, expected) in [
+            ("", vec![]),
+            ("a", vec!["a"]),
+            ("./a/b", vec!["a", "b"]),
+            ("a/./b/", vec!["a", "b"]),
+            ("/a//b", vec!["a", "b"]),
+        ] {
+            let path = Path::try_from(StdPath::new(input)).unwrap();
+            assert_eq!(path.canonical_components(), expected, "for {input}");
+        }
+        for input in [".", "..", "a/../b", "../a"] {
+            assert!(Path::try_from(StdPath::new(input)).is_err(), "for {input}");
+        }
+    }
+
+    #[test]
+    fn simplify_path() {
+        let mut path = Path {
+            components: vec![".".into(), "a".into(), "".into(), "b".into(), ".".into()],
+        };
+        path.simplify().unwrap();
+        assert_eq!(path.canonical_components(), ["a", "b"]);
+
+        let mut canonical = Path::try_from(["a", "b"].as_slice()).unwrap();
+        canonical.simplify().unwrap();
+        assert_eq!(canonical, path);
+
+        let mut invalid = Path {
+            components: vec!["a".into(), "..".into()],
+        };
+        assert!(invalid.simplify().is_err());
+        assert_eq!(invalid.canonical_components(), ["a", ".."]);
+
+        let mut invalid = Path {
+            components: vec!["a/b".into()],
+        };
+        assert!(invalid.simplify().is_err());
+    }
+
+    #[test]
+    fn depth_from() {
+        let path = |components: &[&str]| Path::try_from(components).unwrap();
+        let file4 = path(&["dir1", "dir2", "file4.txt"]);
+        assert_eq!(file4.depth_from(&path(&["dir1"])), Some(2));
+        assert_eq!(file4.depth_from(&Path::empty()), Some(3));
+        assert_eq!(file4.depth_from(&file4), Some(0));
+        assert_eq!(file4.depth_from(&path(&["dir3"])), None);
+        assert_eq!(file4.depth_from(&path(&["dir1", "dir"])), None);
+        assert_eq!(path(&["dir1"]).depth_from(&file4), None);
+
+        assert!(file4.starts_with(&Path::empty()));
+        assert!(file4.starts_with(&file4));
+        assert!(file4.starts_with(&path(&["dir1", "dir2"])));
+        assert!(!file4.starts_with(&path(&["dir1", "dir"])));
+        assert!(!path(&["dir1"]).starts_with(&file4));
+    }
+
+    #[test]
+    fn deserialize_shapes() {
+        let expected = Path::try_from(["a", "b"].as_slice()).unwrap();
+        for json in [r#"{"components":["a","b"]}"#, r#"["a","b"]"#] {
+            assert_eq!(serde_json::from_str::<Path>(json).unwrap(), expected);
+        }
+        assert_eq!(
+            serde_json::to_string(&expected).unwrap(),
+            r#"{"components":["a","b"]}"#
+        );
+        assert_eq!(serde_json::from_str::<Path>("[]").unwrap(), Path::empty());
+
+        for json in [
+            r#"{"components":["a",".."]}"#,
+            r#"["a",".."]"#,
+            r#"[".."]"#,
+            r#"["a/b"]"#,
+            r#""a""#,
+            r#"{"parts":["a"]}"#,
+        ] {
+            assert!(serde_json::from_str::<Path>(json).is_err(), "for {json}");
+        }
+    }
+
+    #[test]
+    fn max_components() {
+        let components = |count: usize| vec!["a"; count];
+        let json = |count: usize| serde_json::to_string(&components(count)).unwrap();
+        let std_path = |count: usize| components(count).join("/");
+        let max = crate::DEFAULT_MAX_COMPONENTS;
+        assert_eq!(Path::max_components(), max);
+
+        assert!(Path::try_from(components(max).as_slice()).is_ok());
+        assert!(serde_json::from_str::<Path>(&json(max)).is_ok());
+        assert!(Path::try_from(StdPath::new(&std_path(max))).is_ok());
+        assert!(matches!(
+            Path::try_from(components(max + 1).as_slice()),
+            Err(crate::Error::InvalidArgument(_))
+        ));
+        assert!(serde_json::from_str::<Path>(&json(max + 1)).is_err());
+        assert!(matches!(
+            Path::try_from(StdPath::new(&std_path(max + 1))),
+            Err(crate::Error::InvalidArgument(_))
+        ));
+        // No-op components don't count
+        let padded = format!("{}/./", std_path(max));
+        assert!(Path::try_from(StdPath::new(&padded)).is_ok());
+
+        // Only raised, so that concurrent tests keep passing
+        Path::set_max_components(2 * max);
+        assert!(Path::try_from(components(max + 1).as_slice()).is_ok());
+        assert!(Path::try_from(components(2 * max + 1).as_slice()).is_err());
+        Path::set_max_components(max);
+    }
 }
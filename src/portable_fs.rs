@@ -1,16 +1,83 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::collections::HashMap;
 use std::num::NonZeroUsize;
 use std::path::Path as StdPath;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 #[cfg(not(target_arch = "wasm32"))]
 use super::native::FsCache;
+use crate::CacheStats;
+use crate::Error;
 use crate::Path;
+use crate::StatOptions;
 use crate::cache::Cache;
 use crate::cache::NullCache;
 use crate::filter::FilterSet;
+use crate::hash::DigestEncoding;
+use crate::hash::HashAlgorithm;
+use crate::hash::RetryPolicy;
+use crate::utils::Precision;
+
+/// Number of chunks of entries buffered between a walk and its consumer,
+/// unless configured otherwise.
+pub const DEFAULT_WALK_CHANNEL_CAPACITY: usize = 100;
+
+/// How walks treat symbolic links.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Skip links entirely.
+    Deny,
+    /// Report the stats of the link itself without following it.
+    ReportOnly,
+    /// Report and traverse the target of the link, as if it was at the link's
+    /// path. Links whose target is outside the walked root or is an ancestor
+    /// of the link, which would loop forever, are skipped.
+    #[default]
+    Follow,
+}
+
+/// How walks treat entries whose names are not valid UTF-8, which can't be
+/// represented by a `Path`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NonUtf8Policy {
+    /// Skip such entries, and the tree under them.
+    #[default]
+    Skip,
+    /// Fail the walk with `Error::InvalidPath`.
+    Error,
+    /// Replace invalid sequences with `U+FFFD`. The resulting `Path` no
+    /// longer maps back to the entry, e.g. to read it.
+    Lossy,
+}
+
+/// Receives the timings of `PortableFs` operations, e.g. to feed metrics or
+/// tracing, see `PortableFs::set_observer`.
+///
+/// Callbacks run inline on the task performing the operation, so they should
+/// be quick. All of them do nothing by default.
+pub trait Observer: Send + Sync {
+    /// Called when `read_dir` or `read_dir_recurse` of `path` returns `count`
+    /// entries after `duration`.
+    fn on_read_dir(&self, _path: &Path, _duration: Duration, _count: usize) {}
+
+    /// Called when the stats of `path` were looked up, whether for `stat` or
+    /// for an entry of a walk. `hit` is true if they were served from the
+    /// cache.
+    fn on_stat(&self, _path: &Path, _duration: Duration, _hit: bool) {}
+
+    /// Called when the stats of file `path` of `bytes` bytes were loaded with
+    /// a digest of its contents. `duration` covers both reading the metadata
+    /// and hashing the contents. Not called for entries loaded without a
+    /// digest, like directories.
+    fn on_hash(&self, _path: &Path, _duration: Duration, _bytes: u64) {}
+}
 
 /// Caching and filtering layers that sit above and below the `PortableFs`
 #[derive(Clone)]
@@ -18,6 +85,41 @@ pub(crate) struct FsLayer {
     #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
     pub(crate) cache: Arc<Mutex<Box<dyn Cache>>>,
     pub filter_set: Arc<RwLock<FilterSet>>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) stat_options: Arc<RwLock<StatOptions>>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) content_only_deltas: Arc<AtomicBool>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) compute_dir_sizes: Arc<AtomicBool>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) compute_dir_digests: Arc<AtomicBool>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) include_directories: Arc<AtomicBool>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) include_empty_dirs: Arc<AtomicBool>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) symlink_policy: Arc<RwLock<SymlinkPolicy>>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) non_utf8_policy: Arc<RwLock<NonUtf8Policy>>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) walk_channel_capacity: Arc<AtomicUsize>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) fsync: Arc<AtomicBool>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) sibling_prefetch: Arc<AtomicBool>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) observer: Arc<RwLock<Option<Arc<dyn Observer>>>>,
+    // Prefix of the cache keys, so that layers rooted at different base
+    // directories can share one cache
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) cache_namespace: Option<Path>,
+    // Per path locks of the stats being loaded, so that concurrent lookups of
+    // an uncached path load it only once
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) in_flight: Arc<Mutex<HashMap<Path, Arc<tokio::sync::Mutex<()>>>>>,
+    // Number of stats loaded from disk
+    #[cfg(test)]
+    pub(crate) loads: Arc<AtomicUsize>,
 }
 
 impl FsLayer {
@@ -26,40 +128,133 @@ impl FsLayer {
         Self {
             cache: Arc::new(Mutex::new(cache)),
             filter_set: Arc::new(RwLock::new(filter_set)),
+            stat_options: Arc::new(RwLock::new(StatOptions::default())),
+            content_only_deltas: Arc::new(AtomicBool::new(false)),
+            compute_dir_sizes: Arc::new(AtomicBool::new(false)),
+            compute_dir_digests: Arc::new(AtomicBool::new(false)),
+            include_directories: Arc::new(AtomicBool::new(true)),
+            include_empty_dirs: Arc::new(AtomicBool::new(true)),
+            symlink_policy: Arc::new(RwLock::new(SymlinkPolicy::default())),
+            non_utf8_policy: Arc::new(RwLock::new(NonUtf8Policy::default())),
+            walk_channel_capacity: Arc::new(AtomicUsize::new(DEFAULT_WALK_CHANNEL_CAPACITY)),
+            fsync: Arc::new(AtomicBool::new(false)),
+            sibling_prefetch: Arc::new(AtomicBool::new(false)),
+            observer: Arc::new(RwLock::new(None)),
+            cache_namespace: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(test)]
+            loads: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Drops the cached stats of this layer, leaving those of layers sharing
+    /// the cache under another namespace.
+    pub(crate) fn flush_cache(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        for key in cache.iter_keys() {
+            let owned = self.cache_namespace.as_ref().is_none_or(|namespace| {
+                key.canonical_components()
+                    .starts_with(namespace.canonical_components())
+            });
+            if owned {
+                cache.pop(&key);
+            }
+        }
+    }
+
+    /// Returns the installed observer, if any.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) fn observer(&self) -> Option<Arc<dyn Observer>> {
+        self.observer.read().unwrap().clone()
+    }
+
+    /// Returns the key under which the stats of `path` are cached.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) fn cache_key(&self, path: &Path) -> Path {
+        match &self.cache_namespace {
+            Some(namespace) => namespace.join(path),
+            None => path.clone(),
         }
     }
 }
 
 /// Represents a filesystem rooted at a relative base directory.
+///
+/// `PortableFs` is `Send` and `Sync`, and so are the futures returned by its
+/// async methods. Clones share the cache, filter and settings, so a fs can be
+/// cloned into concurrent tasks; a filter installed by one task applies to
+/// walks started afterwards by all of them.
 #[derive(Clone)]
 pub struct PortableFs {
     // The relative path from the base directory.
     pub(crate) base_dir: PathBuf,
     pub(crate) layer: Arc<FsLayer>,
+    // Whether paths are checked not to resolve outside of base_dir.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) confined: bool,
 }
 
 impl PortableFs {
-    fn with(base_dir: PathBuf, cache: Box<dyn Cache>) -> Self {
+    fn with(base_dir: PathBuf, cache: Box<dyn Cache>, filter: FilterSet) -> Self {
         PortableFs {
             base_dir,
-            layer: Arc::new(FsLayer::new(cache, FilterSet::new())),
+            layer: Arc::new(FsLayer::new(cache, filter)),
+            confined: false,
         }
     }
 
+    /// Creates portable fs with cache rooted at the canonical form of
+    /// `base_dir`. Accesses through symlinks resolving outside of the root
+    /// fail with `Error::InvalidPath`.
+    ///
+    /// Returns `Error::InvalidPath` if `base_dir` is not an existing
+    /// directory.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new_checked(base_dir: PathBuf) -> Result<Self, Error> {
+        let invalid = |how: &str| Error::InvalidPath {
+            what: format!("{}: {how}", base_dir.display()),
+        };
+        let canonical = base_dir
+            .canonicalize()
+            .map_err(|e| invalid(&e.to_string()))?;
+        if !canonical.is_dir() {
+            return Err(invalid("not a directory"));
+        }
+        let mut fs = Self::with_cache(canonical);
+        fs.confined = true;
+        Ok(fs)
+    }
+
     /// creates portable fs with cache
     #[cfg(not(target_arch = "wasm32"))]
     pub fn with_cache(base_dir: PathBuf) -> Self {
+        Self::with_cache_and_filter(base_dir, FilterSet::new())
+    }
+
+    /// creates portable fs with out cache
+    pub fn without_cache(base_dir: PathBuf) -> Self {
+        Self::with_filter(base_dir, FilterSet::new())
+    }
+
+    /// Creates portable fs with cache that applies `filter` from the start,
+    /// e.g. a filter set deserialized from a config file.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_cache_and_filter(base_dir: PathBuf, filter: FilterSet) -> Self {
         Self::with(
             base_dir,
             Box::new(FsCache::new(NonZeroUsize::new(1000).unwrap())),
+            filter,
         )
     }
 
-    /// creates portable fs with out cache
-    pub fn without_cache(base_dir: PathBuf) -> Self {
+    /// Creates portable fs without cache that applies `filter` from the
+    /// start.
+    pub fn with_filter(base_dir: PathBuf, filter: FilterSet) -> Self {
         Self::with(
             base_dir,
             Box::new(NullCache::new(NonZeroUsize::new(1000).unwrap())),
+            filter,
         )
     }
 
@@ -86,6 +281,36 @@ impl PortableFs {
         relative.append_to(StdPath::new(""))
     }
 
+    /// Points this fs at `base_dir`, e.g. after a volume was remounted, and
+    /// drops the cached stats, which were loaded from the previous base
+    /// directory. Filters and other settings are kept.
+    ///
+    /// The base directory belongs to this instance while the cache is shared
+    /// with its clones, which keep their own base directory. Stats they cache
+    /// would then be served to this fs, so clones should be repointed too.
+    ///
+    /// For file systems created with `PortableFs::new_checked`, returns
+    /// `Error::InvalidPath`, leaving the fs unchanged, if `base_dir` is not an
+    /// existing directory.
+    pub fn set_base_dir(&mut self, base_dir: PathBuf) -> Result<(), Error> {
+        self.base_dir = if self.confined {
+            let invalid = |how: &str| Error::InvalidPath {
+                what: format!("{}: {how}", base_dir.display()),
+            };
+            let canonical = base_dir
+                .canonicalize()
+                .map_err(|e| invalid(&e.to_string()))?;
+            if !canonical.is_dir() {
+                return Err(invalid("not a directory"));
+            }
+            canonical
+        } else {
+            base_dir
+        };
+        self.layer.flush_cache();
+        Ok(())
+    }
+
     /// Add new allow filter.
     /// Deny list overrides allow list
     pub fn allow_path<P: AsRef<StdPath>>(&mut self, path: P) {
@@ -107,4 +332,207 @@ impl PortableFs {
     pub fn allow_filename(&mut self, name: &str) {
         self.layer.filter_set.write().unwrap().allow_filename(name);
     }
+
+    /// Merges rules of `other` into the current filter, see
+    /// `FilterSet::merge`.
+    pub fn merge_filter(&mut self, other: &FilterSet) {
+        self.layer.filter_set.write().unwrap().merge(other);
+    }
+
+    /// Replaces the installed filter with `filter` in one step, e.g. to hot
+    /// reload filter configuration. A walk compiles the filter once when it
+    /// starts, so walks in progress keep using the previous filter until they
+    /// end.
+    pub fn set_filter(&self, filter: FilterSet) {
+        *self.layer.filter_set.write().unwrap() = filter;
+    }
+
+    /// Returns a copy of the installed filter.
+    pub fn filter(&self) -> FilterSet {
+        self.layer.filter_set.read().unwrap().clone()
+    }
+
+    /// Enables or disables capturing extended attributes into
+    /// `FileStat::xattrs`. Disabled by default as reading extended attributes
+    /// costs a syscall per attribute.
+    ///
+    /// Capturing is supported only on unix with the `xattr` feature enabled.
+    /// Already cached stats are not refreshed.
+    pub fn set_capture_xattrs(&mut self, capture: bool) {
+        self.layer.stat_options.write().unwrap().capture_xattrs = capture;
+    }
+
+    /// Sets the algorithm used to digest file contents. Defaults to
+    /// `HashAlgorithm::Sha256`.
+    ///
+    /// Already cached stats are not refreshed.
+    pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) {
+        self.layer.stat_options.write().unwrap().hash_algorithm = algorithm;
+    }
+
+    /// Sets how digests are rendered into `FileStat::sha256`. Defaults to
+    /// `DigestEncoding::Hex`. Digests of nodes applied with `apply_nodes`
+    /// are expected in the same encoding.
+    ///
+    /// Already cached stats are not refreshed.
+    pub fn set_digest_encoding(&mut self, encoding: DigestEncoding) {
+        self.layer.stat_options.write().unwrap().digest_encoding = encoding;
+    }
+
+    /// Sets the size of the buffer used to read files while hashing. Defaults
+    /// to `DEFAULT_HASH_BUFFER_SIZE`; larger buffers can improve throughput
+    /// on fast disks.
+    ///
+    /// Returns `Error::InvalidArgument` if `size` is zero.
+    pub fn set_hash_buffer_size(&mut self, size: usize) -> Result<(), Error> {
+        if size == 0 {
+            return Err(Error::InvalidArgument(
+                "hash buffer size must be non zero".into(),
+            ));
+        }
+        self.layer.stat_options.write().unwrap().hash_buffer_size = size;
+        Ok(())
+    }
+
+    /// Enables or disables hashing files of at least `hash::MMAP_THRESHOLD`
+    /// bytes through a memory map, which can be faster for large files.
+    /// Disabled by default.
+    ///
+    /// Only enable it if no other process truncates the hashed files: reading
+    /// a mapped file that was truncated meanwhile raises SIGBUS, which kills
+    /// the process.
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    pub fn set_use_mmap(&mut self, use_mmap: bool) {
+        self.layer.stat_options.write().unwrap().use_mmap = use_mmap;
+    }
+
+    /// Sets the policy to retry transient IO errors while hashing files, for
+    /// example on flaky network mounts. By default errors are not retried.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.layer.stat_options.write().unwrap().retry_policy = retry_policy;
+    }
+
+    /// Sets the precision of `FileStat::mtime`, e.g. `Precision::Seconds` so
+    /// that files copied across filesystems with coarser timestamps compare
+    /// equal. Defaults to `Precision::Millis`.
+    ///
+    /// Already cached stats are not refreshed.
+    pub fn set_mtime_precision(&mut self, precision: Precision) {
+        self.layer.stat_options.write().unwrap().mtime_precision = precision;
+    }
+
+    /// When enabled, `write` and `apply_nodes` flush written files and their
+    /// parent directory to disk before returning, so that writes survive a
+    /// crash. Disabled by default as it is slow.
+    pub fn set_fsync(&mut self, fsync: bool) {
+        self.layer.fsync.store(fsync, Ordering::Relaxed);
+    }
+
+    /// When enabled, a cache miss on a file caches the stats of the other
+    /// files of its directory in the background, up to the cache capacity,
+    /// as they are likely to be looked up next. Disabled by default.
+    pub fn set_sibling_prefetch(&mut self, prefetch: bool) {
+        self.layer
+            .sibling_prefetch
+            .store(prefetch, Ordering::Relaxed);
+    }
+
+    /// Installs `observer` to receive the timings of operations, replacing
+    /// any previous one. Clones of this fs share the observer.
+    pub fn set_observer(&mut self, observer: Arc<dyn Observer>) {
+        *self.layer.observer.write().unwrap() = Some(observer);
+    }
+
+    /// When enabled, `exchange_deltas` compares files using
+    /// `FileStat::content_eq`, so files differing only in mtime are not sent.
+    pub fn set_content_only_deltas(&mut self, content_only: bool) {
+        self.layer
+            .content_only_deltas
+            .store(content_only, Ordering::Relaxed);
+    }
+
+    /// When enabled, `read_dir_recurse` and `read_dir_recurse_parallel` report
+    /// each directory's size as the sum of the sizes of the files under it
+    /// instead of the size reported by the platform.
+    pub fn set_compute_dir_sizes(&mut self, compute: bool) {
+        self.layer
+            .compute_dir_sizes
+            .store(compute, Ordering::Relaxed);
+    }
+
+    /// When enabled, `read_dir_recurse` and `read_dir_recurse_parallel` set
+    /// each directory's digest to a digest of the names and digests of its
+    /// listed children instead of an empty string. Directories with
+    /// identical filtered subtrees then have identical digests, so subtrees
+    /// can be compared without walking them.
+    pub fn set_compute_dir_digests(&mut self, compute: bool) {
+        self.layer
+            .compute_dir_digests
+            .store(compute, Ordering::Relaxed);
+    }
+
+    /// When disabled, walks return only files. Directories are still
+    /// traversed to find the files under them. Enabled by default.
+    pub fn set_include_directories(&mut self, include: bool) {
+        self.layer
+            .include_directories
+            .store(include, Ordering::Relaxed);
+    }
+
+    /// When disabled, walks omit directories that have no entries on disk.
+    /// Enabled by default. Has no effect if directories are not included, see
+    /// `set_include_directories`.
+    pub fn set_include_empty_dirs(&mut self, include: bool) {
+        self.layer
+            .include_empty_dirs
+            .store(include, Ordering::Relaxed);
+    }
+
+    /// Sets how walks treat symbolic links. Defaults to
+    /// `SymlinkPolicy::Follow`.
+    pub fn set_symlink_policy(&mut self, policy: SymlinkPolicy) {
+        *self.layer.symlink_policy.write().unwrap() = policy;
+    }
+
+    /// Sets how walks treat entries whose names are not valid UTF-8. Defaults
+    /// to `NonUtf8Policy::Skip`.
+    pub fn set_non_utf8_policy(&mut self, policy: NonUtf8Policy) {
+        *self.layer.non_utf8_policy.write().unwrap() = policy;
+    }
+
+    /// Sets the number of chunks of entries buffered between a walk and its
+    /// consumer. Walks pause once the buffer is full until the consumer
+    /// catches up. Defaults to `DEFAULT_WALK_CHANNEL_CAPACITY`.
+    pub fn set_walk_channel_capacity(&mut self, capacity: NonZeroUsize) {
+        self.layer
+            .walk_channel_capacity
+            .store(capacity.get(), Ordering::Relaxed);
+    }
+
+    /// Returns the counters of lookups in the stat cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.layer.cache.lock().unwrap().stats().clone()
+    }
+
+    /// Zeroes the counters returned by `cache_stats`, e.g. after sampling
+    /// them. Cached stats are kept.
+    pub fn reset_cache_stats(&self) {
+        self.layer.cache.lock().unwrap().reset_stats();
+    }
+
+    /// Returns the paths whose stats are currently cached, most recently used
+    /// first. Returns an empty list for a fs created without cache.
+    pub fn cache_keys(&self) -> Vec<Path> {
+        let keys = self.layer.cache.lock().unwrap().iter_keys();
+        let Some(namespace) = &self.layer.cache_namespace else {
+            return keys;
+        };
+        keys.into_iter()
+            .filter_map(|key| {
+                key.canonical_components()
+                    .strip_prefix(namespace.canonical_components())
+                    .map(|rest| Path::try_from(rest).unwrap())
+            })
+            .collect()
+    }
 }
@@ -4,6 +4,8 @@ use std::time::SystemTime;
 
 use chrono::DateTime;
 use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
 
 use crate::errors::Error;
 
@@ -13,11 +15,34 @@ static WINDOWS_RESERVED_NAMES: &[&str] = &[
     "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
 ];
 
+/// Precision of formatted modification times. Finer digits are truncated.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Hash, Eq)]
+pub enum Precision {
+    /// Whole seconds, for example "2018-01-26T18:30:09Z"
+    Seconds,
+    /// Milliseconds, for example "2018-01-26T18:30:09.453Z"
+    #[default]
+    Millis,
+    /// Nanoseconds, for example "2018-01-26T18:30:09.453123456Z"
+    Nanos,
+}
+
 /// Formats a `SystemTime` into a RFC 3339 - Z format.
 /// For example "2018-01-26T18:30:09.453Z"
 pub fn format_system_time(time: SystemTime) -> String {
+    format_system_time_with(time, Precision::default())
+}
+
+/// Formats a `SystemTime` into a RFC 3339 - Z format with the given
+/// `precision`.
+pub fn format_system_time_with(time: SystemTime, precision: Precision) -> String {
     let datetime: DateTime<Utc> = time.into();
-    datetime.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+    let format = match precision {
+        Precision::Seconds => chrono::SecondsFormat::Secs,
+        Precision::Millis => chrono::SecondsFormat::Millis,
+        Precision::Nanos => chrono::SecondsFormat::Nanos,
+    };
+    datetime.to_rfc3339_opts(format, true)
 }
 
 /// Builds a `SystemTime` from a RFC 3339 - Z formatted string.
@@ -30,6 +55,27 @@ pub fn parse_system_time(s: &str) -> Result<SystemTime, Error> {
     Ok(SystemTime::from(datetime))
 }
 
+/// Like `parse_system_time` but also accepts RFC 2822 strings, e.g. "Fri, 26
+/// Jan 2018 18:30:09 +0000", and integers of milliseconds since the Unix
+/// epoch, e.g. "1516991409453", as found in third-party manifests.
+pub fn parse_system_time_flexible(s: &str) -> Result<SystemTime, Error> {
+    let s = s.trim();
+    if let Ok(time) = parse_system_time(s) {
+        return Ok(time);
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc2822(s) {
+        return Ok(SystemTime::from(datetime));
+    }
+    s.parse::<i64>()
+        .ok()
+        .and_then(DateTime::from_timestamp_millis)
+        .map(SystemTime::from)
+        .ok_or_else(|| Error::Parse {
+            what: "parse system time".into(),
+            how: format!("{s} is neither RFC 3339, RFC 2822 nor epoch millis"),
+        })
+}
+
 /// Formats a file size in bytes into a human-readable string (e.g., KB, MB).
 ///
 /// # Arguments
@@ -146,10 +192,25 @@ pub fn sanitize_filename(filename: &str, replacement: char) -> String {
 /// `true` if the filename is valid on Windows, macOS, and Linux, `false`
 /// otherwise
 pub fn is_valid_filename(filename: &str) -> bool {
+    is_valid_filename_with(filename, 255, true)
+}
+
+/// Checks if a filename is valid across all major platforms, with a
+/// configurable length limit and reserved name check.
+///
+/// # Arguments
+/// * `filename` - The filename to validate
+/// * `max_len` - Maximum length of `filename` in bytes, e.g. 143 for eCryptfs
+/// * `check_reserved` - Whether to reject Windows reserved names like `CON`
+///
+/// # Returns
+/// `true` if the filename is valid under the given constraints, `false`
+/// otherwise
+pub fn is_valid_filename_with(filename: &str, max_len: usize, check_reserved: bool) -> bool {
     const INVALID_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
 
     // Check if empty or too long
-    if filename.is_empty() || filename.len() > 255 {
+    if filename.is_empty() || filename.len() > max_len {
         return false;
     }
 
@@ -170,6 +231,10 @@ pub fn is_valid_filename(filename: &str) -> bool {
         return false;
     }
 
+    if !check_reserved {
+        return true;
+    }
+
     // Check for Windows reserved names
     let path = StdPath::new(filename);
 
@@ -188,10 +253,40 @@ pub fn is_valid_filename(filename: &str) -> bool {
     true
 }
 
+/// Parses a comma separated list of extensions, e.g. `"jpg, .PNG ,gif"`,
+/// into values ready for `FilterSet::allow_extension`.
+///
+/// Extensions are trimmed, stripped of a leading dot and lowercased. Empty
+/// entries are skipped.
+pub fn parse_extensions(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|ext| ext.trim())
+        .map(|ext| ext.strip_prefix('.').unwrap_or(ext).to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_format_system_time_with() {
+        let time = parse_system_time("2018-01-26T18:30:09.453123456Z").unwrap();
+        assert_eq!(
+            format_system_time_with(time, Precision::Seconds),
+            "2018-01-26T18:30:09Z"
+        );
+        assert_eq!(
+            format_system_time_with(time, Precision::Millis),
+            format_system_time(time)
+        );
+        assert_eq!(
+            format_system_time_with(time, Precision::Nanos),
+            "2018-01-26T18:30:09.453123456Z"
+        );
+    }
+
     #[test]
     fn test_valid_filename() {
         assert!(is_valid_filename("myfile.txt"));
@@ -223,6 +318,20 @@ mod tests {
         assert!(!is_valid_filename("file.txt."));
     }
 
+    #[test]
+    fn test_valid_filename_with() {
+        let long = "a".repeat(200);
+        assert!(is_valid_filename(&long));
+        assert!(!is_valid_filename_with(&long, 143, true));
+        assert!(is_valid_filename_with(&"a".repeat(143), 143, true));
+        assert!(!is_valid_filename_with(&"a".repeat(256), 255, false));
+
+        assert!(!is_valid_filename_with("CON", 255, true));
+        assert!(is_valid_filename_with("CON", 255, false));
+        assert!(is_valid_filename_with("con.txt", 255, false));
+        assert!(!is_valid_filename_with("CON:", 255, false));
+    }
+
     #[test]
     fn test_sanitize() {
         assert_eq!(
@@ -262,4 +371,49 @@ mod tests {
         assert_eq!(sanitize_filename("...", '_'), "unnamed_");
         assert_eq!(sanitize_filename("   ", '_'), "unnamed_");
     }
+
+    #[test]
+    fn test_parse_system_time_flexible() {
+        let expected = parse_system_time("2018-01-26T18:30:09.453Z").unwrap();
+        for s in [
+            "2018-01-26T18:30:09.453Z",
+            "2018-01-26T19:30:09.453+01:00",
+            "1516991409453",
+            " 1516991409453\n",
+        ] {
+            assert_eq!(parse_system_time_flexible(s).unwrap(), expected, "{s}");
+        }
+        assert_eq!(
+            parse_system_time_flexible("Fri, 26 Jan 2018 18:30:09 +0000").unwrap(),
+            parse_system_time("2018-01-26T18:30:09Z").unwrap()
+        );
+        assert_eq!(
+            parse_system_time_flexible("0").unwrap(),
+            SystemTime::UNIX_EPOCH
+        );
+
+        for s in [
+            "",
+            "yesterday",
+            "2018-01-26",
+            "1516991409.453",
+            "99999999999999999999",
+        ] {
+            assert!(
+                matches!(parse_system_time_flexible(s), Err(Error::Parse { .. })),
+                "{s}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_extensions() {
+        assert_eq!(parse_extensions("jpg,png"), ["jpg", "png"]);
+        assert_eq!(parse_extensions(".jpg,.png"), ["jpg", "png"]);
+        assert_eq!(parse_extensions("jpg, png ,gif"), ["jpg", "png", "gif"]);
+        assert_eq!(parse_extensions("JPG, .Png"), ["jpg", "png"]);
+        assert_eq!(parse_extensions(" tar.gz , ,"), ["tar.gz"]);
+        assert!(parse_extensions("").is_empty());
+        assert!(parse_extensions(" . ").is_empty());
+    }
 }